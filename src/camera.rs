@@ -1,5 +1,6 @@
 use serde::{Deserialize, Serialize};
 
+use crate::frequency::Frequency;
 use crate::point3d::Point3D;
 use crate::ray::Ray;
 
@@ -49,37 +50,74 @@ impl Camera {
         vfov: f32, // vertical field-of-view in degrees
         aspect: f32,
     ) -> Camera {
-        let theta = vfov.to_radians();
-        let half_height = (theta / 2.0).tan();
-        let half_width = aspect * half_height;
-
-        let w = (look_from - look_at).unit_vector();
-        let u = vup.cross(&w).unit_vector();
-        let v = w.cross(&u);
-
-        let origin = look_from;
-        let lower_left_corner = origin - (u * half_width) - (v * half_height) - w;
-        let horizontal = u * 2.0 * half_width;
-        let vertical = v * 2.0 * half_height;
-
-        Camera {
-            origin,
-            lower_left_corner,
-            focal_length: (look_from - look_at).length(),
-            horizontal,
-            vertical,
+        let zero = Point3D::new(0.0, 0.0, 0.0);
+        let mut camera = Camera {
+            origin: zero,
+            lower_left_corner: zero,
+            focal_length: 0.0,
+            horizontal: zero,
+            vertical: zero,
             look_from,
             look_at,
             vup,
             vfov,
             aspect,
-        }
+        };
+        camera.update_basis();
+        camera
+    }
+
+    /// Recomputes the computed fields after the view changed.
+    fn update_basis(&mut self) {
+        let theta = self.vfov.to_radians();
+        let half_height = (theta / 2.0).tan();
+        let half_width = self.aspect * half_height;
+
+        let w = (self.look_from - self.look_at).unit_vector();
+        let u = self.vup.cross(&w).unit_vector();
+        let v = w.cross(&u);
+
+        self.origin = self.look_from;
+        self.lower_left_corner = self.origin - (u * half_width) - (v * half_height) - w;
+        self.horizontal = u * 2.0 * half_width;
+        self.vertical = v * 2.0 * half_height;
+        self.focal_length = (self.look_from - self.look_at).length();
+    }
+
+    /// Moves the camera to `eye`, looking at `target`, keeping the up direction.
+    pub fn look_at(&mut self, eye: Point3D, target: Point3D) {
+        self.look_from = eye;
+        self.look_at = target;
+        self.update_basis();
+    }
+
+    /// Sets the vertical field of view in degrees.
+    pub fn set_fov(&mut self, vfov: f32) {
+        self.vfov = vfov;
+        self.update_basis();
+    }
+
+    pub fn set_aspect(&mut self, aspect: f32) {
+        self.aspect = aspect;
+        self.update_basis();
     }
 
     pub fn get_ray(&self, u: f32, v: f32) -> Ray {
         Ray::new(
             self.origin,
-            self.lower_left_corner + (self.horizontal * u) + (self.vertical * v) - self.origin,0.0,0.0,0
+            self.lower_left_corner + (self.horizontal * u) + (self.vertical * v) - self.origin,0.0,0.0,
+            Frequency::from_hz(0),
+        )
+    }
+
+    /// The same view of a scene resized by `factor` about the origin.
+    pub fn scaled(&self, factor: f32) -> Camera {
+        Camera::new(
+            self.look_from * factor,
+            self.look_at * factor,
+            self.vup,
+            self.vfov,
+            self.aspect,
         )
     }
 }
@@ -139,3 +177,28 @@ fn test_to_json() {
     assert_eq!(camera.horizontal, c.horizontal);
     assert_eq!(camera.vertical, c.vertical);
 }
+
+#[test]
+fn test_look_at() {
+    let mut camera = Camera::new(
+        Point3D::new(0.0, 0.0, 0.0),
+        Point3D::new(0.0, 0.0, -1.0),
+        Point3D::new(0.0, 1.0, 0.0),
+        90.0,
+        1.0,
+    );
+    let (eye, target) = (Point3D::new(3.0, 2.0, 5.0), Point3D::new(-1.0, 0.0, 1.0));
+    camera.look_at(eye, target);
+    let ray = camera.get_ray(0.5, 0.5);
+    assert_eq!(ray.origin, eye);
+    let (direction, expected) = (ray.direction.unit_vector(), (target - eye).unit_vector());
+    assert_approx_eq!(direction.x(), expected.x());
+    assert_approx_eq!(direction.y(), expected.y());
+    assert_approx_eq!(direction.z(), expected.z());
+    // A narrower view keeps the center, but brings the corners in.
+    let corner = camera.get_ray(0.0, 0.0).direction.unit_vector().dot(&expected);
+    camera.set_fov(30.0);
+    assert!(camera.get_ray(0.0, 0.0).direction.unit_vector().dot(&expected) > corner);
+    camera.set_aspect(2.0);
+    assert_approx_eq!(camera.horizontal.length(), 2.0 * camera.vertical.length());
+}
@@ -1,12 +1,21 @@
 use jpeg_decoder::Decoder;
+use palette::Srgb;
 use serde::{Deserialize, Serialize};
-use serde_with::serde_as;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufReader, Read, Write};
+use std::path::Path;
 
 use crate::camera::Camera;
 use crate::cube::Cube;
+use crate::error::RaytracerError;
+use crate::frequency::Frequency;
+use crate::materials::{Material, SrgbAsArray};
+use crate::point3d::Point3D;
+use crate::ray::{Hittable, Ray};
+use crate::world::World;
 
+#[cfg(test)]
+use assert_approx_eq::assert_approx_eq;
 
 #[serde_with::serde_as]
 #[derive(Debug, Serialize, Deserialize)]
@@ -24,17 +33,125 @@ impl Sky {
     }
 }
 
-fn load_texture_image(path: &str) -> (Vec<u8>, usize, usize, String) {
-    let file = File::open(path).expect(path);
+/// Loads a jpeg texture as RGB pixels with its width and height.
+pub fn load_texture_image(path: &str) -> Result<(Vec<u8>, usize, usize), RaytracerError> {
+    let file = File::open(path)
+        .map_err(|e| RaytracerError::TextureLoad(format!("Could not open {}: {}", path, e)))?;
     let mut decoder = Decoder::new(BufReader::new(file));
-    let pixels = decoder.decode().expect("failed to decode image");
+    let pixels = decoder
+        .decode()
+        .map_err(|e| RaytracerError::TextureLoad(format!("Could not decode {}: {}", path, e)))?;
     let metadata = decoder.info().unwrap();
-    (
-        pixels,
-        metadata.width as usize,
-        metadata.height as usize,
-        path.to_string(),
-    )
+    if metadata.width == 0 || metadata.height == 0 {
+        return Err(RaytracerError::TextureLoad(format!(
+            "Image {} has zero size ({}x{})",
+            path, metadata.width, metadata.height
+        )));
+    }
+    Ok((pixels, metadata.width as usize, metadata.height as usize))
+}
+
+/// Loads any image as RGB pixels with its width and height.
+fn load_image(path: &str) -> Result<(Vec<u8>, usize, usize), String> {
+    let image = image::open(path)
+        .map_err(|e| format!("Could not open {}: {}", path, e))?
+        .to_rgb8();
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    Ok((image.into_raw(), width, height))
+}
+
+/// The images referenced by a config that fail to load, as the json pointer of the
+/// field referencing them, where that is in the config, and what went wrong.
+fn failing_images(json: &serde_json::Value) -> Vec<(String, String, String)> {
+    let mut failing = vec![];
+    if let Some(path) = json.pointer("/sky/texture").and_then(|p| p.as_str()) {
+        if !path.is_empty() {
+            if let Err(e) = load_texture_image(path) {
+                failing.push(("/sky/texture".to_string(), "sky".to_string(), e.to_string()));
+            }
+        }
+    }
+    if let Some(path) = json.pointer("/land_cover/image").and_then(|p| p.as_str()) {
+        if let Err(e) = load_image(path) {
+            failing.push(("/land_cover".to_string(), "land_cover".to_string(), e));
+        }
+    }
+    if let Some(objects) = json.get("objects").and_then(|o| o.as_array()) {
+        for (i, object) in objects.iter().enumerate() {
+            if let Some(path) = object
+                .pointer("/material/Texture/pixels")
+                .and_then(|p| p.as_str())
+            {
+                if let Err(e) = load_texture_image(path) {
+                    failing.push((
+                        format!("/objects/{}/material", i),
+                        format!("objects[{}]", i),
+                        e.to_string(),
+                    ));
+                }
+            }
+        }
+    }
+    failing
+}
+
+/// Tries to load every texture and image referenced by a config, without parsing the
+/// rest of it, and describes each one that fails.
+pub fn check_textures(json: &serde_json::Value) -> Vec<String> {
+    failing_images(json)
+        .into_iter()
+        .map(|(_, location, e)| format!("{}: {}", location, e))
+        .collect()
+}
+
+/// Describes everything wrong with the config in `text`: the images that fail to load,
+/// and with those left out, whatever keeps the rest from parsing or validating.
+pub fn check_config(text: &[u8], format: ConfigFormat) -> Vec<String> {
+    let mut value = match format.parse_value(text) {
+        Ok(value) => value,
+        Err(e) => return vec![format!("Invalid {:?}: {}", format, e)],
+    };
+    let failing = failing_images(&value);
+    let mut problems: Vec<String> = failing
+        .iter()
+        .map(|(_, location, e)| format!("{}: {}", location, e))
+        .collect();
+    for (pointer, _, _) in failing.iter() {
+        if let Some(field) = value.pointer_mut(pointer) {
+            *field = match pointer.as_str() {
+                "/sky/texture" => serde_json::json!(""),
+                "/land_cover" => serde_json::Value::Null,
+                _ => serde_json::json!({"Lambertian": {"albedo": [0.5, 0.5, 0.5]}}),
+            };
+        }
+    }
+    // A RON value keeps no enum variant names, so unless images have to be left out the
+    // config is parsed as written.
+    let parsed = if failing.is_empty() && format != ConfigFormat::Json {
+        Config::from_reader_as(text, format).map_err(|e| e.to_string())
+    } else {
+        // Image paths are read as borrowed strings, which a json value cannot lend.
+        serde_json::from_str::<Config>(&value.to_string())
+            .map_err(|e| e.to_string())
+            .map(|mut config| {
+                if let Err(e) = config.resolve_positions() {
+                    problems.push(e.to_string());
+                }
+                config
+            })
+    };
+    match parsed {
+        Ok(mut config) => match config.migrate() {
+            Ok(()) => {
+                if let Err(e) = config.validate() {
+                    problems.push(e.to_string());
+                }
+            }
+            Err(e) => problems.push(e.to_string()),
+        },
+        Err(e) => problems.push(format!("Invalid config: {}", e)),
+    }
+    problems
 }
 
 serde_with::serde_conv!(
@@ -46,23 +163,1252 @@ serde_with::serde_conv!(
             None => "".to_string(),
         }
     },
-    |value: &str| -> Result<_, std::convert::Infallible> {
+    |value: &str| -> Result<_, RaytracerError> {
         match value {
             "" => Ok(None),
-            _ => Ok(Some(load_texture_image(value))),
+            _ => {
+                let (pixels, width, height) = load_texture_image(value)?;
+                Ok(Some((pixels, width, height, value.to_string())))
+            }
         }
     }
 );
 
+serde_with::serde_conv!(
+    ImageAsPath,
+    (Vec<u8>, usize, usize, String),
+    |image: &(Vec<u8>, usize, usize, String)| image.3.clone(),
+    |value: &str| -> Result<_, String> {
+        let (pixels, width, height) = load_image(value)?;
+        Ok((pixels, width, height, value.to_string()))
+    }
+);
+
+/// Ground type covering part of the map, identified by its color in the
+/// land-cover image.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct LandCoverClass {
+    pub color: [u8; 3],
+    // Loss in dB for rays hitting the ground here, both for the signal recorded
+    // there and for what is reflected.
+    pub loss: f32,
+}
+
+/// Map of ground types stretched over the mapped area, with its top row at the
+/// far (+z) edge like the signal map pngs.
+#[serde_with::serde_as]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LandCover {
+    #[serde_as(as = "ImageAsPath")]
+    pub image: (Vec<u8>, usize, usize, String),
+    pub classes: Vec<LandCoverClass>,
+}
+
+impl LandCover {
+    /// Loss of the class whose color is closest to the image at the ground
+    /// position of `point`, or 0 if there are no classes.
+    pub fn loss_at(&self, point: Point3D, world_width: f32, world_height: f32) -> f32 {
+        let (pixels, width, height, _) = &self.image;
+        let u = (point.x() / world_width).clamp(0.0, 1.0);
+        let v = 1.0 - (point.z() / world_height).clamp(0.0, 1.0);
+        let x = ((u * *width as f32) as usize).min(width - 1);
+        let y = ((v * *height as f32) as usize).min(height - 1);
+        let pixel = &pixels[(x + y * width) * 3..(x + y * width) * 3 + 3];
+        self.classes
+            .iter()
+            .min_by_key(|class| {
+                class
+                    .color
+                    .iter()
+                    .zip(pixel)
+                    .map(|(a, b)| (*a as i32 - *b as i32).pow(2))
+                    .sum::<i32>()
+            })
+            .map_or(0.0, |class| class.loss)
+    }
+}
+
+/// Version of the config format written by this build. Configs without a
+/// `version` field are treated as version 1.
+pub const CONFIG_VERSION: u32 = 2;
+
+fn default_version() -> u32 {
+    1
+}
+
+fn default_grid_origin() -> Point3D {
+    Point3D::new(0.0, 0.0, 0.0)
+}
+
+fn default_noise_floor() -> f32 {
+    -110.0
+}
+
+fn default_ground_epsilon() -> f32 {
+    0.001
+}
+
+fn default_t_min() -> f32 {
+    0.00001
+}
+
+fn default_strength_range() -> (f32, f32) {
+    (-30.0, 60.0)
+}
+
+fn default_image_scale() -> f32 {
+    1.0
+}
+
+fn default_supersampling() -> usize {
+    1
+}
+
+fn default_rays_per_target() -> usize {
+    1
+}
+
+fn default_no_signal_color() -> [u8; 3] {
+    [128, 128, 128]
+}
+
+fn default_max_delay() -> f32 {
+    1000.0
+}
+
+fn default_light_sample_probability() -> f32 {
+    1.0
+}
+
+fn default_elevation_min() -> f32 {
+    -90.0
+}
+
+fn default_elevation_max() -> f32 {
+    90.0
+}
+
+/// Light from infinitely far away in one direction, lighting the preview render
+/// along with the sky and the light objects.
+#[serde_with::serde_as]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Sun {
+    // Direction from the scene towards the sun; it need not be normalized.
+    pub direction: Point3D,
+    #[serde_as(as = "SrgbAsArray")]
+    pub color: Srgb,
+    pub intensity: f32,
+}
+
+/// Area of the signal map to keep, in meters on the ground. Points outside
+/// it are set to no signal.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Mask {
+    Circle { x: f32, z: f32, radius: f32 },
+}
+
+impl Mask {
+    pub fn contains(&self, x: f32, z: f32) -> bool {
+        match self {
+            Mask::Circle { x: cx, z: cz, radius } => {
+                (x - cx).powi(2) + (z - cz).powi(2) <= radius.powi(2)
+            }
+        }
+    }
+}
+
+/// Fraction of the signal amplitude the ground reflects at `frequency`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct GroundReflection {
+    pub frequency: Frequency,
+    pub coefficient: f32,
+}
+
+/// Rooftop diffraction in the signal map: rays hitting the roof of a building at
+/// least `min_height` tall also continue from its far edge down towards the street.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Diffraction {
+    // Loss in dB of the diffracted ray.
+    pub loss: f32,
+    #[serde(default)]
+    pub min_height: f32,
+    // The diffracted ray leaves at a random angle up to this many degrees below the
+    // horizon.
+    #[serde(default = "default_diffraction_angle")]
+    pub max_angle: f32,
+}
+
+/// Most bounces a ray can have made, counting the current one, and still scatter off
+/// a material of each kind. A ray running out stops there, like one reaching
+/// `max_depth`. Kinds left out get the whole `max_depth`, so glass can refract a ray
+/// through many panes while diffuse surfaces, which rarely send much on, stop early.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BounceBudget {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lambertian: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metal: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub glass: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub texture: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wall: Option<usize>,
+}
+
+impl BounceBudget {
+    /// Whether a ray on its `bounce`th bounce, counting from 1, may scatter off `material`.
+    pub fn allows(&self, material: &Material, bounce: usize) -> bool {
+        let limit = match material {
+            Material::Lambertian(_) => self.lambertian,
+            Material::Metal(_) => self.metal,
+            Material::Glass(_) => self.glass,
+            Material::Texture(_) => self.texture,
+            Material::Wall(_) => self.wall,
+            Material::Light(_) => None,
+        };
+        limit.is_none_or(|limit| bounce <= limit)
+    }
+}
+
+fn default_diffraction_angle() -> f32 {
+    45.0
+}
+
+/// How the rays toward a point of the signal map grid are spread over it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Subsampling {
+    /// A square grid of subsamples.
+    #[default]
+    Grid,
+    /// A row of subsamples across the direction from the station, resolving the
+    /// edges of its beams more finely.
+    Azimuthal,
+}
+
+/// How the signal map combines the rays arriving at the same point.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Aggregation {
+    /// Keep the strongest ray.
+    #[default]
+    Max,
+    /// Add up the power of all rays, as for incoherent multipath. Each of the rays
+    /// launched towards a point carries an equal share of the power sent its way, so
+    /// a single path comes out as with `Max`.
+    SumLinear,
+}
+
+/// Filter used to resize images to `image_scale`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ImageFilter {
+    /// Repeat each pixel, keeping the edges between pixels sharp.
+    #[default]
+    Nearest,
+    /// Smooth interpolation, for maps meant for print.
+    Lanczos,
+}
+
+/// Resizes an rgb image of `bounds` by `scale` with `filter`. Returns the pixels and their
+/// bounds, or the image unchanged at a scale of 1.
+pub(crate) fn resize_image(
+    pixels: &[u8],
+    bounds: (usize, usize),
+    scale: f32,
+    filter: ImageFilter,
+) -> (Vec<u8>, (usize, usize)) {
+    let width = ((bounds.0 as f32 * scale).round() as usize).max(1);
+    let height = ((bounds.1 as f32 * scale).round() as usize).max(1);
+    if (width, height) == bounds {
+        return (pixels.to_vec(), bounds);
+    }
+    let image = image::RgbImage::from_raw(bounds.0 as u32, bounds.1 as u32, pixels.to_vec())
+        .expect("The pixels fill the image");
+    let filter = match filter {
+        ImageFilter::Nearest => image::imageops::FilterType::Nearest,
+        ImageFilter::Lanczos => image::imageops::FilterType::Lanczos3,
+    };
+    let resized = image::imageops::resize(&image, width as u32, height as u32, filter);
+    (resized.into_raw(), (width, height))
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
+    #[serde(default = "default_version")]
+    pub version: u32,
     pub width: usize,
     pub height: usize,
+    // Size in meters of the area the signal map covers with its `width` x
+    // `height` pixels. Version 1 configs used one pixel per meter.
+    #[serde(default)]
+    pub world_width: f32,
+    #[serde(default)]
+    pub world_height: f32,
+    // World position of the corner of the signal map, so the map can cover an
+    // area away from the origin. Only x and z are used.
+    #[serde(default = "default_grid_origin")]
+    pub grid_origin: Point3D,
     pub samples_per_pixel: u32,
     pub max_depth: usize,
     pub sky: Option<Sky>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sun: Option<Sun>,
     pub camera: Camera,
     pub objects: Vec<Cube>,
     pub nr_probes: i64,
+    // Received strength in dBm below which a pixel is considered unserved.
+    #[serde(default = "default_noise_floor")]
+    pub noise_floor: f32,
+    // Receiver noise figure in dB and bandwidth in Hz. With a bandwidth, the
+    // signal-to-noise ratio is written next to each map.
+    #[serde(default)]
+    pub receiver_noise_figure_db: f32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bandwidth_hz: Option<f64>,
+    // Additional resolutions, as fractions of width/height, at which to write
+    // each signal map. Files get a `_<width>x<height>` suffix.
+    #[serde(default)]
+    pub output_scales: Vec<f32>,
+    // Factor by which every image is resized before it is written, with
+    // `image_filter`. Unlike `output_scales`, the map is not resampled.
+    #[serde(default = "default_image_scale")]
+    pub image_scale: f32,
+    #[serde(default)]
+    pub image_filter: ImageFilter,
+    // Hits below this height count as ground hits in the signal map. f32 hit
+    // points lose precision with their distance from the origin, so scenes
+    // spanning kilometers need a looser value than the default.
+    #[serde(default = "default_ground_epsilon")]
+    pub ground_epsilon: f32,
+    // Minimum distance along a ray for a hit in the signal map, which keeps a
+    // scattered ray from hitting the surface it leaves. Should stay well below
+    // `ground_epsilon`, or rays can skip over the ground.
+    #[serde(default = "default_t_min")]
+    pub t_min: f32,
+    // Points traced per pixel in each direction. Above 1, the png is
+    // antialiased and the data files keep the strongest point of each pixel.
+    #[serde(default = "default_supersampling")]
+    pub supersampling: usize,
+    // Rays launched towards each subsample of a grid point. Repeats get a
+    // jittered direction, which smooths out noise at the beam edges.
+    #[serde(default = "default_rays_per_target")]
+    pub rays_per_target: usize,
+    // Height of the receivers above the ground. Above 0, signal is recorded
+    // where rays cross this height rather than where they hit the ground.
+    #[serde(default)]
+    pub receiver_height: f32,
+    // Only rays arriving at an elevation in this range, in degrees above the
+    // horizon, are recorded. Models receivers that only look one way.
+    #[serde(default = "default_elevation_min")]
+    pub elevation_min: f32,
+    #[serde(default = "default_elevation_max")]
+    pub elevation_max: f32,
+    // Ground hits of rays coming in flatter than this, in degrees between the ray
+    // and the ground, are not recorded. Such rays carry little power to a receiver.
+    #[serde(default)]
+    pub min_grazing_angle: f32,
+    // Propagation delay in nanoseconds drawn as red in the delay png.
+    #[serde(default = "default_max_delay")]
+    pub max_delay: f32,
+    // Color of the points without signal in the signal map pngs.
+    #[serde(default = "default_no_signal_color")]
+    pub no_signal_color: [u8; 3],
+    // Walls a signal ray may pass through. A ray reaching one more wall is
+    // dropped. Unlike bounces, crossings do not count towards `max_depth`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_penetrations: Option<usize>,
+    #[serde(default)]
+    pub aggregation: Aggregation,
+    #[serde(default)]
+    pub subsampling: Subsampling,
+    // Range of light strengths in dBm accepted by `validate`. Anything outside it
+    // is most likely a gain in the wrong unit.
+    #[serde(default = "default_strength_range")]
+    pub strength_range: (f32, f32),
+    // Chance that a diffuse hit in the preview samples a light directly rather
+    // than only finding one by bouncing. Lower is faster but noisier.
+    #[serde(default = "default_light_sample_probability")]
+    pub light_sample_probability: f32,
+    // Only the signal within this area is kept, as for a licensed area.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mask: Option<Mask>,
+    // Reflection coefficients of the ground by frequency. When given, rays hitting
+    // the ground reflect off it like a mirror instead of scattering according to
+    // the ground object's material.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ground_reflection: Vec<GroundReflection>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub diffraction: Option<Diffraction>,
+    // Fewer bounces than `max_depth` for some materials, in the view and the map.
+    #[serde(default)]
+    pub bounce_budget: BounceBudget,
+    // Ground types with their losses. Without it the ground is uniform.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub land_cover: Option<LandCover>,
+    // Receiver positions for which `link_budget` reports the path loss.
+    #[serde(default)]
+    pub probes: Vec<Point3D>,
+    // Needed when objects are placed with `geo` positions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub geo_reference: Option<GeoReference>,
+}
+
+// In J/K, and the standard noise temperature in K.
+const BOLTZMANN: f64 = 1.380_649e-23;
+const NOISE_TEMPERATURE: f64 = 290.0;
+
+// Mean earth radius in meters.
+const EARTH_RADIUS: f64 = 6_371_008.8;
+
+/// Geographic point the scene origin corresponds to.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct GeoReference {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+impl GeoReference {
+    /// Latitude and longitude of the ground position of scene point `point`, the
+    /// inverse of `GeoPosition::to_local`.
+    pub fn to_geo(&self, point: Point3D) -> (f64, f64) {
+        let lat = self.lat + (point.z() as f64 / EARTH_RADIUS).to_degrees();
+        let lon = self.lon
+            + (point.x() as f64 / (self.lat.to_radians().cos() * EARTH_RADIUS)).to_degrees();
+        (lat, lon)
+    }
+}
+
+/// Position given in degrees latitude/longitude and meters above the ground.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct GeoPosition {
+    pub lat: f64,
+    pub lon: f64,
+    pub height: f32,
+}
+
+impl GeoPosition {
+    /// Converts to scene coordinates with x pointing east, y up and z north,
+    /// using a flat-earth approximation around `reference` that holds well
+    /// over the few kilometers a scene spans.
+    pub fn to_local(&self, reference: &GeoReference) -> Point3D {
+        let north = (self.lat - reference.lat).to_radians() * EARTH_RADIUS;
+        let east =
+            (self.lon - reference.lon).to_radians() * reference.lat.to_radians().cos() * EARTH_RADIUS;
+        Point3D::new(east as f32, self.height, north as f32)
+    }
+}
+
+/// Overview of what a scene contains, see `Config::summary`.
+#[derive(Debug, PartialEq)]
+pub struct SceneSummary {
+    pub lambertian: usize,
+    pub metal: usize,
+    pub glass: usize,
+    pub texture: usize,
+    pub lights: usize,
+    pub walls: usize,
+    // Memory taken by the decoded sky and object textures.
+    pub texture_bytes: usize,
+    // Box enclosing all objects, if there are any.
+    pub bounds: Option<(Point3D, Point3D)>,
+}
+
+impl std::fmt::Display for SceneSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Objects: {} lambertian, {} metal, {} glass, {} texture, {} lights, {} walls",
+            self.lambertian, self.metal, self.glass, self.texture, self.lights, self.walls
+        )?;
+        writeln!(f, "Texture memory: {} bytes", self.texture_bytes)?;
+        match self.bounds {
+            Some((min, max)) => write!(
+                f,
+                "Scene bounds: ({}, {}, {}) to ({}, {}, {})",
+                min.x(),
+                min.y(),
+                min.z(),
+                max.x(),
+                max.y(),
+                max.z()
+            ),
+            None => write!(f, "Scene bounds: empty"),
+        }
+    }
+}
+
+/// Format a config file is written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Json,
+    Ron,
+    Yaml,
+}
+
+impl ConfigFormat {
+    /// The format for the extension of `path`: `.ron`, `.yaml` or `.yml`, and json for
+    /// anything else.
+    pub fn from_path(path: &Path) -> ConfigFormat {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("ron") => ConfigFormat::Ron,
+            Some("yaml") | Some("yml") => ConfigFormat::Yaml,
+            _ => ConfigFormat::Json,
+        }
+    }
+
+    /// The config in `text` as a json value, for checks on the config as written.
+    pub fn parse_value(&self, text: &[u8]) -> Result<serde_json::Value, RaytracerError> {
+        let parse_error = |e: &dyn std::fmt::Display| RaytracerError::Parse(e.to_string());
+        match self {
+            ConfigFormat::Json => Ok(serde_json::from_slice(text)?),
+            // RON names struct fields without quotes, which only its own value type reads.
+            ConfigFormat::Ron => ron_options()
+                .from_bytes::<ron::Value>(text)
+                .map_err(|e| parse_error(&e))?
+                .into_rust()
+                .map_err(|e| parse_error(&e)),
+            ConfigFormat::Yaml => {
+                let value = serde_yaml::from_slice(text).map_err(|e| parse_error(&e))?;
+                Ok(serde_json::to_value(untag_yaml(value))?)
+            }
+        }
+    }
+}
+
+/// Writes YAML tags, such as the `!Lambertian` of a material, as the single-key map json
+/// uses for them.
+fn untag_yaml(value: serde_yaml::Value) -> serde_yaml::Value {
+    match value {
+        serde_yaml::Value::Tagged(tagged) => {
+            let tag = tagged.tag.to_string().trim_start_matches('!').to_string();
+            let mut mapping = serde_yaml::Mapping::new();
+            mapping.insert(tag.into(), untag_yaml(tagged.value));
+            serde_yaml::Value::Mapping(mapping)
+        }
+        serde_yaml::Value::Sequence(items) => {
+            serde_yaml::Value::Sequence(items.into_iter().map(untag_yaml).collect())
+        }
+        serde_yaml::Value::Mapping(mapping) => serde_yaml::Value::Mapping(
+            mapping
+                .into_iter()
+                .map(|(key, value)| (key, untag_yaml(value)))
+                .collect(),
+        ),
+        other => other,
+    }
+}
+
+/// `Config::terrain_height` of the objects in `world`.
+fn height_below(world: &World<&Cube>, x: f32, z: f32) -> f32 {
+    let above = world.bounding_box().1.y().max(0.0) + 1.0;
+    let down = Ray::shadow(Point3D::new(x, above, z), Point3D::new(0.0, -1.0, 0.0));
+    world
+        .hit(&down, 0.0, f32::MAX)
+        .map_or(0.0, |hit| hit.point.y().max(0.0))
+}
+
+// Optional fields are given without `Some(..)`, as in the other formats.
+fn ron_options() -> ron::Options {
+    ron::Options::default().with_default_extension(ron::extensions::Extensions::IMPLICIT_SOME)
+}
+
+impl Config {
+    /// Parses a json config without reading it into memory first.
+    pub fn from_reader<R: Read>(reader: R) -> Result<Config, RaytracerError> {
+        let mut config: Config = serde_json::from_reader(reader)?;
+        config.resolve_positions()?;
+        Ok(config)
+    }
+
+    /// Parses a config in the given format.
+    pub fn from_reader_as<R: Read>(
+        reader: R,
+        format: ConfigFormat,
+    ) -> Result<Config, RaytracerError> {
+        let parse_error = |e: &dyn std::fmt::Display| RaytracerError::Parse(e.to_string());
+        let mut config: Config = match format {
+            ConfigFormat::Json => return Config::from_reader(reader),
+            ConfigFormat::Ron => ron_options()
+                .from_reader(reader)
+                .map_err(|e| parse_error(&e))?,
+            ConfigFormat::Yaml => serde_yaml::from_reader(reader).map_err(|e| parse_error(&e))?,
+        };
+        config.resolve_positions()?;
+        Ok(config)
+    }
+
+    /// Reads the config in `path`, in the format its extension gives.
+    pub fn from_path(path: &Path) -> Result<Config, RaytracerError> {
+        let file = File::open(path).map_err(|e| {
+            std::io::Error::new(
+                e.kind(),
+                format!("Unable to read {}: {}", path.display(), e),
+            )
+        })?;
+        Config::from_reader_as(BufReader::new(file), ConfigFormat::from_path(path)).map_err(|e| {
+            match e {
+                RaytracerError::Parse(message) => RaytracerError::Parse(format!(
+                    "Unable to parse {}: {}",
+                    path.display(),
+                    message
+                )),
+                e => e,
+            }
+        })
+    }
+
+    /// Sets the origin of the objects placed by latitude/longitude, then puts the
+    /// stations with a `height_agl` at that height above the surface below them.
+    pub fn resolve_positions(&mut self) -> Result<(), RaytracerError> {
+        self.resolve_geo_positions()?;
+        self.place_stations();
+        Ok(())
+    }
+
+    /// Sets the origin of every object placed by latitude/longitude.
+    pub fn resolve_geo_positions(&mut self) -> Result<(), RaytracerError> {
+        for object in self.objects.iter_mut() {
+            if let Some(geo) = object.geo {
+                let reference = self.geo_reference.as_ref().ok_or_else(|| {
+                    RaytracerError::Validation(format!(
+                        "Object {} has a geo position but the config has no geo_reference",
+                        object.id
+                    ))
+                })?;
+                object.origin = geo.to_local(reference);
+            }
+        }
+        Ok(())
+    }
+
+    /// Height of the highest surface at `(x, z)`: the top of the highest object there
+    /// other than a station, so a roof where there is a building, or 0 on open ground.
+    pub fn terrain_height(&self, x: f32, z: f32) -> f32 {
+        let objects = self.objects_by_material(|m| !matches!(m, Material::Light(_)));
+        height_below(&World::new(&objects), x, z)
+    }
+
+    /// Moves each station with a `height_agl` to that height above the surface below it.
+    fn place_stations(&mut self) {
+        let objects = self.objects_by_material(|m| !matches!(m, Material::Light(_)));
+        let world = World::new(&objects);
+        let heights: Vec<Option<f32>> = self
+            .objects
+            .iter()
+            .map(|object| match &object.material {
+                Material::Light(light) => light.height_agl.map(|height| {
+                    height_below(&world, object.origin.x(), object.origin.z()) + height
+                }),
+                _ => None,
+            })
+            .collect();
+        for (object, height) in self.objects.iter_mut().zip(heights) {
+            if let Some(height) = height {
+                object.origin = Point3D::new(object.origin.x(), height, object.origin.z());
+            }
+        }
+    }
+
+    pub fn to_writer<W: Write>(&self, writer: W) -> Result<(), serde_json::Error> {
+        serde_json::to_writer(writer, self)
+    }
+
+    /// Objects whose material satisfies `pred`, in config order.
+    pub fn objects_by_material(&self, pred: impl Fn(&Material) -> bool) -> Vec<&Cube> {
+        self.objects.iter().filter(|o| pred(&o.material)).collect()
+    }
+
+    pub fn summary(&self) -> SceneSummary {
+        let mut summary = SceneSummary {
+            lambertian: 0,
+            metal: 0,
+            glass: 0,
+            texture: 0,
+            lights: 0,
+            walls: 0,
+            texture_bytes: 0,
+            bounds: None,
+        };
+        if let Some(Sky {
+            texture: Some((pixels, _, _, _)),
+        }) = &self.sky
+        {
+            summary.texture_bytes += pixels.len();
+        }
+        for object in self.objects.iter() {
+            match &object.material {
+                Material::Lambertian(_) => summary.lambertian += 1,
+                Material::Metal(_) => summary.metal += 1,
+                Material::Glass(_) => summary.glass += 1,
+                Material::Texture(t) => {
+                    summary.texture += 1;
+                    summary.texture_bytes += t.pixels.len();
+                }
+                Material::Light(_) => summary.lights += 1,
+                Material::Wall(_) => summary.walls += 1,
+            }
+            let (min, max) = object.bounding_box();
+            summary.bounds = Some(match summary.bounds {
+                None => (min, max),
+                Some((lo, hi)) => (lo.min(&min), hi.max(&max)),
+            });
+        }
+        summary
+    }
+
+    /// Upgrades a config parsed from an older format version to `CONFIG_VERSION`,
+    /// filling fields introduced since then with their documented defaults.
+    /// Fails on configs written by a newer build, whose semantics we cannot know.
+    pub fn migrate(&mut self) -> Result<(), RaytracerError> {
+        if self.version > CONFIG_VERSION {
+            return Err(RaytracerError::Validation(format!(
+                "Config version {} is newer than the supported version {}",
+                self.version, CONFIG_VERSION
+            )));
+        }
+        if self.version == 0 {
+            return Err(RaytracerError::Validation(
+                "Config version 0 is not valid".to_string(),
+            ));
+        }
+        // Each step upgrades from version n to n + 1.
+        while self.version < CONFIG_VERSION {
+            if self.version == 1 {
+                self.world_width = self.width as f32;
+                self.world_height = self.height as f32;
+            }
+            self.version += 1;
+        }
+        Ok(())
+    }
+
+    /// Checks the scene for values that would make tracing meaningless, listing every
+    /// station whose strength is outside `strength_range`.
+    pub fn validate(&self) -> Result<(), RaytracerError> {
+        // Version 1 configs get these from `migrate`, later ones have to give them.
+        if !(self.world_width > 0.0 && self.world_height > 0.0) {
+            return Err(RaytracerError::Validation(format!(
+                "World size {} by {} m is not positive, set world_width and world_height",
+                self.world_width, self.world_height
+            )));
+        }
+        if let Some(bandwidth) = self.bandwidth_hz.filter(|b| b.is_nan() || *b <= 0.0) {
+            return Err(RaytracerError::Validation(format!(
+                "Receiver bandwidth_hz {} is not positive",
+                bandwidth
+            )));
+        }
+        // A coefficient of zero would be an infinite loss, above one a gain.
+        if let Some(entry) = self
+            .ground_reflection
+            .iter()
+            .find(|e| !(e.coefficient > 0.0 && e.coefficient <= 1.0))
+        {
+            return Err(RaytracerError::Validation(format!(
+                "Ground reflection coefficient {} at {} is not in (0, 1]",
+                entry.coefficient, entry.frequency
+            )));
+        }
+        let (min, max) = self.strength_range;
+        let offending: Vec<String> = self
+            .objects
+            .iter()
+            .filter_map(|object| match &object.material {
+                Material::Light(light) if !(min..=max).contains(&light.strength) => {
+                    Some(format!("station {} ({} dBm)", object.id, light.strength))
+                }
+                _ => None,
+            })
+            .collect();
+        if offending.is_empty() {
+            Ok(())
+        } else {
+            Err(RaytracerError::Validation(format!(
+                "Light strength outside {} to {} dBm: {}",
+                min,
+                max,
+                offending.join(", ")
+            )))
+        }
+    }
+
+    /// Sets the frequency of every light.
+    pub fn set_frequency(&mut self, frequency: Frequency) {
+        for object in self.objects.iter_mut() {
+            if let Material::Light(light) = &mut object.material {
+                light.frequency = frequency;
+            }
+        }
+    }
+
+    /// Loss in dB of a ray of `frequency` reflecting off the ground, with the
+    /// coefficient interpolated between the nearest `ground_reflection` entries.
+    /// `None` without ground reflection.
+    pub fn ground_reflection_loss(&self, frequency: Frequency) -> Option<f32> {
+        let entries = self.ground_reflection.iter();
+        let below = entries
+            .clone()
+            .filter(|e| e.frequency <= frequency)
+            .max_by_key(|e| e.frequency);
+        let above = entries
+            .filter(|e| e.frequency >= frequency)
+            .min_by_key(|e| e.frequency);
+        let coefficient = match (below, above) {
+            (Some(below), Some(above)) if below.frequency != above.frequency => {
+                let fraction = (frequency.hz() - below.frequency.hz()) as f32
+                    / (above.frequency.hz() - below.frequency.hz()) as f32;
+                below.coefficient + (above.coefficient - below.coefficient) * fraction
+            }
+            (Some(nearest), _) | (None, Some(nearest)) => nearest.coefficient,
+            (None, None) => return None,
+        };
+        Some(-20.0 * coefficient.log10())
+    }
+
+    /// Thermal noise over `bandwidth_hz` at 290 K plus the receiver's noise figure,
+    /// in dBm, or `None` without a bandwidth.
+    pub fn noise_dbm(&self) -> Option<f32> {
+        self.bandwidth_hz.map(|bandwidth| {
+            let ktb_mw = BOLTZMANN * NOISE_TEMPERATURE * bandwidth * 1000.0;
+            (10.0 * ktb_mw.log10()) as f32 + self.receiver_noise_figure_db
+        })
+    }
+
+    /// Position of `point` relative to the corner of the signal map, at the same height.
+    pub fn map_position(&self, point: Point3D) -> Point3D {
+        point - Point3D::new(self.grid_origin.x(), 0.0, self.grid_origin.z())
+    }
+
+    /// Size of a signal map pixel in meters, along x and z.
+    pub fn pixel_size(&self) -> (f32, f32) {
+        (
+            self.world_width / self.width as f32,
+            self.world_height / self.height as f32,
+        )
+    }
+
+    /// Resizes the whole scene by `factor` about the origin, for instance to turn a
+    /// scene drawn in feet into meters. The map keeps its `width` x `height` pixels,
+    /// which grow with the area they cover.
+    pub fn scale(&mut self, factor: f32) {
+        self.world_width *= factor;
+        self.world_height *= factor;
+        self.grid_origin = self.grid_origin * factor;
+        self.camera = self.camera.scaled(factor);
+        for object in self.objects.iter_mut() {
+            object.origin = object.origin * factor;
+            object.dim_x *= factor;
+            object.dim_y *= factor;
+            object.dim_z *= factor;
+            if let Material::Light(light) = &mut object.material {
+                light.aperture.width *= factor;
+                light.aperture.height *= factor;
+                if let Some(height) = &mut light.height_agl {
+                    *height *= factor;
+                }
+            }
+        }
+        self.receiver_height *= factor;
+        if let Some(Mask::Circle { x, z, radius }) = &mut self.mask {
+            *x *= factor;
+            *z *= factor;
+            *radius *= factor;
+        }
+        if let Some(diffraction) = &mut self.diffraction {
+            diffraction.min_height *= factor;
+        }
+        for probe in self.probes.iter_mut() {
+            *probe = *probe * factor;
+        }
+    }
+}
+
+#[cfg(test)]
+const V1_CONFIG: &str = r#"{
+    "width": 10,
+    "height": 10,
+    "samples_per_pixel": 1,
+    "nr_probes": 0,
+    "max_depth": 5,
+    "camera": {
+        "look_from": {"x": 0.0, "y": 10.0, "z": 0.0},
+        "look_at": {"x": 5.0, "y": 0.0, "z": 5.0},
+        "vup": {"x": 0.0, "y": 1.0, "z": 0.0},
+        "vfov": 50.0,
+        "aspect": 1.0
+    },
+    "objects": []
+}"#;
+
+/// Builds a minimal scene of the given size for tests elsewhere in the crate.
+#[cfg(test)]
+pub(crate) fn test_config(width: usize, height: usize, objects: Vec<Cube>) -> Config {
+    let mut config = serde_json::from_str::<Config>(V1_CONFIG).unwrap();
+    config.width = width;
+    config.height = height;
+    config.objects = objects;
+    config.migrate().unwrap();
+    config
+}
+
+#[test]
+fn test_migrate_v1() {
+    let mut config = serde_json::from_str::<Config>(V1_CONFIG).unwrap();
+    assert_eq!(config.version, 1);
+    config.migrate().unwrap();
+    assert_eq!(config.version, CONFIG_VERSION);
+    assert_eq!(config.width, 10);
+    assert_eq!(config.max_depth, 5);
+    assert_eq!((config.world_width, config.world_height), (10.0, 10.0));
+    assert_eq!(config.pixel_size(), (1.0, 1.0));
+}
+
+#[test]
+fn test_check_missing_texture() {
+    let mut json = serde_json::from_str::<serde_json::Value>(V1_CONFIG).unwrap();
+    json["sky"] = serde_json::json!({"texture": "/nonexistent/sky.jpg"});
+    let problems = check_textures(&json);
+    assert_eq!(problems.len(), 1);
+    assert!(problems[0].starts_with("sky: Could not open /nonexistent/sky.jpg"));
+    // Parsing reports the same problem as an error instead of panicking.
+    let error = serde_json::from_value::<Config>(json).unwrap_err();
+    assert!(error.to_string().contains("/nonexistent/sky.jpg"));
 }
 
+#[test]
+fn test_check_config_reports_all_problems() {
+    let mut json = serde_json::from_str::<serde_json::Value>(V1_CONFIG).unwrap();
+    json["sky"] = serde_json::json!({"texture": "/nonexistent/sky.jpg"});
+    json["land_cover"] = serde_json::json!({"image": "/nonexistent/cover.png", "classes": []});
+    json["objects"] = serde_json::json!([{
+        "origin": {"x": 1.0, "y": 1.0, "z": 1.0}, "dim_x": 0.5, "dim_y": 0.5, "dim_z": 0.5,
+        "id": 4, "material": {"Light": {
+            "color": [1.0, 1.0, 1.0], "strength": -200.0, "beams": 1, "frequency": 2000.0
+        }}
+    }]);
+    let problems = check_config(json.to_string().as_bytes(), ConfigFormat::Json);
+    assert_eq!(problems.len(), 3, "{:?}", problems);
+    assert!(problems[0].starts_with("sky: Could not open /nonexistent/sky.jpg"));
+    assert!(problems[1].starts_with("land_cover: Could not open /nonexistent/cover.png"));
+    assert_eq!(problems[2], "Light strength outside -30 to 60 dBm: station 4 (-200 dBm)");
+}
+
+#[test]
+fn test_summary() {
+    use crate::materials::{Lambertian, Light};
+    use palette::Srgb;
+    let lambertian = Material::Lambertian(Lambertian::new(Srgb::new(0.5, 0.5, 0.5)));
+    let light = Material::Light(Light::new(
+        Srgb::new(1.0, 1.0, 1.0),
+        30.0,
+        3,
+        Frequency::from_mhz(2000.0),
+    ));
+    let objects = vec![
+        Cube::new(Point3D::new(0.0, 0.0, 0.0), 1.0, 1.0, 1.0, lambertian.clone(), 0),
+        Cube::new(Point3D::new(5.0, 0.0, 0.0), 1.0, 1.0, 1.0, lambertian.clone(), 1),
+        Cube::new(Point3D::new(0.0, 4.0, 0.0), 1.0, 1.0, 1.0, lambertian, 2),
+        Cube::new(Point3D::new(0.0, 0.0, -3.0), 1.0, 1.0, 1.0, light.clone(), 3),
+        Cube::new(Point3D::new(2.0, 2.0, 2.0), 1.0, 1.0, 1.0, light, 4),
+    ];
+    let summary = test_config(10, 10, objects).summary();
+    assert_eq!(summary.lambertian, 3);
+    assert_eq!(summary.lights, 2);
+    assert_eq!(summary.metal + summary.glass + summary.texture + summary.walls, 0);
+    assert_eq!(summary.texture_bytes, 0);
+    assert_eq!(
+        summary.bounds,
+        Some((Point3D::new(-1.0, -1.0, -4.0), Point3D::new(6.0, 5.0, 3.0)))
+    );
+}
+
+#[test]
+fn test_reader_writer_round_trip() {
+    let config = Config::from_reader(V1_CONFIG.as_bytes()).unwrap();
+    let mut buffer = vec![];
+    config.to_writer(&mut buffer).unwrap();
+    let copy = Config::from_reader(buffer.as_slice()).unwrap();
+    assert_eq!(copy.width, config.width);
+    assert_eq!(copy.max_depth, config.max_depth);
+    assert_eq!(copy.camera.origin, config.camera.origin);
+    assert_eq!(copy.camera.lower_left_corner, config.camera.lower_left_corner);
+    let mut copy_buffer = vec![];
+    copy.to_writer(&mut copy_buffer).unwrap();
+    assert_eq!(copy_buffer, buffer);
+}
+
+#[test]
+fn test_geo_position_to_local() {
+    let reference = GeoReference {
+        lat: 52.0,
+        lon: 5.0,
+    };
+    let position = GeoPosition {
+        lat: 52.001,
+        lon: 5.001,
+        height: 30.0,
+    };
+    let local = position.to_local(&reference);
+    // A thousandth of a degree is 111.2m north-south, and cos(52) of that east-west.
+    assert!((local.z() - 111.195).abs() < 0.01);
+    assert!((local.x() - 68.459).abs() < 0.01);
+    assert_eq!(local.y(), 30.0);
+}
+
+#[test]
+fn test_resolve_geo_positions() {
+    let mut json = serde_json::from_str::<serde_json::Value>(V1_CONFIG).unwrap();
+    json["objects"] = serde_json::json!([{
+        "geo": {"lat": 51.999, "lon": 5.0, "height": 10.0},
+        "dim_x": 1.0, "dim_y": 1.0, "dim_z": 1.0, "id": 7,
+        "material": {"Glass": {"index_of_refraction": 1.5}}
+    }]);
+    let text = json.to_string();
+    let error = Config::from_reader(text.as_bytes()).unwrap_err();
+    assert!(error.to_string().contains("no geo_reference"));
+    json["geo_reference"] = serde_json::json!({"lat": 52.0, "lon": 5.0});
+    let config = Config::from_reader(json.to_string().as_bytes()).unwrap();
+    assert!((config.objects[0].origin.z() + 111.195).abs() < 0.01);
+    assert!(config.objects[0].origin.x().abs() < 1e-6);
+}
+
+#[test]
+fn test_height_agl() {
+    let mut json = serde_json::from_str::<serde_json::Value>(V1_CONFIG).unwrap();
+    let block = |x: f32, top: f32, id: i64| serde_json::json!({
+        "origin": {"x": x, "y": top / 2.0, "z": 0.0},
+        "dim_x": 5.0, "dim_y": top / 2.0, "dim_z": 5.0, "id": id,
+        "material": {"Lambertian": {"albedo": [0.5, 0.5, 0.5]}}
+    });
+    let station = |x: f32, height_agl: Option<f32>, id: i64| serde_json::json!({
+        "origin": {"x": x, "y": 3.0, "z": 0.0},
+        "dim_x": 0.5, "dim_y": 0.5, "dim_z": 0.5, "id": id,
+        "material": {"Light": {
+            "color": [1.0, 1.0, 1.0], "strength": 30.0, "beams": 1, "frequency": 2000.0,
+            "height_agl": height_agl
+        }}
+    });
+    // A slope rising in steps to the east.
+    json["objects"] = serde_json::json!([
+        block(5.0, 2.0, 1),
+        block(15.0, 6.0, 2),
+        station(5.0, Some(10.0), 3),
+        station(15.0, Some(10.0), 4),
+        station(15.0, None, 5),
+    ]);
+    let config = Config::from_reader(json.to_string().as_bytes()).unwrap();
+    assert_eq!(config.terrain_height(5.0, 0.0), 2.0);
+    assert_eq!(config.terrain_height(30.0, 0.0), 0.0);
+    let heights: Vec<f32> = config.objects[2..].iter().map(|o| o.origin.y()).collect();
+    assert_eq!(heights, vec![12.0, 16.0, 3.0]);
+}
+
+#[test]
+fn test_validate_world_size() {
+    let mut json = serde_json::from_str::<serde_json::Value>(V1_CONFIG).unwrap();
+    json["version"] = serde_json::json!(CONFIG_VERSION);
+    let mut config = serde_json::from_value::<Config>(json.clone()).unwrap();
+    config.migrate().unwrap();
+    let error = config.validate().unwrap_err();
+    assert!(error.to_string().contains("world_width"), "{}", error);
+    json["world_width"] = serde_json::json!(20.0);
+    json["world_height"] = serde_json::json!(20.0);
+    let mut config = serde_json::from_value::<Config>(json).unwrap();
+    config.migrate().unwrap();
+    assert!(config.validate().is_ok());
+}
+
+#[test]
+fn test_validate_bandwidth() {
+    let mut config = test_config(10, 10, vec![]);
+    let bandwidths = [(None, true), (Some(20e6), true), (Some(0.0), false), (Some(-1.0), false)];
+    for (bandwidth, valid) in bandwidths {
+        config.bandwidth_hz = bandwidth;
+        assert_eq!(config.validate().is_ok(), valid, "bandwidth {:?}", bandwidth);
+    }
+}
+
+#[test]
+fn test_validate_ground_reflection() {
+    let mut config = test_config(10, 10, vec![]);
+    for (coefficient, valid) in [(1.0, true), (0.3, true), (0.0, false), (1.5, false)] {
+        config.ground_reflection = vec![GroundReflection {
+            frequency: Frequency::from_mhz(2000.0),
+            coefficient,
+        }];
+        assert_eq!(config.validate().is_ok(), valid, "coefficient {}", coefficient);
+    }
+    config.ground_reflection[0].coefficient = f32::NAN;
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_migrate_newer_version() {
+    let mut config = serde_json::from_str::<Config>(V1_CONFIG).unwrap();
+    config.version = CONFIG_VERSION + 1;
+    assert!(matches!(config.migrate(), Err(RaytracerError::Validation(_))));
+}
+
+#[test]
+fn test_objects_by_material() {
+    use crate::materials::{Glass, Lambertian, Light};
+    use palette::Srgb;
+    let glass = Material::Glass(Glass::new(1.5));
+    let objects = vec![
+        Cube::new(Point3D::new(0.0, 0.0, 0.0), 1.0, 1.0, 1.0, glass.clone(), 0),
+        Cube::new(
+            Point3D::new(5.0, 0.0, 0.0),
+            1.0,
+            1.0,
+            1.0,
+            Material::Lambertian(Lambertian::new(Srgb::new(0.5, 0.5, 0.5))),
+            1,
+        ),
+        Cube::new(Point3D::new(0.0, 4.0, 0.0), 1.0, 1.0, 1.0, glass, 2),
+        Cube::new(
+            Point3D::new(0.0, 0.0, -3.0),
+            1.0,
+            1.0,
+            1.0,
+            Material::Light(Light::new(
+                Srgb::new(1.0, 1.0, 1.0),
+                30.0,
+                3,
+                Frequency::from_mhz(2000.0),
+            )),
+            3,
+        ),
+    ];
+    let config = test_config(10, 10, objects);
+    let glass = config.objects_by_material(|m| matches!(m, Material::Glass(_)));
+    assert_eq!(glass.iter().map(|o| o.id).collect::<Vec<_>>(), vec![0, 2]);
+    assert!(config.objects_by_material(|m| matches!(m, Material::Metal(_))).is_empty());
+}
+
+#[test]
+fn test_ground_reflection_loss() {
+    let mhz = Frequency::from_mhz;
+    let mut config = test_config(10, 10, vec![]);
+    assert_eq!(config.ground_reflection_loss(mhz(2000.0)), None);
+    config.ground_reflection = vec![
+        GroundReflection { frequency: mhz(3000.0), coefficient: 0.1 },
+        GroundReflection { frequency: mhz(1000.0), coefficient: 0.5 },
+    ];
+    assert_approx_eq!(config.ground_reflection_loss(mhz(500.0)).unwrap(), 6.0206, 1e-3);
+    // Halfway between 0.5 and 0.1.
+    assert_approx_eq!(config.ground_reflection_loss(mhz(2000.0)).unwrap(), 10.4576, 1e-3);
+    assert_approx_eq!(config.ground_reflection_loss(mhz(5000.0)).unwrap(), 20.0, 1e-3);
+}
+
+#[test]
+fn test_resize_image() {
+    let pixels: Vec<u8> = (0..2 * 2 * 3).map(|i| i as u8).collect();
+    let (resized, bounds) = resize_image(&pixels, (2, 2), 2.0, ImageFilter::Nearest);
+    assert_eq!(bounds, (4, 4));
+    assert_eq!(resized.len(), pixels.len() * 4);
+    for y in 0..4 {
+        for x in 0..4 {
+            let original = ((y / 2) * 2 + x / 2) * 3;
+            let scaled = (y * 4 + x) * 3;
+            assert_eq!(resized[scaled..scaled + 3], pixels[original..original + 3]);
+        }
+    }
+    assert_eq!(
+        resize_image(&pixels, (2, 2), 1.0, ImageFilter::Lanczos),
+        (pixels, (2, 2))
+    );
+}
+
+#[test]
+fn test_scale() {
+    use crate::materials::Lambertian;
+    use crate::ray::Ray;
+    let block = Cube::new(
+        Point3D::new(4.0, 1.0, 3.0),
+        1.0,
+        1.0,
+        2.0,
+        Material::Lambertian(Lambertian::new(Srgb::new(0.5, 0.5, 0.5))),
+        0,
+    );
+    let mut config = test_config(10, 10, vec![block]);
+    // Distance to the block from a point, looking along a unit direction.
+    let distance = |config: &Config, origin: Point3D, direction: Point3D| {
+        let ray = Ray::new(origin, direction, 0.0, 0.0, Frequency::from_mhz(2000.0));
+        config.objects[0].hit(&ray, 0.0, f32::MAX).unwrap().t
+    };
+    let (side, above) = (Point3D::new(0.0, 1.0, 3.0), Point3D::new(4.0, 9.0, 3.5));
+    let (east, down) = (Point3D::new(1.0, 0.0, 0.0), Point3D::new(0.0, -1.0, 0.0));
+    let from_side = distance(&config, side, east);
+    let from_above = distance(&config, above, down);
+    let world = (config.world_width, config.world_height);
+    config.scale(2.0);
+    assert_eq!((config.world_width, config.world_height), (2.0 * world.0, 2.0 * world.1));
+    assert_approx_eq!(distance(&config, side * 2.0, east), 2.0 * from_side, 1e-4);
+    assert_approx_eq!(distance(&config, above * 2.0, down), 2.0 * from_above, 1e-4);
+    assert_eq!(config.objects[0].dim_z, 4.0);
+}
+
+#[test]
+fn test_config_formats() {
+    let json = r#"{
+        "width": 10, "height": 10, "samples_per_pixel": 1, "nr_probes": 0, "max_depth": 5,
+        "camera": {
+            "look_from": {"x": 0.0, "y": 10.0, "z": 0.0},
+            "look_at": {"x": 5.0, "y": 0.0, "z": 5.0},
+            "vup": {"x": 0.0, "y": 1.0, "z": 0.0},
+            "vfov": 50.0, "aspect": 1.0
+        },
+        "objects": [{
+            "origin": {"x": 1.0, "y": 2.0, "z": 3.0}, "dim_x": 1.0, "dim_y": 2.0, "dim_z": 1.0,
+            "id": 1, "material": {"Lambertian": {"albedo": [0.5, 0.25, 0.5]}}
+        }]
+    }"#;
+    let ron = r#"(
+        width: 10, height: 10, samples_per_pixel: 1, nr_probes: 0, max_depth: 5,
+        camera: (
+            look_from: (x: 0.0, y: 10.0, z: 0.0),
+            look_at: (x: 5.0, y: 0.0, z: 5.0),
+            vup: (x: 0.0, y: 1.0, z: 0.0),
+            vfov: 50.0, aspect: 1.0,
+        ),
+        objects: [(
+            origin: (x: 1.0, y: 2.0, z: 3.0), dim_x: 1.0, dim_y: 2.0, dim_z: 1.0,
+            id: 1, material: Lambertian((albedo: (0.5, 0.25, 0.5))),
+        )],
+    )"#;
+    let yaml = "
+width: 10
+height: 10
+samples_per_pixel: 1
+nr_probes: 0
+max_depth: 5
+camera:
+  look_from: {x: 0.0, y: 10.0, z: 0.0}
+  look_at: {x: 5.0, y: 0.0, z: 5.0}
+  vup: {x: 0.0, y: 1.0, z: 0.0}
+  vfov: 50.0
+  aspect: 1.0
+objects:
+  - origin: {x: 1.0, y: 2.0, z: 3.0}
+    dim_x: 1.0
+    dim_y: 2.0
+    dim_z: 1.0
+    id: 1
+    material: !Lambertian {albedo: [0.5, 0.25, 0.5]}
+";
+    let expected = serde_json::to_value(Config::from_reader(json.as_bytes()).unwrap()).unwrap();
+    for (text, format) in [(ron, ConfigFormat::Ron), (yaml, ConfigFormat::Yaml)] {
+        let config = Config::from_reader_as(text.as_bytes(), format).unwrap();
+        assert_eq!(serde_json::to_value(config).unwrap(), expected);
+    }
+    assert_eq!(ConfigFormat::Ron.parse_value(ron.as_bytes()).unwrap()["width"], 10);
+    assert_eq!(
+        ConfigFormat::Yaml.parse_value(yaml.as_bytes()).unwrap()["objects"][0]["material"],
+        serde_json::json!({"Lambertian": {"albedo": [0.5, 0.25, 0.5]}})
+    );
+    assert!(check_config(ron.as_bytes(), ConfigFormat::Ron).is_empty());
+    assert!(check_config(yaml.as_bytes(), ConfigFormat::Yaml).is_empty());
+    assert_eq!(ConfigFormat::from_path(Path::new("scene.ron")), ConfigFormat::Ron);
+    assert_eq!(ConfigFormat::from_path(Path::new("scene.yml")), ConfigFormat::Yaml);
+    assert_eq!(ConfigFormat::from_path(Path::new("scene.json")), ConfigFormat::Json);
+}
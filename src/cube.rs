@@ -1,19 +1,76 @@
 use serde::{Deserialize, Serialize};
 
+use crate::config::GeoPosition;
 use crate::materials::Material;
 use crate::point3d::Point3D;
 use crate::ray::HitRecord;
 use crate::ray::Hittable;
 use crate::ray::Ray;
 
+fn default_origin() -> Point3D {
+    Point3D::new(0.0, 0.0, 0.0)
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(try_from = "CubeDef")]
 pub struct Cube {
+    // May be left out when `geo` is given.
+    #[serde(default = "default_origin")]
     pub origin: Point3D,
     pub dim_x: f32,
     pub dim_y: f32,
     pub dim_z: f32,
     pub material: Material,
     pub id: i64,
+    // Rotation around the vertical axis through `origin`, in degrees.
+    #[serde(default)]
+    pub rotation_y: f32,
+    // Alternative to `origin`, converted using the config's geo_reference.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub geo: Option<GeoPosition>,
+}
+
+/// A cube as written in a config: either `origin` with the half-extents `dim_x`,
+/// `dim_y` and `dim_z`, or the `min` and `max` corners of the unrotated box.
+#[derive(Deserialize)]
+struct CubeDef {
+    #[serde(default = "default_origin")]
+    origin: Point3D,
+    dim_x: Option<f32>,
+    dim_y: Option<f32>,
+    dim_z: Option<f32>,
+    min: Option<Point3D>,
+    max: Option<Point3D>,
+    material: Material,
+    id: i64,
+    #[serde(default)]
+    rotation_y: f32,
+    #[serde(default)]
+    geo: Option<GeoPosition>,
+}
+
+impl TryFrom<CubeDef> for Cube {
+    type Error = String;
+
+    fn try_from(def: CubeDef) -> Result<Cube, String> {
+        let mut cube = match (def.dim_x, def.dim_y, def.dim_z, def.min, def.max) {
+            (Some(dim_x), Some(dim_y), Some(dim_z), None, None) => {
+                Cube::new(def.origin, dim_x, dim_y, dim_z, def.material, def.id)
+            }
+            (None, None, None, Some(min), Some(max)) if def.geo.is_none() => {
+                Cube::from_corners(min, max, def.material, def.id)
+            }
+            _ => {
+                return Err(format!(
+                    "Cube {} needs either dim_x, dim_y and dim_z or min and max (without geo)",
+                    def.id
+                ))
+            }
+        };
+        cube.rotation_y = def.rotation_y;
+        cube.geo = def.geo;
+        Ok(cube)
+    }
 }
 
 impl Cube {
@@ -24,9 +81,32 @@ impl Cube {
             dim_y,
             dim_z,
             material,
-            id
+            id,
+            rotation_y: 0.0,
+            geo: None,
         }
     }
+
+    /// The box between the corners `min` and `max`, in any order.
+    pub fn from_corners(min: Point3D, max: Point3D, material: Material, id: i64) -> Cube {
+        let half = (max - min) / 2.0;
+        Cube::new(
+            (min + max) / 2.0,
+            half.x().abs(),
+            half.y().abs(),
+            half.z().abs(),
+            material,
+            id,
+        )
+    }
+
+    /// Whether `p` lies inside the cube or on its surface.
+    pub fn contains(&self, p: &Point3D) -> bool {
+        let local = (*p - self.origin).rotate_y(-self.rotation_y.to_radians());
+        local.x().abs() <= self.dim_x
+            && local.y().abs() <= self.dim_y
+            && local.z().abs() <= self.dim_z
+    }
 }
 
 fn u_v_from_cube_hit_point(hit_point_on_cube: Point3D) -> (f32, f32) {
@@ -39,15 +119,56 @@ fn u_v_from_cube_hit_point(hit_point_on_cube: Point3D) -> (f32, f32) {
     (u, v)
 }
 
+/// A ray crossing a face, as (hit point, t, outward normal).
+type FaceHit = (Point3D, f32, Point3D);
+
+/// Index of the axis a face normal lies along.
+fn axis(normal: &Point3D) -> usize {
+    if normal.x() != 0.0 {
+        0
+    } else if normal.y() != 0.0 {
+        1
+    } else {
+        2
+    }
+}
+
 impl Cube {
-    fn get_hit_for_cube(&self, ray: &Ray) -> Option<(Point3D, f32, Point3D)> {
+    /// Nearest face hit beyond `t_min`, as (hit point, t, outward normal), and the
+    /// face the ray leaves through after it, if it enters there.
+    fn get_hit_for_cube(&self, ray: &Ray, t_min: f32) -> Option<(FaceHit, Option<FaceHit>)> {
+        if self.rotation_y == 0.0 {
+            return self.get_hit_for_aabb(ray, t_min);
+        }
+        // Intersect in the cube's own frame, where it is axis-aligned. Rotations
+        // preserve lengths, so t carries over unchanged.
+        let angle = self.rotation_y.to_radians();
+        let local_ray = Ray::new(
+            self.origin + (ray.origin - self.origin).rotate_y(-angle),
+            ray.direction.rotate_y(-angle),
+            ray.strength,
+            ray.ray_time,
+            ray.frequency,
+        );
+        let to_world = |(intersect, t, normal): FaceHit| {
+            (
+                self.origin + (intersect - self.origin).rotate_y(angle),
+                t,
+                normal.rotate_y(angle),
+            )
+        };
+        self.get_hit_for_aabb(&local_ray, t_min)
+            .map(|(entry, exit)| (to_world(entry), exit.map(to_world)))
+    }
+
+    fn get_hit_for_aabb(&self, ray: &Ray, t_min: f32) -> Option<(FaceHit, Option<FaceHit>)> {
         let mut results = Vec::new();
         for x_dir in [-1.0,1.0] {
             let dist = ray.origin.x() - (self.origin.x() - x_dir * self.dim_x);
             let t = -(dist / ray.direction.x());
             let intersect = ray.at(t);
-            if (intersect.y() - self.origin.y()).abs() < self.dim_y
-                && (intersect.z() - self.origin.z()).abs() < self.dim_z
+            if (intersect.y() - self.origin.y()).abs() <= self.dim_y
+                && (intersect.z() - self.origin.z()).abs() <= self.dim_z
             {
                 let normal = Point3D::new(-x_dir, 0.0, 0.0);
                 if self.origin.y() < 0.2 {
@@ -60,10 +181,10 @@ impl Cube {
             let dist =  ray.origin.y() - (self.origin.y() - y_dir * self.dim_y);
             let t = -(dist / ray.direction.y());
             let intersect = ray.at(t);
-            if ((intersect.x() - self.origin.x()).abs() < self.dim_x)
-                && ((intersect.z() - self.origin.z()).abs() < self.dim_z)
+            if ((intersect.x() - self.origin.x()).abs() <= self.dim_x)
+                && ((intersect.z() - self.origin.z()).abs() <= self.dim_z)
             {
-                let normal = Point3D::new(0.0, y_dir, 0.0);
+                let normal = Point3D::new(0.0, -y_dir, 0.0);
                 if self.origin.y() < 0.2 {
                     //dbg!("y", intersect,normal);
                 }
@@ -74,13 +195,13 @@ impl Cube {
             let dist = ray.origin.z() - (self.origin.z() - z_dir * self.dim_z);
             let t = -(dist / ray.direction.z());
             let intersect = ray.at(t);
-            if (intersect.y() - self.origin.y()).abs() < self.dim_y
-                && (intersect.x() - self.origin.x()).abs() < self.dim_x
+            if (intersect.y() - self.origin.y()).abs() <= self.dim_y
+                && (intersect.x() - self.origin.x()).abs() <= self.dim_x
             {
                 if self.origin.y() < 0.2 {
                     //dbg!("z", intersect);
                 }
-                let normal = Point3D::new(0.0, 0.0, z_dir);
+                let normal = Point3D::new(0.0, 0.0, -z_dir);
                 results.push((intersect, t, normal));
             }
         }
@@ -91,23 +212,45 @@ impl Cube {
 
         //println!("{:?}",results);
         }
-        results.sort_by(|x, y| x.1.partial_cmp(&y.1).unwrap_or(std::cmp::Ordering::Equal));
-        let (intersect, t, normal) = results.first().expect("results is non-empty");
-        Some((*intersect, *t, *normal))
+        // Faces at the same t, where the ray hits an edge or a flat cube, are
+        // ordered by whether they face the ray and then by axis, so the same
+        // ray always gets the same normal.
+        results.sort_by(|a, b| {
+            let facing_away = |normal: &Point3D| normal.dot(&ray.direction) >= 0.0;
+            a.1.total_cmp(&b.1)
+                .then(facing_away(&a.2).cmp(&facing_away(&b.2)))
+                .then(axis(&a.2).cmp(&axis(&b.2)))
+        });
+        // Faces behind the origin are skipped, so a ray starting inside the
+        // cube finds the face it leaves through.
+        let mut ahead = results.into_iter().filter(|(_, t, _)| *t > t_min);
+        let entry = ahead.next()?;
+        let exit = if entry.2.dot(&ray.direction) < 0.0 {
+            ahead.find(|(_, t, normal)| *t > entry.1 && normal.dot(&ray.direction) > 0.0)
+        } else {
+            None
+        };
+        Some((entry, exit))
     }
 }
 
 impl Hittable for Cube {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
-        if let Some((hit_loc, ray_t, normal)) = self.get_hit_for_cube(ray) {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        if let Some(((hit_loc, ray_t, normal), exit)) = self.get_hit_for_cube(ray, t_min) {
             //let ray_t = ((hit_loc.x() - ray.origin.x()) / ray.direction.x()).abs();
             if ray_t < t_max && ray_t > t_min {
                 let (u, v) = u_v_from_cube_hit_point(hit_loc - self.origin);
+                // Like for spheres, the normal points against the ray, so one
+                // leaving the cube gets the inward normal and front_face false.
+                let front_face = ray.direction.dot(&normal) < 0.0;
                 return Some(HitRecord {
                     t: ray_t,
                     point: hit_loc,
-                    normal,
-                    front_face: ray.direction.dot(&normal) > 0.0,
+                    normal: if front_face { normal } else { -normal },
+                    front_face,
+                    thickness: exit.map_or(0.0, |(_, exit_t, _)| {
+                        (exit_t - ray_t) * ray.direction.length()
+                    }),
                     material: &self.material,
                     u,
                     v,
@@ -116,4 +259,239 @@ impl Hittable for Cube {
         }
         None
     }
+
+    fn bounding_box(&self) -> (Point3D, Point3D) {
+        // A rotation around y mixes the horizontal half-extents.
+        let (sin, cos) = self.rotation_y.to_radians().sin_cos();
+        let extent = Point3D::new(
+            self.dim_x * cos.abs() + self.dim_z * sin.abs(),
+            self.dim_y,
+            self.dim_x * sin.abs() + self.dim_z * cos.abs(),
+        );
+        (self.origin - extent, self.origin + extent)
+    }
+}
+
+#[cfg(test)]
+use assert_approx_eq::assert_approx_eq;
+
+#[cfg(test)]
+use crate::frequency::Frequency;
+#[cfg(test)]
+use crate::materials::Lambertian;
+
+#[cfg(test)]
+fn test_material() -> Material {
+    Material::Lambertian(Lambertian::new(palette::Srgb::new(0.5, 0.5, 0.5)))
+}
+
+#[test]
+fn test_rotated_cube_normal() {
+    let mut cube = Cube::new(Point3D::new(0.0, 0.0, 0.0), 1.0, 1.0, 1.0, test_material(), 0);
+    cube.rotation_y = 45.0;
+    // The -x face of the unrotated cube now faces (-1, 0, 1) / sqrt(2).
+    let face_normal = Point3D::new(-1.0, 0.0, 0.0).rotate_y(45f32.to_radians());
+    assert_approx_eq!(face_normal.x(), -std::f32::consts::FRAC_1_SQRT_2);
+    assert_approx_eq!(face_normal.z(), std::f32::consts::FRAC_1_SQRT_2);
+    let ray = Ray::new(face_normal * 5.0, -face_normal, 0.0, 0.0, Frequency::from_mhz(2000.0));
+    let hit = cube.hit(&ray, 0.0001, f32::MAX).expect("ray should hit the cube");
+    assert_approx_eq!(hit.t, 4.0, 1e-4);
+    assert_approx_eq!(hit.normal.x(), face_normal.x(), 1e-4);
+    assert_approx_eq!(hit.normal.y(), 0.0, 1e-4);
+    assert_approx_eq!(hit.normal.z(), face_normal.z(), 1e-4);
+}
+
+#[test]
+fn test_rotated_cube_miss() {
+    let mut cube = Cube::new(Point3D::new(0.0, 0.0, 0.0), 1.0, 1.0, 1.0, test_material(), 0);
+    // Unrotated, this ray grazes past the cube at x = 1.2; rotated by 45 degrees
+    // the corner reaches out to sqrt(2) and is hit.
+    let ray = Ray::new(
+        Point3D::new(1.2, 0.0, -5.0),
+        Point3D::new(0.0, 0.0, 1.0),
+        0.0,
+        0.0,
+        Frequency::from_mhz(2000.0),
+    );
+    assert!(cube.hit(&ray, 0.0001, f32::MAX).is_none());
+    cube.rotation_y = 45.0;
+    assert!(cube.hit(&ray, 0.0001, f32::MAX).is_some());
+}
+
+#[test]
+fn test_cube_bounding_box() {
+    for rotation in [0.0, 30.0, 45.0, 120.0] {
+        let mut cube = Cube::new(Point3D::new(5.0, 2.0, -3.0), 3.0, 2.0, 1.0, test_material(), 0);
+        cube.rotation_y = rotation;
+        let (min, max) = cube.bounding_box();
+        for corner in 0..8 {
+            let local = Point3D::new(
+                if corner & 1 == 0 { -cube.dim_x } else { cube.dim_x },
+                if corner & 2 == 0 { -cube.dim_y } else { cube.dim_y },
+                if corner & 4 == 0 { -cube.dim_z } else { cube.dim_z },
+            );
+            let p = cube.origin + local.rotate_y(rotation.to_radians());
+            assert!(p.x() >= min.x() - 1e-4 && p.y() >= min.y() - 1e-4 && p.z() >= min.z() - 1e-4);
+            assert!(p.x() <= max.x() + 1e-4 && p.y() <= max.y() + 1e-4 && p.z() <= max.z() + 1e-4);
+        }
+    }
+}
+
+#[test]
+fn test_corner_hit_normal() {
+    let cube = Cube::new(Point3D::new(0.0, 0.0, 0.0), 1.0, 1.0, 1.0, test_material(), 0);
+    let ray = Ray::new(
+        Point3D::new(3.0, 3.0, 3.0),
+        Point3D::new(-1.0, -1.0, -1.0),
+        0.0,
+        0.0,
+        Frequency::from_mhz(2000.0),
+    );
+    // All three faces meeting at the corner are hit at t = 2; the x face wins.
+    for _ in 0..10 {
+        let hit = cube.hit(&ray, 0.0, f32::MAX).expect("corner is hit");
+        assert_eq!(hit.t, 2.0);
+        assert_eq!(hit.normal, Point3D::new(1.0, 0.0, 0.0));
+    }
+    // Both faces of a flat cube are at the same t; the one facing the ray wins.
+    let flat = Cube::new(Point3D::new(0.0, 0.0, 0.0), 1.0, 0.0, 1.0, test_material(), 0);
+    for direction_y in [-1.0, 1.0] {
+        let ray = Ray::new(
+            Point3D::new(0.2, -direction_y, 0.3),
+            Point3D::new(0.0, direction_y, 0.0),
+            0.0,
+            0.0,
+            Frequency::from_mhz(2000.0),
+        );
+        let hit = flat.hit(&ray, 0.0, f32::MAX).expect("flat cube is hit");
+        assert_eq!(hit.normal, Point3D::new(0.0, -direction_y, 0.0));
+    }
+}
+
+#[test]
+fn test_ray_from_inside() {
+    let cube = Cube::new(Point3D::new(0.0, 0.0, 0.0), 1.0, 2.0, 1.0, test_material(), 0);
+    let ray = Ray::new(
+        Point3D::new(0.0, 0.0, 0.0),
+        Point3D::new(0.0, 1.0, 0.0),
+        0.0,
+        0.0,
+        Frequency::from_mhz(2000.0),
+    );
+    let hit = cube.hit(&ray, 0.001, f32::MAX).expect("ray leaves the cube");
+    assert_eq!(hit.t, 2.0);
+    assert_eq!(hit.point, Point3D::new(0.0, 2.0, 0.0));
+    assert_eq!(hit.normal, Point3D::new(0.0, -1.0, 0.0));
+    assert!(!hit.front_face);
+    // The same face seen from outside.
+    let ray = Ray::new(
+        Point3D::new(0.0, 5.0, 0.0),
+        Point3D::new(0.0, -1.0, 0.0),
+        0.0,
+        0.0,
+        Frequency::from_mhz(2000.0),
+    );
+    let hit = cube.hit(&ray, 0.001, f32::MAX).expect("ray enters the cube");
+    assert_eq!(hit.t, 3.0);
+    assert_eq!(hit.normal, Point3D::new(0.0, 1.0, 0.0));
+    assert!(hit.front_face);
+}
+
+#[test]
+fn test_cube_contains() {
+    let mut cube = Cube::new(Point3D::new(1.0, 2.0, 3.0), 2.0, 1.0, 0.5, test_material(), 0);
+    assert!(cube.contains(&Point3D::new(1.0, 2.0, 3.0)));
+    assert!(cube.contains(&Point3D::new(2.5, 1.5, 3.2)));
+    assert!(cube.contains(&Point3D::new(3.0, 2.0, 3.0)));
+    assert!(cube.contains(&Point3D::new(-1.0, 3.0, 2.5)));
+    assert!(!cube.contains(&Point3D::new(3.1, 2.0, 3.0)));
+    assert!(!cube.contains(&Point3D::new(1.0, 2.0, 3.6)));
+    cube.rotation_y = 90.0;
+    // The long side now lies along z.
+    assert!(cube.contains(&Point3D::new(1.0, 2.0, 4.5)));
+    assert!(!cube.contains(&Point3D::new(2.5, 2.0, 3.0)));
+}
+
+#[test]
+fn test_cube_from_corners() {
+    let material = r#"{"Lambertian": {"albedo": [0.5, 0.5, 0.5]}}"#;
+    let centered: Cube = serde_json::from_str(&format!(
+        r#"{{"origin": {{"x": 2.0, "y": 1.0, "z": -1.0}}, "dim_x": 2.0, "dim_y": 1.0, "dim_z": 0.5,
+            "material": {}, "id": 3, "rotation_y": 30.0}}"#,
+        material
+    ))
+    .unwrap();
+    let corners: Cube = serde_json::from_str(&format!(
+        r#"{{"min": {{"x": 0.0, "y": 0.0, "z": -1.5}}, "max": {{"x": 4.0, "y": 2.0, "z": -0.5}},
+            "material": {}, "id": 3, "rotation_y": 30.0}}"#,
+        material
+    ))
+    .unwrap();
+    // Points inside the box, given relative to its center before rotating.
+    for local in [
+        Point3D::new(0.0, 0.0, 0.0),
+        Point3D::new(1.5, -0.8, 0.2),
+        Point3D::new(-1.5, 0.9, -0.4),
+    ] {
+        let target = centered.origin + local.rotate_y(30f32.to_radians());
+        for origin in [Point3D::new(10.0, 5.0, 3.0), Point3D::new(-4.0, 0.5, -8.0)] {
+            let ray = Ray::new(
+                origin,
+                target - origin,
+                0.0,
+                0.0,
+                Frequency::from_mhz(2000.0),
+            );
+            let a = centered
+                .hit(&ray, 0.0001, f32::MAX)
+                .expect("ray aims inside the box");
+            let b = corners
+                .hit(&ray, 0.0001, f32::MAX)
+                .expect("ray aims inside the box");
+            assert_eq!(a.t, b.t);
+            assert_eq!(
+                (a.normal.x(), a.normal.y(), a.normal.z()),
+                (b.normal.x(), b.normal.y(), b.normal.z())
+            );
+        }
+    }
+    let missing = format!(
+        r#"{{"min": {{"x": 0.0, "y": 0.0, "z": 0.0}}, "material": {}, "id": 3}}"#,
+        material
+    );
+    assert!(serde_json::from_str::<Cube>(&missing).is_err());
+}
+
+#[test]
+fn test_wall_thickness_loss() {
+    use crate::materials::{Scatterable, Wall};
+    let mut wall = Wall::new(palette::Srgb::new(0.5, 0.5, 0.5), 0.0);
+    wall.loss_per_meter = 10.0;
+    let loss = |thickness: f32, rotation_y: f32| {
+        let mut cube = Cube::new(
+            Point3D::new(0.0, 0.0, 0.0),
+            thickness / 2.0,
+            4.0,
+            4.0,
+            Material::Wall(wall),
+            0,
+        );
+        cube.rotation_y = rotation_y;
+        let ray = Ray::new(
+            Point3D::new(-5.0, 0.0, 0.0),
+            Point3D::new(2.0, 0.0, 0.0),
+            0.0,
+            0.0,
+            Frequency::from_mhz(2000.0),
+        );
+        let hit = cube
+            .hit(&ray, 0.0001, f32::MAX)
+            .expect("ray aims at the wall");
+        let (scattered, _) = wall.scatter(&ray, &hit).unwrap();
+        -scattered.unwrap().strength
+    };
+    assert_approx_eq!(loss(0.2, 0.0), 2.0, 1e-3);
+    assert_approx_eq!(loss(0.4, 0.0), 2.0 * loss(0.2, 0.0), 1e-3);
+    // Crossing a wall turned by 60 degrees takes twice its thickness.
+    assert_approx_eq!(loss(0.2, 60.0), 4.0, 1e-3);
 }
@@ -0,0 +1,86 @@
+use std::fmt;
+
+/// What went wrong in one of the crate's public functions.
+#[derive(Debug)]
+pub enum RaytracerError {
+    /// Reading or writing a file failed, or an output file exists already.
+    Io(std::io::Error),
+    /// A config or other input is not well-formed.
+    Parse(String),
+    /// A config that parses but cannot be traced, such as one without stations.
+    Validation(String),
+    /// A texture or sky image could not be read or decoded.
+    TextureLoad(String),
+    /// A region or buffer that does not fit the image.
+    Geometry(String),
+}
+
+impl fmt::Display for RaytracerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RaytracerError::Io(e) => write!(f, "{}", e),
+            RaytracerError::Parse(message)
+            | RaytracerError::Validation(message)
+            | RaytracerError::TextureLoad(message)
+            | RaytracerError::Geometry(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for RaytracerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RaytracerError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for RaytracerError {
+    fn from(e: std::io::Error) -> RaytracerError {
+        RaytracerError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for RaytracerError {
+    fn from(e: serde_json::Error) -> RaytracerError {
+        RaytracerError::Parse(e.to_string())
+    }
+}
+
+#[test]
+fn test_error_variants() {
+    use std::error::Error;
+    let missing = std::io::Error::new(std::io::ErrorKind::NotFound, "scene.json not found");
+    let errors = [
+        RaytracerError::from(missing),
+        RaytracerError::from(serde_json::from_str::<u32>("{").unwrap_err()),
+        RaytracerError::Validation("scene contains no Material::Light objects".to_string()),
+        RaytracerError::TextureLoad("Could not open sky.jpg".to_string()),
+        RaytracerError::Geometry("Region is not within the image".to_string()),
+    ];
+    for (i, error) in errors.iter().enumerate() {
+        let variant = match error {
+            RaytracerError::Io(e) => {
+                assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+                assert!(error.source().is_some());
+                0
+            }
+            RaytracerError::Parse(_) => 1,
+            RaytracerError::Validation(_) => 2,
+            RaytracerError::TextureLoad(_) => 3,
+            RaytracerError::Geometry(_) => 4,
+        };
+        assert_eq!(variant, i);
+    }
+    assert!(matches!(
+        crate::config::load_texture_image("/nonexistent/sky.jpg"),
+        Err(RaytracerError::TextureLoad(_))
+    ));
+    assert!(matches!(
+        crate::config::Config::from_reader("{".as_bytes()),
+        Err(RaytracerError::Parse(_))
+    ));
+    assert_eq!(errors[0].to_string(), "scene.json not found");
+    assert_eq!(errors[3].to_string(), "Could not open sky.jpg");
+}
@@ -0,0 +1,87 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+const SPEED_OF_LIGHT: f64 = 299_792_458.0;
+
+/// A radio frequency, kept in Hz. Configs give it in MHz, which is also how it is
+/// shown in file names and logs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "f64", into = "f64")]
+pub struct Frequency {
+    hz: u64,
+}
+
+impl Frequency {
+    pub const fn from_hz(hz: u64) -> Frequency {
+        Frequency { hz }
+    }
+
+    pub fn from_mhz(mhz: f64) -> Frequency {
+        Frequency::from_hz((mhz * 1e6).round() as u64)
+    }
+
+    pub fn from_ghz(ghz: f64) -> Frequency {
+        Frequency::from_hz((ghz * 1e9).round() as u64)
+    }
+
+    pub fn hz(&self) -> u64 {
+        self.hz
+    }
+
+    pub fn mhz(&self) -> f64 {
+        self.hz as f64 / 1e6
+    }
+
+    pub fn ghz(&self) -> f64 {
+        self.hz as f64 / 1e9
+    }
+
+    /// Wavelength in meters.
+    pub fn wavelength(&self) -> f32 {
+        (SPEED_OF_LIGHT / self.hz as f64) as f32
+    }
+}
+
+impl TryFrom<f64> for Frequency {
+    type Error = String;
+
+    /// From MHz, as in the config.
+    fn try_from(mhz: f64) -> Result<Frequency, String> {
+        if mhz.is_finite() && mhz > 0.0 {
+            Ok(Frequency::from_mhz(mhz))
+        } else {
+            Err(format!("Frequency {} MHz is not positive", mhz))
+        }
+    }
+}
+
+impl From<Frequency> for f64 {
+    fn from(frequency: Frequency) -> f64 {
+        frequency.mhz()
+    }
+}
+
+/// In MHz, without a fraction for whole MHz, as in `2600` or `2600.5`.
+impl fmt::Display for Frequency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.mhz())
+    }
+}
+
+#[test]
+fn test_wavelength() {
+    let frequency = Frequency::from_mhz(2600.0);
+    assert_eq!(frequency.hz(), 2_600_000_000);
+    assert!((frequency.wavelength() - 0.1153).abs() < 1e-4);
+    assert_eq!(Frequency::from_ghz(2.6), frequency);
+    assert_eq!(frequency.to_string(), "2600");
+    assert_eq!(Frequency::from_hz(2_600_500_000).to_string(), "2600.5");
+}
+
+#[test]
+fn test_deserialize_mhz() {
+    let frequency: Frequency = serde_json::from_str("2400").unwrap();
+    assert_eq!(frequency, Frequency::from_mhz(2400.0));
+    assert_eq!(serde_json::to_string(&frequency).unwrap(), "2400.0");
+    assert!(serde_json::from_str::<Frequency>("-5").is_err());
+}
@@ -0,0 +1,159 @@
+use std::io::Read;
+
+use palette::Srgb;
+use serde::Deserialize;
+
+use crate::config::{Config, GeoPosition, GeoReference};
+use crate::cube::Cube;
+use crate::error::RaytracerError;
+use crate::materials::{Lambertian, Material};
+use crate::point3d::Point3D;
+
+#[derive(Deserialize)]
+struct FeatureCollection {
+    features: Vec<Feature>,
+}
+
+#[derive(Deserialize)]
+struct Feature {
+    geometry: Geometry,
+    #[serde(default)]
+    properties: Properties,
+}
+
+#[derive(Deserialize)]
+struct Geometry {
+    #[serde(rename = "type")]
+    kind: String,
+    coordinates: serde_json::Value,
+}
+
+#[derive(Default, Deserialize)]
+struct Properties {
+    // Height of the building in meters.
+    height: Option<f32>,
+}
+
+// A polygon is a list of rings of [lon, lat] positions, the first being the outline.
+type Polygon = Vec<Vec<Vec<f64>>>;
+
+/// Appends a cube for each building footprint in a GeoJSON feature collection to
+/// `scene.objects`, returning how many were added. Footprints are given as
+/// (multi)polygons with a `height` property and are placed using the scene's
+/// `geo_reference`. Each polygon becomes the axis-aligned box around its
+/// outline, which is exact for rectangular footprints aligned with the axes.
+pub fn import_buildings<R: Read>(scene: &mut Config, reader: R) -> Result<usize, RaytracerError> {
+    let reference = scene.geo_reference.ok_or_else(|| {
+        RaytracerError::Validation(
+            "Importing buildings needs a geo_reference in the config".to_string(),
+        )
+    })?;
+    let collection: FeatureCollection = serde_json::from_reader(reader)
+        .map_err(|e| RaytracerError::Parse(format!("Invalid GeoJSON: {}", e)))?;
+    let mut next_id = scene.objects.iter().map(|o| o.id + 1).max().unwrap_or(0);
+    let mut added = 0;
+    for (i, feature) in collection.features.iter().enumerate() {
+        let height = feature
+            .properties
+            .height
+            .ok_or_else(|| RaytracerError::Parse(format!("Feature {} has no height", i)))?;
+        let coordinates = feature.geometry.coordinates.clone();
+        let polygons: Vec<Polygon> = match feature.geometry.kind.as_str() {
+            "Polygon" => serde_json::from_value(coordinates).map(|p| vec![p]),
+            "MultiPolygon" => serde_json::from_value(coordinates),
+            other => {
+                return Err(RaytracerError::Parse(format!(
+                    "Feature {} is a {}, not a polygon",
+                    i, other
+                )))
+            }
+        }
+        .map_err(|e| {
+            RaytracerError::Parse(format!("Feature {} has invalid coordinates: {}", i, e))
+        })?;
+        for polygon in polygons.iter() {
+            let outline = polygon
+                .first()
+                .filter(|ring| !ring.is_empty())
+                .ok_or_else(|| {
+                    RaytracerError::Parse(format!("Feature {} has an empty polygon", i))
+                })?;
+            let mut cube = footprint_to_cube(outline, height, &reference).ok_or_else(|| {
+                RaytracerError::Parse(format!(
+                    "Feature {} has a position without longitude and latitude",
+                    i
+                ))
+            })?;
+            cube.id = next_id;
+            next_id += 1;
+            scene.objects.push(cube);
+            added += 1;
+        }
+    }
+    Ok(added)
+}
+
+/// Box standing on the ground around the [lon, lat] positions of `outline`.
+fn footprint_to_cube(outline: &[Vec<f64>], height: f32, reference: &GeoReference) -> Option<Cube> {
+    let mut corners = Vec::with_capacity(outline.len());
+    for position in outline {
+        let (lon, lat) = (*position.first()?, *position.get(1)?);
+        corners.push(
+            GeoPosition {
+                lat,
+                lon,
+                height: 0.0,
+            }
+            .to_local(reference),
+        );
+    }
+    let min = corners.iter().fold(corners[0], |a, b| a.min(b));
+    let max = corners.iter().fold(corners[0], |a, b| a.max(b));
+    Some(Cube::from_corners(
+        min,
+        Point3D::new(max.x(), height, max.z()),
+        Material::Lambertian(Lambertian::new(Srgb::new(0.5, 0.5, 0.5))),
+        0,
+    ))
+}
+
+#[cfg(test)]
+use crate::config::test_config;
+#[cfg(test)]
+use assert_approx_eq::assert_approx_eq;
+
+#[test]
+fn test_import_rectangular_building() {
+    let geojson = r#"{
+        "type": "FeatureCollection",
+        "features": [{
+            "type": "Feature",
+            "properties": {"height": 12.0},
+            "geometry": {
+                "type": "Polygon",
+                "coordinates": [[
+                    [5.0, 52.0], [5.001, 52.0], [5.001, 52.0005], [5.0, 52.0005], [5.0, 52.0]
+                ]]
+            }
+        }]
+    }"#;
+    let mut scene = test_config(10, 10, vec![]);
+    assert!(matches!(
+        import_buildings(&mut scene, geojson.as_bytes()),
+        Err(RaytracerError::Validation(_))
+    ));
+    scene.geo_reference = Some(GeoReference {
+        lat: 52.0,
+        lon: 5.0,
+    });
+    assert_eq!(import_buildings(&mut scene, geojson.as_bytes()).unwrap(), 1);
+    let building = &scene.objects[0];
+    // 0.001 degrees of longitude at 52 degrees north is about 68.5 m, 0.0005 degrees of
+    // latitude about 55.6 m.
+    assert_approx_eq!(building.dim_x, 68.46 / 2.0, 0.1);
+    assert_approx_eq!(building.dim_z, 55.60 / 2.0, 0.1);
+    assert_eq!(building.dim_y, 6.0);
+    assert_approx_eq!(building.origin.x(), building.dim_x, 1e-3);
+    assert_eq!(building.origin.y(), 6.0);
+    assert_approx_eq!(building.origin.z(), building.dim_z, 1e-3);
+}
@@ -1,9 +1,15 @@
 pub mod camera;
 pub mod config;
+pub mod error;
+pub mod frequency;
+pub mod import;
 pub mod materials;
+pub mod max_grid;
 pub mod point3d;
 pub mod ray;
 pub mod raytracer;
 pub mod sphere;
+pub mod timing;
 pub mod cube;
 pub mod signal_map;
+pub mod world;
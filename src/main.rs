@@ -1,34 +1,535 @@
 use std::fs;
+use std::io::BufReader;
+use std::path::Path;
 
 use clap::Parser;
-use raytracer::config::Config;
-use raytracer::raytracer::render;
-use raytracer::signal_map::generate_sigmap;
+use raytracer::config::{check_config, Config, ConfigFormat};
+use raytracer::error::RaytracerError;
+use raytracer::frequency::Frequency;
+use raytracer::import::import_buildings;
+use raytracer::materials::Material;
+use raytracer::raytracer::{render, render_ray_count};
+use raytracer::signal_map::{
+    combined_filename, compare_to_measurements, coverage_stats, generate_batch,
+    generate_monte_carlo, generate_sigmap, link_budget, read_data, read_measurements,
+    reproject_mercator, signal_ray_count, stream_sigmap, sweep_frequencies, time_per_signal_ray,
+    write_diff, write_mercator_tiles, write_plan, write_residuals, BatchOutcome, Combine,
+    SigmapOptions,
+};
+use raytracer::timing::TimingReport;
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum CombineArg {
+    Max,
+    MaxPerFrequency,
+}
 
 #[derive(Parser)]
 struct Args {
     config: String,
-    ouput_filename: String,
+    #[arg(required_unless_present_any = ["check", "dry_run"])]
+    ouput_filename: Option<String>,
     #[arg(long)]
     angles: bool,
+    /// Write the arrival angles in degrees instead of radians
+    #[arg(long)]
+    angle_degrees: bool,
     #[arg(long)]
     times: bool,
     #[arg(long)]
     png: bool,
     #[arg(long)]
     view: bool,
+    /// Camera samples per pixel for --view, overriding the config's samples_per_pixel
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    samples: Option<u32>,
+    #[arg(long)]
+    diagnose: bool,
+    /// Write the number of rays that reached each point to a .count file
+    #[arg(long)]
+    counts: bool,
+    /// Write the RMS delay spread in nanoseconds at each point to a .ds file
+    #[arg(long)]
+    delay_spread: bool,
+    /// Write every position a ray was recorded at, with its strength, to this PLY file
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["freq_sweep", "monte_carlo"])]
+    point_cloud: Option<String>,
+    /// Also write each map as N by N point tiles, to <map>_tile_<column>_<row>.data
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    tile_size: Option<u32>,
+    #[arg(long)]
+    sectors: bool,
+    #[arg(long)]
+    angle_png: bool,
+    /// Render the propagation delay as a png, from blue to red at the config's max_delay
+    #[arg(long)]
+    time_png: bool,
+    #[arg(long)]
+    subdirectories: bool,
+    /// Also write a map with the strongest signal over all stations
+    #[arg(long, value_enum)]
+    combine: Option<CombineArg>,
+    /// Generate the maps once for each of these comma-separated frequencies in MHz,
+    /// overriding the frequency of every station
+    #[arg(long, value_delimiter = ',', value_parser = parse_mhz)]
+    freq_sweep: Vec<Frequency>,
+    /// With --freq-sweep, also write <output>_bestband.data with the frequency giving the
+    /// strongest signal at each point, and its strength in <output>_bestband_strength.data
+    #[arg(long, requires = "freq_sweep")]
+    best_band: bool,
+    /// Only trace the stations with these comma-separated ids
+    #[arg(long, value_delimiter = ',')]
+    stations: Vec<i64>,
+    /// Instead of the station maps, write the mean and 10th percentile of this many runs
+    /// with the stations moved randomly by --jitter
+    #[arg(long, value_name = "RUNS", conflicts_with_all = ["freq_sweep", "combine"])]
+    monte_carlo: Option<usize>,
+    /// Standard deviation in meters of the station offsets for --monte-carlo
+    #[arg(long, requires = "monte_carlo", default_value_t = 0.0)]
+    jitter: f32,
+    /// Trace the stations one at a time and write their .data files row by row while
+    /// tracing, keeping only a few rows in memory. Nothing is homogenized
+    #[arg(long, conflicts_with_all = [
+        "monte_carlo", "freq_sweep", "combine", "png", "angles", "angle_png", "times",
+        "time_png", "diagnose", "counts", "delay_spread", "point_cloud", "tile_size",
+    ])]
+    stream: bool,
+    /// Keep points without coverage at the no-signal value instead of filling them in
+    #[arg(long)]
+    no_homogenize: bool,
+    /// Write the .data files in mW instead of dBm, with 0.0 where there is no signal
+    #[arg(long)]
+    linear: bool,
+    /// Stop with an error if any output file already exists
+    #[arg(long, conflicts_with = "force")]
+    no_clobber: bool,
+    /// Overwrite existing output files (the default)
+    #[arg(long)]
+    force: bool,
+    /// Write the link budget of the config's probes to <output>_link_budget.json
+    #[arg(long)]
+    link_budget: bool,
+    /// Compare the combined map to the x,y,dBm measurements in this csv file and write
+    /// the residuals to <output>_residuals.csv
+    #[arg(long, requires = "combine", conflicts_with_all = ["linear", "freq_sweep"])]
+    compare: Option<String>,
+    /// Write the change from the combined map in this .data file to the one of this run
+    /// to <output>_diff.data and <output>_diff.png
+    #[arg(long, requires = "combine", conflicts_with_all = ["linear", "freq_sweep"])]
+    diff: Option<String>,
+    /// Reproject the combined map to Web Mercator at this zoom level and cut it into
+    /// <output>_tiles/<zoom>/<x>/<y>.png tiles for a web map
+    #[arg(long, value_name = "ZOOM", requires = "combine", conflicts_with_all = ["linear", "freq_sweep"],
+        value_parser = clap::value_parser!(u8).range(0..=24))]
+    mercator: Option<u8>,
+    /// Print how much of the combined map, in percent and in square meters, gets at least
+    /// this many dBm
+    #[arg(long, value_name = "DBM", requires = "combine", allow_negative_numbers = true,
+        conflicts_with_all = ["linear", "freq_sweep"])]
+    coverage: Option<f32>,
+    /// Write how long the map and the view took, per station and in total, as json
+    #[arg(long, value_name = "PATH")]
+    timing_json: Option<String>,
+    /// Add the buildings in this GeoJSON file to the scene
+    #[arg(long)]
+    import: Option<String>,
+    /// Only write a top-down plan of the buildings and stations to <output>_plan.png
+    #[arg(long)]
+    plan: bool,
+    /// Take the config as a directory and generate the maps of every .json scene in it,
+    /// with <output>/<scene name> as output name. Scenes whose outputs exist are skipped
+    #[arg(long, conflicts_with_all = [
+        "check", "dry_run", "plan", "view", "stream", "monte_carlo", "freq_sweep", "compare",
+        "diff", "mercator", "coverage", "import", "link_budget", "timing_json", "point_cloud",
+    ])]
+    batch: bool,
+    /// Only check that the config parses and its textures load
+    #[arg(long)]
+    check: bool,
+    /// Only report how many rays would be traced and roughly how long that takes
+    #[arg(long)]
+    dry_run: bool,
+    /// Only print warnings and errors
+    #[arg(long, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Also print debugging details
+    #[arg(long)]
+    verbose: bool,
+}
+
+/// Parses a frequency given in MHz.
+fn parse_mhz(value: &str) -> Result<Frequency, String> {
+    let mhz: f64 = value.parse().map_err(|e| format!("{}", e))?;
+    Frequency::try_from(mhz)
+}
+
+/// Prints log messages, with warnings and errors going to stderr.
+struct StdoutLogger;
+
+impl log::Log for StdoutLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if record.level() <= log::Level::Warn {
+            eprintln!("{}: {}", record.level(), record.args());
+        } else {
+            println!("{}", record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StdoutLogger = StdoutLogger;
+
+// Rays traced to estimate the time per ray for --dry-run.
+const CALIBRATION_RAYS: usize = 500;
+
+fn dry_run(scene: &Config, view: bool) {
+    let signal_rays = signal_ray_count(scene);
+    let per_ray = time_per_signal_ray(scene, CALIBRATION_RAYS).unwrap_or_default();
+    println!(
+        "Signal map: {} rays, about {:.1}s on one thread",
+        signal_rays,
+        per_ray.as_secs_f64() * signal_rays as f64
+    );
+    if view {
+        // Camera rays bounce about as much as signal rays, so the same calibration is used.
+        let render_rays = render_ray_count(scene);
+        println!(
+            "Render: {} rays, about {:.1}s on one thread",
+            render_rays,
+            per_ray.as_secs_f64() * render_rays as f64
+        );
+    }
+}
+
+/// Everything timed in one run, for --timing-json.
+#[derive(serde::Serialize)]
+struct Timings {
+    sigmap: Option<TimingReport>,
+    view: Option<TimingReport>,
+}
+
+/// `e` from reading `path`, naming the file.
+fn unable_to_read(path: &str, e: std::io::Error) -> std::io::Error {
+    std::io::Error::new(e.kind(), format!("Unable to read {}: {}", path, e))
+}
+
+fn compare(
+    path: &str,
+    filename: &str,
+    scene: &Config,
+    options: &SigmapOptions,
+) -> Result<(), RaytracerError> {
+    let file = fs::File::open(path).map_err(|e| unable_to_read(path, e))?;
+    let measurements = read_measurements(BufReader::new(file))?;
+    let combined = combined_filename(filename, None, options) + ".data";
+    let signals = read_data(Path::new(&combined))?;
+    let stats = compare_to_measurements(&signals, scene, &measurements);
+    log::info!(
+        "Compared {} of {} measurements: mean error {:.2} dB, rmse {:.2} dB, correlation {}",
+        stats.count,
+        measurements.len(),
+        stats.mean_error,
+        stats.rmse,
+        stats
+            .correlation
+            .map_or("undefined".to_string(), |c| format!("{:.3}", c))
+    );
+    let file = fs::File::create(filename.to_string() + "_residuals.csv")?;
+    write_residuals(std::io::BufWriter::new(file), &stats)?;
+    Ok(())
+}
+
+fn diff(
+    other: &str,
+    filename: &str,
+    scene: &Config,
+    options: &SigmapOptions,
+) -> Result<(), RaytracerError> {
+    let before = read_data(Path::new(other)).map_err(|e| unable_to_read(other, e))?;
+    let combined = combined_filename(filename, None, options) + ".data";
+    let after = read_data(Path::new(&combined))?;
+    write_diff(&(filename.to_string() + "_diff"), &before, &after, scene)
+}
+
+fn mercator(
+    zoom: u8,
+    filename: &str,
+    scene: &Config,
+    options: &SigmapOptions,
+) -> Result<(), RaytracerError> {
+    let combined = combined_filename(filename, None, options) + ".data";
+    let signals = read_data(Path::new(&combined))?;
+    let map = reproject_mercator(&signals, scene, zoom)?;
+    let tiles = write_mercator_tiles(&(filename.to_string() + "_tiles"), &map)?;
+    log::info!("Wrote {} tiles at zoom {}", tiles, zoom);
+    Ok(())
+}
+
+fn coverage(
+    threshold: f32,
+    filename: &str,
+    scene: &Config,
+    options: &SigmapOptions,
+) -> Result<(), RaytracerError> {
+    let combined = combined_filename(filename, None, options) + ".data";
+    let signals = read_data(Path::new(&combined))?;
+    let stats = coverage_stats(&signals, scene, threshold)?;
+    log::info!(
+        "Coverage at {} dBm: {} of {} points ({:.1}%), {:.0} m2",
+        threshold,
+        stats.served,
+        stats.total,
+        stats.percentage,
+        stats.served_area
+    );
+    Ok(())
+}
+
+fn sigmap_options(args: &Args) -> SigmapOptions {
+    SigmapOptions {
+        times: args.times,
+        angles: args.angles,
+        png: args.png,
+        diagnose: args.diagnose,
+        counts: args.counts,
+        delay_spread: args.delay_spread,
+        point_cloud: args.point_cloud.clone(),
+        tile_size: args.tile_size.map(|size| size as usize),
+        sectors: args.sectors,
+        angle_png: args.angle_png,
+        time_png: args.time_png,
+        subdirectories: args.subdirectories,
+        combine: args.combine.map(|combine| match combine {
+            CombineArg::Max => Combine::Max,
+            CombineArg::MaxPerFrequency => Combine::MaxPerFrequency,
+        }),
+        no_clobber: args.no_clobber,
+        no_homogenize: args.no_homogenize,
+        linear: args.linear,
+        best_band: args.best_band,
+        angle_degrees: args.angle_degrees,
+        stations: args.stations.clone(),
+    }
+}
+
+/// Replaces the settings of `scene` that are also given on the command line.
+fn apply_overrides(args: &Args, scene: &mut Config) {
+    if let Some(samples) = args.samples {
+        scene.samples_per_pixel = samples;
+    }
+}
+
+/// Runs --batch and writes its summary, returning whether every scene went well.
+fn batch(args: &Args) -> Result<bool, RaytracerError> {
+    let output = args.ouput_filename.as_deref().ok_or_else(|| {
+        RaytracerError::Validation("--batch needs an output directory".to_string())
+    })?;
+    let outcomes = generate_batch(
+        Path::new(&args.config),
+        Path::new(output),
+        &sigmap_options(args),
+    )?;
+    let mut summary = String::new();
+    for (name, outcome) in outcomes.iter() {
+        let line = match outcome {
+            BatchOutcome::Done => format!("{}: done\n", name),
+            BatchOutcome::Skipped => format!("{}: skipped\n", name),
+            BatchOutcome::Failed(e) => format!("{}: failed: {}\n", name, e),
+        };
+        summary.push_str(&line);
+    }
+    let summary_name = Path::new(output).join("batch_summary.txt");
+    fs::write(&summary_name, summary)?;
+    let failed = outcomes
+        .iter()
+        .filter(|(_, outcome)| matches!(outcome, BatchOutcome::Failed(_)))
+        .count();
+    log::info!(
+        "{} of {} scenes failed, see {}",
+        failed,
+        outcomes.len(),
+        summary_name.display()
+    );
+    Ok(failed == 0)
 }
 
 fn main() {
     let args = Args::parse();
-    let json = fs::read(&args.config).expect("Unable to read config file.");
-    let scene = serde_json::from_slice::<Config>(&json).expect("Unable to parse config json");
+    log::set_logger(&LOGGER).expect("Unable to set logger");
+    log::set_max_level(if args.quiet {
+        log::LevelFilter::Warn
+    } else if args.verbose {
+        log::LevelFilter::Debug
+    } else {
+        log::LevelFilter::Info
+    });
+    if args.batch {
+        match batch(&args) {
+            Ok(true) => return,
+            Ok(false) => std::process::exit(1),
+            Err(e) => {
+                log::error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if args.check {
+        let text = fs::read(&args.config).expect("Unable to read config file.");
+        let problems = check_config(&text, ConfigFormat::from_path(Path::new(&args.config)));
+        for problem in problems.iter() {
+            println!("{}", problem);
+        }
+        if !problems.is_empty() {
+            std::process::exit(1);
+        }
+        println!("{} is valid", args.config);
+        return;
+    }
+    let mut scene = Config::from_path(Path::new(&args.config)).expect("Unable to load config");
+    if let Err(e) = scene.migrate() {
+        log::error!("{}", e);
+        std::process::exit(1);
+    }
+    apply_overrides(&args, &mut scene);
+    if let Some(path) = &args.import {
+        let file = fs::File::open(path).expect("Unable to read import file.");
+        match import_buildings(&mut scene, BufReader::new(file)) {
+            Ok(count) => log::info!("Imported {} buildings from {}", count, path),
+            Err(e) => {
+                log::error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Err(e) = scene.validate() {
+        log::error!("{}", e);
+        std::process::exit(1);
+    }
+    log::info!("{}", scene.summary());
+    if args.dry_run {
+        dry_run(&scene, args.view);
+        return;
+    }
 
-    let filename = args.ouput_filename.as_str(); //format!("{}_{:0>3}.png", args[2], i);
-    println!("\nRendering {}", filename);
-    generate_sigmap(filename, &scene, args.times, args.angles, args.png);
-    if args.view {
-        let view_name = filename.to_string() + "_view.png";
-        render(&view_name, &scene);
+    let filename = args
+        .ouput_filename
+        .as_deref()
+        .expect("No output file given"); //format!("{}_{:0>3}.png", args[2], i);
+    if args.plan {
+        let plan_name = filename.to_string() + "_plan.png";
+        if args.no_clobber && Path::new(&plan_name).exists() {
+            log::error!("{} already exists", plan_name);
+            std::process::exit(1);
+        }
+        if let Err(e) = write_plan(&plan_name, &scene) {
+            log::error!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    log::info!("\nRendering {}", filename);
+    let options = sigmap_options(&args);
+    let station_ids: Vec<i64> = scene
+        .objects_by_material(|m| matches!(m, Material::Light(_)))
+        .iter()
+        .map(|s| s.id)
+        .collect();
+    for id in args.stations.iter().filter(|id| !station_ids.contains(id)) {
+        log::warn!("There is no station with id {}", id);
+    }
+    let budget_name = filename.to_string() + "_link_budget.json";
+    let view_name = filename.to_string() + "_view.png";
+    if args.no_clobber {
+        for (enabled, name) in [(args.link_budget, &budget_name), (args.view, &view_name)] {
+            if enabled && Path::new(name).exists() {
+                log::error!("{} already exists", name);
+                std::process::exit(1);
+            }
+        }
+    }
+    let result = if let Some(runs) = args.monte_carlo {
+        generate_monte_carlo(filename, &scene, runs, args.jitter, &options)
+    } else if args.stream {
+        stream_sigmap(filename, &scene, &options)
+    } else if args.freq_sweep.is_empty() {
+        generate_sigmap(filename, &scene, &options)
+    } else {
+        sweep_frequencies(filename, &mut scene, &args.freq_sweep, &options)
+    };
+    let sigmap_timing = result.as_ref().ok().cloned();
+    let mut result = result.map(|_| ());
+    if let (Ok(()), Some(path)) = (&result, &args.compare) {
+        result = compare(path, filename, &scene, &options);
     }
+    if let (Ok(()), Some(other)) = (&result, &args.diff) {
+        result = diff(other, filename, &scene, &options);
+    }
+    if let (Ok(()), Some(zoom)) = (&result, args.mercator) {
+        result = mercator(zoom, filename, &scene, &options);
+    }
+    if let (Ok(()), Some(threshold)) = (&result, args.coverage) {
+        result = coverage(threshold, filename, &scene, &options);
+    }
+    if let Err(e) = &result {
+        log::error!("{}", e);
+    } else if args.link_budget {
+        let file = fs::File::create(&budget_name).expect("Unable to create link budget file");
+        serde_json::to_writer_pretty(file, &link_budget(&scene))
+            .expect("Unable to write link budget");
+    }
+    // The preview does not need the signal map, so it is still rendered if that failed.
+    let view_timing = if args.view {
+        match render(&view_name, &scene) {
+            Ok(timing) => Some(timing),
+            Err(e) => {
+                log::error!("Unable to render the view: {}", e);
+                result = Err(e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    if let Some(path) = &args.timing_json {
+        let timings = Timings {
+            sigmap: sigmap_timing,
+            view: view_timing,
+        };
+        let file = fs::File::create(path).expect("Unable to create timing file");
+        serde_json::to_writer_pretty(file, &timings).expect("Unable to write timing");
+    }
+    if result.is_err() {
+        std::process::exit(1);
+    }
+}
+
+#[test]
+fn test_samples_override() {
+    let json = r#"{
+        "width": 10, "height": 10, "samples_per_pixel": 3, "nr_probes": 0, "max_depth": 5,
+        "camera": {
+            "look_from": {"x": 0.0, "y": 10.0, "z": 0.0},
+            "look_at": {"x": 5.0, "y": 0.0, "z": 5.0},
+            "vup": {"x": 0.0, "y": 1.0, "z": 0.0},
+            "vfov": 50.0, "aspect": 1.0
+        },
+        "objects": []
+    }"#;
+    let mut scene = Config::from_reader(json.as_bytes()).unwrap();
+    apply_overrides(
+        &Args::parse_from(["raytracer", "scene.json", "out"]),
+        &mut scene,
+    );
+    assert_eq!(scene.samples_per_pixel, 3);
+    let args = Args::parse_from(["raytracer", "scene.json", "out", "--samples", "7"]);
+    apply_overrides(&args, &mut scene);
+    assert_eq!(scene.samples_per_pixel, 7);
 }
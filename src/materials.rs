@@ -1,22 +1,32 @@
-use jpeg_decoder::Decoder;
-use palette::Srgb;
+use palette::{LinSrgb, Srgb};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
-use serde_with::serde_as;
-use std::fs::File;
-use std::io::BufReader;
 
+use crate::config::load_texture_image;
+use crate::error::RaytracerError;
+use crate::frequency::Frequency;
 use crate::point3d::Point3D;
 use crate::ray::HitRecord;
 use crate::ray::Ray;
 
 pub trait Scatterable {
     fn scatter(&self, ray: &Ray, hit_record: &HitRecord) -> Option<(Option<Ray>, Srgb)>;
+
+    /// All rays leaving the hit, for materials that split the signal over several of them.
+    /// Their powers add up to that of the single ray `scatter` returns.
+    fn scatter_multi(&self, ray: &Ray, hit_record: &HitRecord) -> Vec<(Option<Ray>, Srgb)> {
+        self.scatter(ray, hit_record).into_iter().collect()
+    }
+
+    /// Color the surface gives off by itself, added to whatever light it scatters.
+    fn emitted(&self) -> Srgb {
+        Srgb::new(0.0, 0.0, 0.0)
+    }
 }
 
 // https://docs.rs/serde_with/1.9.4/serde_with/macro.serde_conv.html
 serde_with::serde_conv!(
-    SrgbAsArray,
+    pub(crate) SrgbAsArray,
     Srgb,
     |srgb: &Srgb| [srgb.red, srgb.green, srgb.blue],
     |value: [f32; 3]| -> Result<_, std::convert::Infallible> {
@@ -24,21 +34,189 @@ serde_with::serde_conv!(
     }
 );
 
+/// Light color as either an `[r, g, b]` array or a `{ "kelvin": 3200 }` color temperature.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LightColor {
+    Rgb([f32; 3]),
+    Temperature { kelvin: f32 },
+}
+
+serde_with::serde_conv!(
+    SrgbAsArrayOrKelvin,
+    Srgb,
+    |srgb: &Srgb| [srgb.red, srgb.green, srgb.blue],
+    |value: LightColor| -> Result<_, std::convert::Infallible> {
+        Ok(match value {
+            LightColor::Rgb(rgb) => Srgb::new(rgb[0], rgb[1], rgb[2]),
+            LightColor::Temperature { kelvin } => kelvin_to_srgb(kelvin),
+        })
+    }
+);
+
+/// Color of a black body at the given temperature, scaled so the brightest channel is 1.
+///
+/// Uses the cubic approximation of the Planckian locus by Kim et al., which covers 1667K to
+/// 25000K; temperatures outside that range are clamped.
+pub fn kelvin_to_srgb(kelvin: f32) -> Srgb {
+    let t = kelvin.clamp(1667.0, 25000.0) as f64;
+    let x = if t <= 4000.0 {
+        -0.2661239e9 / t.powi(3) - 0.2343589e6 / t.powi(2) + 0.8776956e3 / t + 0.179910
+    } else {
+        -3.0258469e9 / t.powi(3) + 2.1070379e6 / t.powi(2) + 0.2226347e3 / t + 0.240390
+    };
+    let y = if t <= 2222.0 {
+        -1.1063814 * x.powi(3) - 1.34811020 * x.powi(2) + 2.18555832 * x - 0.20219683
+    } else if t <= 4000.0 {
+        -0.9549476 * x.powi(3) - 1.37418593 * x.powi(2) + 2.09137015 * x - 0.16748867
+    } else {
+        3.0817580 * x.powi(3) - 5.87338670 * x.powi(2) + 3.75112997 * x - 0.37001483
+    };
+    // xyY with Y = 1 to XYZ, then to linear sRGB.
+    let (big_x, big_y, big_z) = (x / y, 1.0, (1.0 - x - y) / y);
+    let linear = [
+        3.2404542 * big_x - 1.5371385 * big_y - 0.4985314 * big_z,
+        -0.9692660 * big_x + 1.8760108 * big_y + 0.0415560 * big_z,
+        0.0556434 * big_x - 0.2040259 * big_y + 1.0572252 * big_z,
+    ]
+    .map(|c| c.max(0.0));
+    let max = linear.iter().cloned().fold(f64::MIN, f64::max);
+    let [r, g, b] = linear.map(|c| (c / max) as f32);
+    Srgb::from_linear(LinSrgb::new(r, g, b))
+}
+
+/// Encodes a linear color, clamped to [0, 1], as 8-bit sRGB for image output.
+pub fn linear_to_srgb8(color: LinSrgb) -> [u8; 3] {
+    let clamped = LinSrgb::new(
+        color.red.clamp(0.0, 1.0),
+        color.green.clamp(0.0, 1.0),
+        color.blue.clamp(0.0, 1.0),
+    );
+    Srgb::<f32>::from_linear(clamped).into_format::<u8>().into()
+}
+
 // TODO: replace this with the more elegant implementation in config.rs
 serde_with::serde_conv!(
     TexturePixelsAsPath,
     Vec<u8>,
     |_pixels: &Vec<u8>| "/tmp/texture.jpg",
-    |value: &str| -> Result<_, std::convert::Infallible> { Ok(load_texture_image(value).0) }
+    |value: &str| -> Result<_, RaytracerError> { Ok(load_texture_image(value)?.0) }
 );
 
+/// A surface material. Besides the `{ "Metal": { ... } }` form of each variant, it can be
+/// given as a `{ "preset": "concrete" }`, see `MaterialPreset`.
 #[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(from = "MaterialSpec")]
 pub enum Material {
     Lambertian(Lambertian),
     Metal(Metal),
     Glass(Glass),
     Texture(Texture),
     Light(Light),
+    Wall(Wall),
+}
+
+/// The variants of `Material` as they are read, with the presets next to them.
+#[derive(Deserialize)]
+enum MaterialSpec {
+    Lambertian(Lambertian),
+    Metal(Metal),
+    Glass(Glass),
+    Texture(Texture),
+    Light(Light),
+    Wall(Wall),
+    #[serde(rename = "preset")]
+    Preset(MaterialPreset),
+}
+
+impl From<MaterialSpec> for Material {
+    fn from(spec: MaterialSpec) -> Material {
+        match spec {
+            MaterialSpec::Lambertian(l) => Material::Lambertian(l),
+            MaterialSpec::Metal(m) => Material::Metal(m),
+            MaterialSpec::Glass(g) => Material::Glass(g),
+            MaterialSpec::Texture(t) => Material::Texture(t),
+            MaterialSpec::Light(l) => Material::Light(l),
+            MaterialSpec::Wall(w) => Material::Wall(w),
+            MaterialSpec::Preset(preset) => preset.material(),
+        }
+    }
+}
+
+/// Electrical properties of a building material per ITU-R P.2040, Table 3, with the
+/// frequency f in GHz: relative permittivity `a * f^b` and conductivity `c * f^d` S/m.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct ItuMaterial {
+    pub a: f32,
+    pub b: f32,
+    pub c: f32,
+    pub d: f32,
+}
+
+impl ItuMaterial {
+    /// Loss in dB of a wave of `frequency` reflecting off the material at normal
+    /// incidence.
+    pub fn reflection_loss(&self, frequency: Frequency) -> f32 {
+        let f = frequency.ghz() as f32;
+        let permittivity = self.a * f.powf(self.b);
+        let conductivity = self.c * f.powf(self.d);
+        // Complex relative permittivity eta = re - j im, and its square root.
+        let (re, im) = (permittivity, 17.98 * conductivity / f);
+        let magnitude = (re * re + im * im).sqrt();
+        let (root_re, root_im) = (
+            ((magnitude + re) / 2.0).sqrt(),
+            -((magnitude - re) / 2.0).sqrt(),
+        );
+        // Reflection coefficient (1 - sqrt(eta)) / (1 + sqrt(eta)).
+        let numerator = ((1.0 - root_re).powi(2) + root_im.powi(2)).sqrt();
+        let denominator = ((1.0 + root_re).powi(2) + root_im.powi(2)).sqrt();
+        -20.0 * (numerator / denominator).log10()
+    }
+}
+
+/// Common building materials, which expand to a `Metal` whose reflection loss follows
+/// the ITU-R P.2040 parameters of the material. Glass expands to a `Glass` instead, with
+/// the refractive index its permittivity gives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MaterialPreset {
+    Concrete,
+    Brick,
+    Plasterboard,
+    Wood,
+    Glass,
+    Metal,
+}
+
+impl MaterialPreset {
+    pub fn itu(&self) -> ItuMaterial {
+        let (a, b, c, d) = match self {
+            MaterialPreset::Concrete => (5.24, 0.0, 0.0462, 0.7822),
+            MaterialPreset::Brick => (3.91, 0.0, 0.0238, 0.16),
+            MaterialPreset::Plasterboard => (2.73, 0.0, 0.0085, 0.9395),
+            MaterialPreset::Wood => (1.99, 0.0, 0.0047, 1.0718),
+            MaterialPreset::Glass => (6.31, 0.0, 0.0036, 1.3394),
+            MaterialPreset::Metal => (1.0, 0.0, 1e7, 0.0),
+        };
+        ItuMaterial { a, b, c, d }
+    }
+
+    pub fn material(&self) -> Material {
+        if let MaterialPreset::Glass = self {
+            return Material::Glass(Glass::new(self.itu().a.sqrt()));
+        }
+        let (albedo, fuzz) = match self {
+            MaterialPreset::Concrete => (Srgb::new(0.6, 0.6, 0.6), 0.3),
+            MaterialPreset::Brick => (Srgb::new(0.6, 0.3, 0.2), 0.3),
+            MaterialPreset::Plasterboard => (Srgb::new(0.9, 0.9, 0.85), 0.2),
+            MaterialPreset::Wood => (Srgb::new(0.5, 0.35, 0.2), 0.2),
+            MaterialPreset::Metal | MaterialPreset::Glass => (Srgb::new(0.8, 0.8, 0.8), 0.0),
+        };
+        Material::Metal(Metal {
+            itu: Some(self.itu()),
+            ..Metal::new(albedo, fuzz, 0.0)
+        })
+    }
 }
 
 impl Scatterable for Material {
@@ -49,31 +227,108 @@ impl Scatterable for Material {
             Material::Glass(g) => g.scatter(ray, hit_record),
             Material::Texture(t) => t.scatter(ray, hit_record),
             Material::Light(l) => l.scatter(ray, hit_record),
+            Material::Wall(w) => w.scatter(ray, hit_record),
+        }
+    }
+
+    fn scatter_multi(&self, ray: &Ray, hit_record: &HitRecord) -> Vec<(Option<Ray>, Srgb)> {
+        match self {
+            Material::Metal(m) => m.scatter_multi(ray, hit_record),
+            _ => self.scatter(ray, hit_record).into_iter().collect(),
+        }
+    }
+
+    fn emitted(&self) -> Srgb {
+        match self {
+            Material::Lambertian(l) => l.emitted(),
+            _ => Srgb::new(0.0, 0.0, 0.0),
         }
     }
 }
 
 #[serde_with::serde_as]
-#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Light {
-    #[serde_as(as = "SrgbAsArray")]
+    #[serde_as(as = "SrgbAsArrayOrKelvin")]
     pub color: Srgb,
     pub strength: f32,
     pub beams: i64,
-    pub frequency: i64,
+    pub frequency: Frequency,
+    /// Individually steered beams. When given, these replace the `beams` sectors.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub beam_configs: Vec<BeamConfig>,
+    /// Size of the antenna. Zero, the default, is a point source.
+    #[serde(default)]
+    pub aperture: Aperture,
+    /// Height of the antenna above the highest surface below it, a roof included. When
+    /// given, it replaces the height of the station's origin, see `Config::terrain_height`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height_agl: Option<f32>,
 }
 
 impl Light {
-    pub fn new(color: Srgb, strength: f32, beams: i64, frequency: i64) -> Light {
+    pub fn new(color: Srgb, strength: f32, beams: i64, frequency: Frequency) -> Light {
         Light {
             color,
             strength,
             beams,
             frequency,
+            beam_configs: vec![],
+            aperture: Aperture::default(),
+            height_agl: None,
         }
     }
 }
 
+/// A rectangular antenna in meters. Each signal ray leaves from a random point on it,
+/// with the rectangle standing upright and turned across the direction of the ray.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+pub struct Aperture {
+    pub width: f32,
+    pub height: f32,
+}
+
+impl Aperture {
+    /// Where a ray towards `direction` leaves an antenna centered on `center`.
+    pub fn ray_origin(&self, center: Point3D, direction: Point3D) -> Point3D {
+        if self.width == 0.0 && self.height == 0.0 {
+            return center;
+        }
+        let across = if direction.x() == 0.0 && direction.z() == 0.0 {
+            Point3D::new(1.0, 0.0, 0.0)
+        } else {
+            Point3D::new(-direction.z(), 0.0, direction.x()).unit_vector()
+        };
+        let mut rng = rand::thread_rng();
+        let (u, v) = (rng.gen::<f32>() - 0.5, rng.gen::<f32>() - 0.5);
+        center + across * (u * self.width) + Point3D::new(0.0, v * self.height, 0.0)
+    }
+}
+
+/// A beam pointed in its own direction, with angles in degrees.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct BeamConfig {
+    // Counted from the x axis towards z, like the `beams` sectors.
+    pub azimuth: f32,
+    // Electrical downtilt below the horizon.
+    pub tilt: f32,
+    // Added to the light's strength, in dB.
+    #[serde(default)]
+    pub gain: f32,
+}
+
+impl BeamConfig {
+    /// Unit vector along the center of the beam.
+    pub fn boresight(&self) -> Point3D {
+        let (azimuth, tilt) = (self.azimuth.to_radians(), self.tilt.to_radians());
+        Point3D::new(
+            azimuth.cos() * tilt.cos(),
+            -tilt.sin(),
+            azimuth.sin() * tilt.cos(),
+        )
+    }
+}
+
 impl Scatterable for Light {
     fn scatter(&self, _ray: &Ray, _hit_record: &HitRecord) -> Option<(Option<Ray>, Srgb)> {
         Some((None, self.color))
@@ -85,28 +340,51 @@ impl Scatterable for Light {
 pub struct Lambertian {
     #[serde_as(as = "SrgbAsArray")]
     pub albedo: Srgb,
+    /// Scatter back to whichever side the ray came from, for thin panels. Otherwise
+    /// rays always scatter to the outside of the surface.
+    #[serde(default)]
+    pub two_sided: bool,
+    /// A faint glow of its own, so enclosed rooms without lights are not pitch black
+    /// in the render. Has no effect on the signal.
+    #[serde_as(as = "SrgbAsArray")]
+    #[serde(default)]
+    pub emission: Srgb,
 }
 
 impl Lambertian {
     pub fn new(albedo: Srgb) -> Lambertian {
-        Lambertian { albedo }
+        Lambertian {
+            albedo,
+            two_sided: false,
+            emission: Srgb::new(0.0, 0.0, 0.0),
+        }
     }
 }
 
 impl Scatterable for Lambertian {
     fn scatter(&self, ray: &Ray, hit_record: &HitRecord) -> Option<(Option<Ray>, Srgb)> {
-        let scatter_direction = Point3D::random_in_hemi_sphere(&hit_record.normal);
+        // The hit record's normal faces the ray; the outward normal only does so on the front.
+        let normal = if self.two_sided || hit_record.front_face {
+            hit_record.normal
+        } else {
+            -hit_record.normal
+        };
+        let scatter_direction = Point3D::random_in_hemi_sphere(&normal);
         let target = hit_record.point + scatter_direction;
         let scattered = Ray::new(
             hit_record.point,
             target - hit_record.point,
             ray.strength,
-            hit_record.t,
+            ray.ray_time + hit_record.t,
             ray.frequency,
         );
         let attenuation = self.albedo;
         Some((Some(scattered), attenuation))
     }
+
+    fn emitted(&self) -> Srgb {
+        self.emission
+    }
 }
 
 #[serde_with::serde_as]
@@ -116,6 +394,15 @@ pub struct Metal {
     pub albedo: Srgb,
     pub fuzz: f32,
     pub dampening: f32,
+    /// Adds the frequency dependent reflection loss of this material to `dampening`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub itu: Option<ItuMaterial>,
+    /// With `scatter_multi`, split off this many diffusely scattered rays, sharing
+    /// `diffuse_fraction` of the reflected power.
+    #[serde(default)]
+    pub diffuse_rays: usize,
+    #[serde(default)]
+    pub diffuse_fraction: f32,
 }
 
 impl Metal {
@@ -124,23 +411,28 @@ impl Metal {
             albedo,
             fuzz,
             dampening,
+            itu: None,
+            diffuse_rays: 0,
+            diffuse_fraction: 0.0,
         }
     }
-}
 
-fn reflect(v: &Point3D, n: &Point3D) -> Point3D {
-    *v - *n * (2.0 * v.dot(n))
+    /// Loss in dB of a ray of `frequency` reflecting off the metal.
+    fn reflection_loss(&self, frequency: Frequency) -> f32 {
+        self.dampening + self.itu.map_or(0.0, |itu| itu.reflection_loss(frequency))
+    }
 }
 
 impl Scatterable for Metal {
     fn scatter(&self, ray: &Ray, hit_record: &HitRecord) -> Option<(Option<Ray>, Srgb)> {
-        let reflected = reflect(&ray.direction, &hit_record.normal);
+        let reflected = ray.direction.reflect(&hit_record.normal);
         //println!("Reflecting with strength {}", ray.strength_at(hit_record.t));
+        let loss = self.reflection_loss(ray.frequency);
         let scattered = Ray::new(
             hit_record.point,
             reflected + Point3D::random_in_unit_sphere() * self.fuzz,
-            ray.strength - self.dampening,
-            hit_record.t,
+            ray.strength - loss,
+            ray.ray_time + hit_record.t,
             ray.frequency,
         );
         let attenuation = self.albedo;
@@ -150,6 +442,73 @@ impl Scatterable for Metal {
             None
         }
     }
+
+    fn scatter_multi(&self, ray: &Ray, hit_record: &HitRecord) -> Vec<(Option<Ray>, Srgb)> {
+        if self.diffuse_rays == 0 || self.diffuse_fraction <= 0.0 {
+            return self.scatter(ray, hit_record).into_iter().collect();
+        }
+        let fraction = self.diffuse_fraction.min(1.0);
+        let mut scattered = Vec::with_capacity(self.diffuse_rays + 1);
+        if fraction < 1.0 {
+            if let Some((Some(mut specular), attenuation)) = self.scatter(ray, hit_record) {
+                specular.strength += 10.0 * (1.0 - fraction).log10();
+                scattered.push((Some(specular), attenuation));
+            }
+        }
+        let strength = ray.strength - self.reflection_loss(ray.frequency)
+            + 10.0 * (fraction / self.diffuse_rays as f32).log10();
+        for _ in 0..self.diffuse_rays {
+            let diffuse = Ray::new(
+                hit_record.point,
+                Point3D::random_in_hemi_sphere(&hit_record.normal),
+                strength,
+                ray.ray_time + hit_record.t,
+                ray.frequency,
+            );
+            scattered.push((Some(diffuse), self.albedo));
+        }
+        scattered
+    }
+}
+
+/// Surface that signals pass straight through, losing `penetration_loss` dB on the way in
+/// and `loss_per_meter` dB for each meter they travel inside it.
+#[serde_with::serde_as]
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct Wall {
+    #[serde_as(as = "SrgbAsArray")]
+    pub albedo: Srgb,
+    pub penetration_loss: f32,
+    #[serde(default)]
+    pub loss_per_meter: f32,
+}
+
+impl Wall {
+    pub fn new(albedo: Srgb, penetration_loss: f32) -> Wall {
+        Wall {
+            albedo,
+            penetration_loss,
+            loss_per_meter: 0.0,
+        }
+    }
+}
+
+impl Scatterable for Wall {
+    fn scatter(&self, ray: &Ray, hit_record: &HitRecord) -> Option<(Option<Ray>, Srgb)> {
+        let loss = if hit_record.front_face {
+            self.penetration_loss + self.loss_per_meter * hit_record.thickness
+        } else {
+            0.0
+        };
+        let scattered = Ray::new(
+            hit_record.point,
+            ray.direction,
+            ray.strength - loss,
+            ray.ray_time + hit_record.t,
+            ray.frequency,
+        );
+        Some((Some(scattered), self.albedo))
+    }
 }
 
 #[derive(Debug, Clone, Copy, Deserialize, Serialize)]
@@ -165,29 +524,12 @@ impl Glass {
     }
 }
 
-fn refract(uv: &Point3D, n: &Point3D, etai_over_etat: f32) -> Point3D {
-    let cos_theta = ((-*uv).dot(n)).min(1.0);
-    let r_out_perp = (*uv + *n * cos_theta) * etai_over_etat;
-    let r_out_parallel = *n * (-1.0 * (1.0 - r_out_perp.length_squared()).abs().sqrt());
-    r_out_perp + r_out_parallel
-}
-
 fn reflectance(cosine: f32, ref_idx: f32) -> f32 {
     let mut r0 = (1.0 - ref_idx) / (1.0 + ref_idx);
     r0 = r0 * r0;
     r0 + (1.0 - r0) * (1.0 - cosine).powi(5)
 }
 
-#[test]
-fn test_refract() {
-    let uv = Point3D::new(1.0, 1.0, 0.0);
-    let n = Point3D::new(-1.0, 0.0, 0.0);
-    let etai_over_etat = 1.0;
-    let expected = Point3D::new(0.0, 1.0, 0.0);
-    let actual = refract(&uv, &n, etai_over_etat);
-    assert_eq!(actual, expected);
-}
-
 #[test]
 fn test_reflectance() {
     let cosine = 0.0;
@@ -211,22 +553,22 @@ impl Scatterable for Glass {
         let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
         let cannot_refract = refraction_ratio * sin_theta > 1.0;
         if cannot_refract || reflectance(cos_theta, refraction_ratio) > rng.gen::<f32>() {
-            let reflected = reflect(&unit_direction, &hit_record.normal);
+            let reflected = unit_direction.reflect(&hit_record.normal);
             let scattered = Ray::new(
                 hit_record.point,
                 reflected,
                 ray.strength,
-                hit_record.t,
+                ray.ray_time + hit_record.t,
                 ray.frequency,
             );
             Some((Some(scattered), attenuation))
         } else {
-            let direction = refract(&unit_direction, &hit_record.normal, refraction_ratio);
+            let direction = unit_direction.refract(&hit_record.normal, refraction_ratio);
             let scattered = Ray::new(
                 hit_record.point,
                 direction,
                 ray.strength,
-                hit_record.t,
+                ray.ray_time + hit_record.t,
                 ray.frequency,
             );
             Some((Some(scattered), attenuation))
@@ -246,26 +588,31 @@ pub struct Texture {
     h_offset: f32,
 }
 
-fn load_texture_image(path: &str) -> (Vec<u8>, u64, u64) {
-    let file = File::open(path).expect(path);
-    let mut decoder = Decoder::new(BufReader::new(file));
-    let pixels = decoder.decode().expect("failed to decode image");
-    let metadata = decoder.info().unwrap();
-    (pixels, metadata.width as u64, metadata.height as u64)
-}
-
 impl Texture {
-    pub fn new(albedo: Srgb, texture_path: &str, rot: f32) -> Texture {
-        let file = File::open(texture_path).expect("failed to open texture file");
-        let mut decoder = Decoder::new(BufReader::new(file));
-        let pixels = decoder.decode().expect("failed to decode image");
-        let metadata = decoder.info().unwrap();
+    pub fn new(albedo: Srgb, texture_path: &str, rot: f32) -> Result<Texture, RaytracerError> {
+        let (pixels, width, height) = load_texture_image(texture_path)?;
+        Ok(Texture::from_pixels(albedo, pixels, width, height, rot))
+    }
+
+    /// Texture from rgb `pixels` in rows from the top, `width` by `height`.
+    pub fn from_pixels(
+        albedo: Srgb,
+        pixels: Vec<u8>,
+        width: usize,
+        height: usize,
+        h_offset: f32,
+    ) -> Texture {
+        assert_eq!(
+            pixels.len(),
+            width * height * 3,
+            "texture size does not match its pixels"
+        );
         Texture {
             albedo,
             pixels,
-            width: metadata.width as u64,
-            height: metadata.height as u64,
-            h_offset: rot,
+            width: width as u64,
+            height: height as u64,
+            h_offset,
         }
     }
 
@@ -299,10 +646,184 @@ impl Scatterable for Texture {
             hit_record.point,
             target - hit_record.point,
             ray.strength,
-            hit_record.t,
+            ray.ray_time + hit_record.t,
             ray.frequency,
         );
         let attenuation = self.get_albedo(hit_record.u, hit_record.v);
         Some((Some(scattered), attenuation))
     }
 }
+
+#[test]
+fn test_kelvin_to_srgb() {
+    let white = kelvin_to_srgb(6500.0);
+    for channel in [white.red, white.green, white.blue] {
+        assert!(channel > 0.95, "{:?} is not white", white);
+    }
+    let candle = kelvin_to_srgb(1900.0);
+    assert!(candle.red > candle.green && candle.green > candle.blue);
+
+    let light: Light = serde_json::from_str(
+        r#"{"color": {"kelvin": 6500}, "strength": 1.0, "beams": 1, "frequency": 2400}"#,
+    )
+    .unwrap();
+    assert_eq!(light.color, white);
+    let light: Light = serde_json::from_str(
+        r#"{"color": [0.5, 0.5, 0.5], "strength": 1.0, "beams": 1, "frequency": 2400}"#,
+    )
+    .unwrap();
+    assert_eq!(light.color, Srgb::new(0.5, 0.5, 0.5));
+}
+
+#[test]
+fn test_two_sided_lambertian() {
+    // Hitting a panel in the z = 0 plane from behind, coming from +z.
+    let ray = Ray::new(
+        Point3D::new(0.0, 0.0, 1.0),
+        Point3D::new(0.0, 0.0, -1.0),
+        0.0,
+        0.0,
+        Frequency::from_mhz(2000.0),
+    );
+    let mut lambertian = Lambertian::new(Srgb::new(0.5, 0.5, 0.5));
+    let material = Material::Lambertian(lambertian);
+    let hit_record = HitRecord {
+        t: 1.0,
+        point: Point3D::new(0.0, 0.0, 0.0),
+        normal: Point3D::new(0.0, 0.0, 1.0),
+        front_face: false,
+        thickness: 0.0,
+        material: &material,
+        u: 0.0,
+        v: 0.0,
+    };
+    for _ in 0..100 {
+        let (scattered, _) = lambertian.scatter(&ray, &hit_record).unwrap();
+        assert!(scattered.unwrap().direction.z() <= 0.0);
+    }
+    lambertian.two_sided = true;
+    for _ in 0..100 {
+        let (scattered, _) = lambertian.scatter(&ray, &hit_record).unwrap();
+        assert!(scattered.unwrap().direction.z() >= 0.0);
+    }
+}
+
+#[test]
+fn test_texture_from_pixels() {
+    let (black, white) = (Srgb::new(0.0, 0.0, 0.0), Srgb::new(1.0, 1.0, 1.0));
+    #[rustfmt::skip]
+    let pixels = vec![
+        0, 0, 0, 255, 255, 255,
+        255, 255, 255, 0, 0, 0,
+    ];
+    let texture = Texture::from_pixels(white, pixels, 2, 2, 0.0);
+    // v = 1 is the top row.
+    assert_eq!(texture.get_albedo(0.25, 1.0), black);
+    assert_eq!(texture.get_albedo(0.75, 1.0), white);
+    assert_eq!(texture.get_albedo(0.25, 0.0), white);
+    assert_eq!(texture.get_albedo(0.75, 0.0), black);
+    // The offset wraps around horizontally.
+    let shifted = Texture::from_pixels(white, texture.pixels.clone(), 2, 2, 0.5);
+    assert_eq!(shifted.get_albedo(0.75, 1.0), black);
+    assert_eq!(shifted.get_albedo(0.25, 1.0), white);
+}
+
+#[test]
+fn test_concrete_preset() {
+    let material: Material = serde_json::from_str(r#"{ "preset": "concrete" }"#).unwrap();
+    let Material::Metal(metal) = material else {
+        panic!("Expected a metal, got {:?}", material);
+    };
+    assert_eq!(
+        metal.itu,
+        Some(ItuMaterial {
+            a: 5.24,
+            b: 0.0,
+            c: 0.0462,
+            d: 0.7822
+        })
+    );
+    assert_eq!(metal.dampening, 0.0);
+    // |(1 - sqrt(5.24 - 0.715j)) / (1 + sqrt(5.24 - 0.715j))| at 2 GHz.
+    let loss = metal
+        .itu
+        .unwrap()
+        .reflection_loss(Frequency::from_mhz(2000.0));
+    assert!((loss - 8.07).abs() < 0.05, "{}", loss);
+    // Metal reflects nearly everything.
+    assert!(
+        MaterialPreset::Metal
+            .itu()
+            .reflection_loss(Frequency::from_mhz(2000.0))
+            < 0.01
+    );
+    let plain: Material =
+        serde_json::from_str(r#"{ "Metal": { "albedo": [1, 1, 1], "fuzz": 0, "dampening": 3 } }"#)
+            .unwrap();
+    assert!(matches!(plain, Material::Metal(Metal { itu: None, .. })));
+    assert!(serde_json::from_str::<Material>(r#"{ "preset": "cheese" }"#).is_err());
+    assert!(serde_json::from_str::<Material>(r#"{ "Cheese": {} }"#).is_err());
+    let glass: Material = serde_json::from_str(r#"{ "preset": "glass" }"#).unwrap();
+    let Material::Glass(glass) = glass else {
+        panic!("Expected a glass, got {:?}", glass);
+    };
+    assert!((glass.index_of_refraction - 6.31f32.sqrt()).abs() < 1e-6);
+}
+
+#[test]
+fn test_material_ron_round_trip() {
+    let material: Material = ron::from_str("Lambertian((albedo: (0.5, 0.25, 1.0)))").unwrap();
+    let Material::Lambertian(ref lambertian) = material else {
+        panic!("Expected a lambertian, got {:?}", material);
+    };
+    assert_eq!(lambertian.albedo, Srgb::new(0.5, 0.25, 1.0));
+    let text = ron::to_string(&material).unwrap();
+    let parsed: Material = ron::from_str(&text).unwrap();
+    assert_eq!(ron::to_string(&parsed).unwrap(), text);
+    let preset: Material = ron::from_str("preset(concrete)").unwrap();
+    assert!(matches!(
+        preset,
+        Material::Metal(Metal { itu: Some(_), .. })
+    ));
+}
+
+#[test]
+fn test_split_scatter_energy() {
+    let mut metal = Metal::new(Srgb::new(1.0, 1.0, 1.0), 0.0, 3.0);
+    metal.diffuse_rays = 4;
+    metal.diffuse_fraction = 0.3;
+    let ray = Ray::new(
+        Point3D::new(0.0, 1.0, 0.0),
+        Point3D::new(1.0, -1.0, 0.0),
+        30.0,
+        0.0,
+        Frequency::from_mhz(2000.0),
+    );
+    let material = Material::Metal(metal);
+    let hit_record = HitRecord {
+        t: 2f32.sqrt(),
+        point: Point3D::new(1.0, 0.0, 0.0),
+        normal: Point3D::new(0.0, 1.0, 0.0),
+        front_face: true,
+        thickness: 0.0,
+        material: &material,
+        u: 0.0,
+        v: 0.0,
+    };
+    let single = metal.scatter(&ray, &hit_record).unwrap().0.unwrap();
+    let split = material.scatter_multi(&ray, &hit_record);
+    assert_eq!(split.len(), 5);
+    let power = |dbm: f32| 10f32.powf(dbm / 10.0);
+    let total: f32 = split
+        .iter()
+        .map(|(ray, _)| power(ray.as_ref().unwrap().strength))
+        .sum();
+    assert!(
+        (total / power(single.strength) - 1.0).abs() < 1e-4,
+        "{}",
+        total
+    );
+    for (ray, _) in split.iter() {
+        assert!(ray.as_ref().unwrap().direction.dot(&hit_record.normal) > 0.0);
+    }
+}
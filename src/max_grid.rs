@@ -0,0 +1,93 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Grid of f32 values that only go up, safe to update from several threads at
+/// once. Values are stored as their bit patterns in atomics.
+pub struct MaxGrid {
+    cells: Vec<AtomicU32>,
+}
+
+impl MaxGrid {
+    pub fn new(len: usize, initial: f32) -> MaxGrid {
+        MaxGrid {
+            cells: (0..len)
+                .map(|_| AtomicU32::new(initial.to_bits()))
+                .collect(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.cells.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> f32 {
+        f32::from_bits(self.cells[index].load(Ordering::Relaxed))
+    }
+
+    /// Raises the value at `index` to `value` if that is larger. Returns whether
+    /// it did, so the caller can record what else goes with the new maximum.
+    pub fn update_max(&self, index: usize, value: f32) -> bool {
+        let cell = &self.cells[index];
+        let mut current = cell.load(Ordering::Relaxed);
+        loop {
+            if value.partial_cmp(&f32::from_bits(current)) != Some(std::cmp::Ordering::Greater) {
+                return false;
+            }
+            match cell.compare_exchange_weak(
+                current,
+                value.to_bits(),
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    pub fn into_vec(self) -> Vec<f32> {
+        self.cells
+            .into_iter()
+            .map(|cell| f32::from_bits(cell.into_inner()))
+            .collect()
+    }
+}
+
+#[test]
+fn test_update_max() {
+    let grid = MaxGrid::new(2, -140.0);
+    assert!(grid.update_max(0, -80.0));
+    assert!(!grid.update_max(0, -90.0));
+    assert!(!grid.update_max(0, f32::NAN));
+    assert_eq!(grid.into_vec(), vec![-80.0, -140.0]);
+}
+
+#[test]
+fn test_update_max_concurrent() {
+    // Threads alternate between rising and falling sequences so they race.
+    let value = |thread: usize, i: usize| {
+        let step = if thread.is_multiple_of(2) { i } else { 10_000 - i };
+        step as f32 / 100.0 - thread as f32
+    };
+    let grid = MaxGrid::new(4, -140.0);
+    std::thread::scope(|scope| {
+        for thread in 0..8 {
+            let grid = &grid;
+            scope.spawn(move || {
+                for i in 0..10_000 {
+                    grid.update_max(i % 4, value(thread, i));
+                }
+            });
+        }
+    });
+    let mut expected = [-140.0f32; 4];
+    for thread in 0..8 {
+        for i in 0..10_000 {
+            expected[i % 4] = expected[i % 4].max(value(thread, i));
+        }
+    }
+    assert_eq!(grid.into_vec(), expected.to_vec());
+}
@@ -2,6 +2,7 @@ use rand::Rng;
 use serde::{Deserialize, Serialize};
 use std::cmp::PartialEq;
 use std::f32::consts;
+use std::iter::Sum;
 use std::ops::{Add, Div, Mul, Neg, Sub};
 use rand_distr::StandardNormal;
 
@@ -41,18 +42,21 @@ impl Point3D {
         )
     }
 
+    /// Uniformly distributed point on the unit sphere. By Archimedes' hat-box
+    /// theorem, a uniform height and azimuth give a uniform area density.
     pub fn random_in_unit_sphere() -> Point3D {
         let mut rng = rand::thread_rng();
         let phi = rng.gen_range(-consts::PI..consts::PI);
-        let psi = rng.gen_range(-consts::PI..consts::PI);
-        Point3D::new(phi.sin() * psi.cos(), psi.sin(), phi.cos() * psi.cos())
+        let z: f32 = rng.gen_range(-1.0..1.0);
+        let radius = (1.0 - z * z).sqrt();
+        Point3D::new(radius * phi.cos(), radius * phi.sin(), z)
     }
     pub fn random_in_hemi_sphere(dir: &Point3D) -> Point3D {
         let new = Point3D::random_in_unit_sphere();
-        if new.distance(dir) < consts::SQRT_2 {
-            new
-        } else {
+        if new.dot(dir) < 0.0 {
             -new
+        } else {
+            new
         }
     }
 
@@ -100,6 +104,60 @@ impl Point3D {
         )
     }
 
+    /// Rotates the vector around the y axis by `angle` radians, counter-clockwise
+    /// when looking down from +y.
+    pub fn rotate_y(&self, angle: f32) -> Point3D {
+        let (sin, cos) = angle.sin_cos();
+        Point3D::new(
+            self.x * cos + self.z * sin,
+            self.y,
+            -self.x * sin + self.z * cos,
+        )
+    }
+
+    /// Mirrors the vector in the plane with normal `normal`, which must be a unit vector.
+    pub fn reflect(&self, normal: &Point3D) -> Point3D {
+        *self - *normal * (2.0 * self.dot(normal))
+    }
+
+    /// Bends a unit vector passing through a surface with unit normal `normal` following
+    /// Snell's law, where `eta` is the ratio of the refractive indices (incoming over outgoing).
+    /// The normal has to point against the vector.
+    pub fn refract(&self, normal: &Point3D, eta: f32) -> Point3D {
+        let cos_theta = ((-*self).dot(normal)).min(1.0);
+        let r_out_perp = (*self + *normal * cos_theta) * eta;
+        let r_out_parallel = *normal * -(1.0 - r_out_perp.length_squared()).abs().sqrt();
+        r_out_perp + r_out_parallel
+    }
+
+    /// Component-wise minimum, as for the lower corner of a bounding box.
+    pub fn min(&self, other: &Point3D) -> Point3D {
+        Point3D::new(self.x.min(other.x), self.y.min(other.y), self.z.min(other.z))
+    }
+
+    /// Component-wise maximum, as for the upper corner of a bounding box.
+    pub fn max(&self, other: &Point3D) -> Point3D {
+        Point3D::new(self.x.max(other.x), self.y.max(other.y), self.z.max(other.z))
+    }
+
+    /// The nearest point within the box from `lo` to `hi`, clamping each component
+    /// separately. `lo` must not be above `hi` in any component.
+    pub fn clamp(&self, lo: &Point3D, hi: &Point3D) -> Point3D {
+        Point3D::new(
+            self.x.clamp(lo.x, hi.x),
+            self.y.clamp(lo.y, hi.y),
+            self.z.clamp(lo.z, hi.z),
+        )
+    }
+
+    /// Average of `points`, or `None` if there are none.
+    pub fn mean(points: &[Point3D]) -> Option<Point3D> {
+        if points.is_empty() {
+            return None;
+        }
+        Some(points.iter().copied().sum::<Point3D>() / points.len() as f32)
+    }
+
     pub fn near_zero(&self) -> bool {
         self.x.abs() < f32::EPSILON && self.y.abs() < f32::EPSILON && self.z.abs() < f32::EPSILON
     }
@@ -117,6 +175,12 @@ impl Add for Point3D {
     }
 }
 
+impl Sum for Point3D {
+    fn sum<I: Iterator<Item = Point3D>>(iter: I) -> Point3D {
+        iter.fold(Point3D::new(0.0, 0.0, 0.0), |total, p| total + p)
+    }
+}
+
 impl Sub for Point3D {
     type Output = Point3D;
 
@@ -195,3 +259,86 @@ impl PartialEq for Point3D {
     }
 }
 
+
+#[test]
+fn test_random_in_unit_sphere_uniform() {
+    const SAMPLES: usize = 100_000;
+    const BINS: usize = 10;
+    let mut histogram = [0usize; BINS];
+    for _ in 0..SAMPLES {
+        let p = Point3D::random_in_unit_sphere();
+        assert!((p.length() - 1.0).abs() < 1e-4);
+        let bin = (((p.z() + 1.0) / 2.0) * BINS as f32) as usize;
+        histogram[bin.min(BINS - 1)] += 1;
+    }
+    // Each bin expects 10000 samples with a standard deviation of about 95.
+    for count in histogram {
+        assert!(count.abs_diff(SAMPLES / BINS) < 600, "{:?}", histogram);
+    }
+}
+
+#[test]
+fn test_random_in_hemi_sphere() {
+    // The normal does not need to be a unit vector.
+    for normal in [
+        Point3D::new(0.0, 3.0, 0.0),
+        Point3D::new(-0.2, 0.0, 0.1),
+        Point3D::new(1.0, -1.0, 1.0),
+    ] {
+        for _ in 0..1000 {
+            assert!(Point3D::random_in_hemi_sphere(&normal).dot(&normal) >= 0.0);
+        }
+    }
+}
+
+#[test]
+fn test_reflect() {
+    let v = Point3D::new(1.0, -1.0, 0.5);
+    let n = Point3D::new(0.0, 1.0, 0.0);
+    assert_eq!(v.reflect(&n), Point3D::new(1.0, 1.0, 0.5));
+    // Reflecting twice gives the original vector back.
+    assert_eq!(v.reflect(&n).reflect(&n), v);
+}
+
+#[test]
+fn test_refract() {
+    let uv = Point3D::new(1.0, 1.0, 0.0);
+    let n = Point3D::new(-1.0, 0.0, 0.0);
+    assert_eq!(uv.refract(&n, 1.0), Point3D::new(0.0, 1.0, 0.0));
+    // Head-on the direction does not change, whatever the indices.
+    let down = Point3D::new(0.0, -1.0, 0.0);
+    let up = Point3D::new(0.0, 1.0, 0.0);
+    assert_eq!(down.refract(&up, 1.0 / 1.5), down);
+}
+
+#[test]
+fn test_sum_and_mean() {
+    let points = [
+        Point3D::new(1.0, 2.0, 3.0),
+        Point3D::new(-1.0, 0.0, 1.0),
+        Point3D::new(3.0, 1.0, -1.0),
+    ];
+    assert_eq!(points.iter().copied().sum::<Point3D>(), Point3D::new(3.0, 3.0, 3.0));
+    assert_eq!(Point3D::mean(&points), Some(Point3D::new(1.0, 1.0, 1.0)));
+    assert_eq!(std::iter::empty().sum::<Point3D>(), Point3D::new(0.0, 0.0, 0.0));
+    assert_eq!(Point3D::mean(&[]), None);
+}
+
+#[test]
+fn test_min_max() {
+    let a = Point3D::new(1.0, -2.0, 3.0);
+    let b = Point3D::new(-1.0, 5.0, 3.0);
+    assert_eq!(a.min(&b), Point3D::new(-1.0, -2.0, 3.0));
+    assert_eq!(a.max(&b), Point3D::new(1.0, 5.0, 3.0));
+    assert_eq!(a.min(&a), a);
+}
+
+#[test]
+fn test_clamp() {
+    let lo = Point3D::new(0.0, 0.0, 0.0);
+    let hi = Point3D::new(10.0, 5.0, 1.0);
+    assert_eq!(Point3D::new(-3.0, 2.0, 4.0).clamp(&lo, &hi), Point3D::new(0.0, 2.0, 1.0));
+    assert_eq!(Point3D::new(12.0, -1.0, 0.5).clamp(&lo, &hi), Point3D::new(10.0, 0.0, 0.5));
+    // Points inside the box stay where they are.
+    assert_eq!(Point3D::new(3.0, 4.0, 0.0).clamp(&lo, &hi), Point3D::new(3.0, 4.0, 0.0));
+}
@@ -1,18 +1,34 @@
+use crate::frequency::Frequency;
 use crate::materials::Material;
 use crate::point3d::Point3D;
 
+// Relative precision of f32 positions, with some margin for the rounding the
+// intersection tests add.
+const RELATIVE_EPSILON: f32 = 32.0 * f32::EPSILON;
+
+/// A ray whose next hit is closer than this many times its self-intersection t
+/// is caught bouncing around a corner, and gets nowhere by tracing it further.
+pub const STALL_FACTOR: f32 = 10.0;
+
+/// Wavelength over 4 pi in meters, the distance at which free-space falloff at
+/// `frequency` is 0 dB.
+fn dist_factor(frequency: Frequency) -> f32 {
+    frequency.wavelength() / (4.0 * std::f32::consts::PI)
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct Ray {
     pub origin: Point3D,
     pub direction: Point3D,
     pub strength: f32,
     pub ray_time: f32,
-    pub frequency: i64,
+    pub frequency: Frequency,
     dist_factor: f32,
 }
 
 impl Ray {
-    fn free_space_fallof(&self, dist: f32) -> f32 {
+    /// Free-space path loss in dB over `dist` meters at this ray's frequency.
+    pub fn free_space_fallof(&self, dist: f32) -> f32 {
         20.0 * (dist / self.dist_factor).log10()
     }
     pub fn new(
@@ -20,28 +36,66 @@ impl Ray {
         direction: Point3D,
         strength: f32,
         ray_time: f32,
-        frequency: i64,
+        frequency: Frequency,
     ) -> Ray {
-        let dist_factor: f32 = {
-            let wavelength: f32 = 299792458.0 / ((frequency * 1_000_000) as f32);
-            wavelength / (4.0 * std::f32::consts::PI)
-        };
         Ray {
             origin,
             direction,
             strength,
             ray_time,
             frequency,
-            dist_factor,
+            dist_factor: dist_factor(frequency),
+        }
+    }
+
+    /// The same ray at `frequency`, for materials that shift the frequency of
+    /// what they scatter. Free-space falloff over the whole path, including the part
+    /// already travelled, is then taken at the new frequency.
+    pub fn with_frequency(self, frequency: Frequency) -> Ray {
+        Ray {
+            frequency,
+            dist_factor: dist_factor(frequency),
+            ..self
+        }
+    }
+
+    /// Ray that only tests what lies along `direction`, as for visibility checks in
+    /// the preview. It carries no signal, so its strength is NaN.
+    pub fn shadow(origin: Point3D, direction: Point3D) -> Ray {
+        Ray {
+            origin,
+            direction,
+            strength: f32::NAN,
+            ray_time: 0.0,
+            frequency: Frequency::from_hz(0),
+            dist_factor: f32::NAN,
         }
     }
 
+    /// Smallest t at which a hit is a different surface from the one the ray leaves.
+    /// That is `base`, unless the ray starts so far from the scene origin that f32
+    /// positions are coarser than that.
+    pub fn self_intersection_t(&self, base: f32) -> f32 {
+        let scale = self
+            .origin
+            .x()
+            .abs()
+            .max(self.origin.y().abs())
+            .max(self.origin.z().abs());
+        base.max(scale * RELATIVE_EPSILON / self.direction.length())
+    }
+
     pub fn at(&self, t: f32) -> Point3D {
         self.origin + self.direction * t
     }
     pub fn strength_at(&self, t: f32) -> f32 {
         self.strength - self.free_space_fallof(self.ray_time + t)
     }
+    /// Inverse of `strength_at`: the distance along the ray at which free-space
+    /// falloff alone brings the ray down to `strength`.
+    pub fn distance_to_strength(&self, strength: f32) -> f32 {
+        self.dist_factor * 10f32.powf((self.strength - strength) / 20.0) - self.ray_time
+    }
 }
 
 pub struct HitRecord<'material> {
@@ -49,11 +103,51 @@ pub struct HitRecord<'material> {
     pub point: Point3D,
     pub normal: Point3D,
     pub front_face: bool,
+    // Distance the ray goes on inside the object before it leaves again, for hits
+    // from the outside. 0 when it is not known.
+    pub thickness: f32,
     pub material: &'material Material,
     pub u: f32,
     pub v: f32,
 }
 
 pub trait Hittable {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord>;
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>>;
+    /// Axis-aligned box enclosing the object, as (min corner, max corner).
+    fn bounding_box(&self) -> (Point3D, Point3D);
+}
+
+#[test]
+fn test_self_intersection_t() {
+    let direction = Point3D::new(0.0, -2.0, 0.0);
+    let near = Ray::new(
+        Point3D::new(1.0, 2.0, 3.0),
+        direction,
+        0.0,
+        0.0,
+        Frequency::from_mhz(2000.0),
+    );
+    assert_eq!(near.self_intersection_t(1e-5), 1e-5);
+    // At 50 km, neighbouring f32 values are about 4 mm apart.
+    let far = Ray::new(
+        Point3D::new(50000.0, 2.0, 3.0),
+        direction,
+        0.0,
+        0.0,
+        Frequency::from_mhz(2000.0),
+    );
+    let t = far.self_intersection_t(1e-5);
+    assert!(t * direction.length() > 0.004 && t * direction.length() < 0.5);
+}
+
+#[test]
+fn test_with_frequency() {
+    let origin = Point3D::new(1.0, 2.0, 3.0);
+    let direction = Point3D::new(0.0, -1.0, 0.0);
+    let shifted = Ray::new(origin, direction, -10.0, 5.0, Frequency::from_mhz(700.0))
+        .with_frequency(Frequency::from_mhz(3500.0));
+    let direct = Ray::new(origin, direction, -10.0, 5.0, Frequency::from_mhz(3500.0));
+    assert_eq!(shifted.frequency, Frequency::from_mhz(3500.0));
+    assert_eq!(shifted.dist_factor, direct.dist_factor);
+    assert_eq!(shifted.strength_at(20.0), direct.strength_at(20.0));
 }
@@ -1,110 +1,178 @@
 use image::codecs::png::PngEncoder;
 use image::ImageEncoder;
-use palette::Srgb;
-use rand::Rng;
+use palette::{LinSrgb, Srgb};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use rayon::prelude::*;
 use std::fs::File;
+use std::ops::Range;
 use std::time::Instant;
 
-use crate::config::Config;
+use crate::config::{resize_image, Config};
 use crate::cube::Cube;
+use crate::error::RaytracerError;
 use crate::materials::Material;
+use crate::materials::linear_to_srgb8;
 use crate::materials::Scatterable;
+use crate::point3d::Point3D;
 use crate::ray::HitRecord;
 use crate::ray::Hittable;
 use crate::ray::Ray;
+use crate::ray::STALL_FACTOR;
+use crate::world::World;
+use crate::timing::TimingReport;
 
-fn write_image(filename: &str, pixels: &[u8], bounds: (usize, usize)) -> Result<(), &'static str> {
-    let output = File::create(filename).map_err(|_| "Could not create file")?;
+fn write_image(
+    filename: &str,
+    pixels: &[u8],
+    bounds: (usize, usize),
+    scene: &Config,
+) -> Result<(), RaytracerError> {
+    let (pixels, bounds) = resize_image(pixels, bounds, scene.image_scale, scene.image_filter);
+    let output = File::create(filename)?;
     let encoder = PngEncoder::new(output);
     encoder
         .write_image(
-            pixels,
+            &pixels,
             bounds.0 as u32,
             bounds.1 as u32,
             image::ExtendedColorType::Rgb8,
         )
-        .map_err(|_| "Could not write image")?;
+        .map_err(|e| RaytracerError::Io(std::io::Error::other(e)))?;
     Ok(())
 }
 
-fn hit_world<'material>(
-    world: &'material Vec<Cube>,
-    r: &Ray,
-    t_min: f32,
-    t_max: f32,
-) -> Option<HitRecord<'material>> {
-    let mut closest_so_far = t_max;
-    let mut hit_record = None;
-    for sphere in world {
-        if let Some(hit) = sphere.hit(r, t_min, closest_so_far) {
-            closest_so_far = hit.t;
-            hit_record = Some(hit);
-        }
+fn falloff(dist: f32) -> f32 {
+    (((20.0 - dist) / 20.0).powi(2)).clamp(0.0, 1.0)
+}
+
+/// Whether the light around `point + to_light` can be seen from `point`.
+fn light_visible(scene: &Config, point: Point3D, to_light: Point3D) -> bool {
+    let shadow_ray = Ray::shadow(point, to_light);
+    let t_min = shadow_ray.self_intersection_t(0.001);
+    // The target lies inside the light, so the first thing hit before it has to be a light.
+    match World::new(&scene.objects).hit(&shadow_ray, t_min, 1.0) {
+        Some(blocker) => matches!(blocker.material, Material::Light(_)),
+        None => true,
     }
-    hit_record
 }
 
-fn falloff(dist: f32) -> f32 {
-    (((20.0 - dist) / 20.0).powi(2)).clamp(0.0, 1.0)
+/// Light arriving at a diffuse hit directly from one randomly picked light, already divided by
+/// the chance of picking it. Zero when the light is behind the surface or blocked.
+fn sample_light(hit_record: &HitRecord, scene: &Config, lights: &[Cube]) -> Srgb {
+    let mut rng = rand::thread_rng();
+    if lights.is_empty() {
+        return Srgb::new(0.0, 0.0, 0.0);
+    }
+    let light = &lights[rng.gen_range(0..lights.len())];
+    let Material::Light(emitter) = &light.material else {
+        return Srgb::new(0.0, 0.0, 0.0);
+    };
+    let to_light = light.origin - hit_record.point;
+    // The normal is on the side the ray came from.
+    let cos_theta = hit_record.normal.dot(&to_light.unit_vector());
+    if cos_theta <= 0.0 {
+        return Srgb::new(0.0, 0.0, 0.0);
+    }
+    if !light_visible(scene, hit_record.point, to_light) {
+        return Srgb::new(0.0, 0.0, 0.0);
+    }
+    // The Lambertian 1/pi is taken as part of the light's brightness.
+    let weight = cos_theta * falloff(to_light.length()) * lights.len() as f32;
+    Srgb::new(
+        emitter.color.red * weight,
+        emitter.color.green * weight,
+        emitter.color.blue * weight,
+    )
+}
+
+/// Light arriving at a diffuse hit straight from the sun, or black without a sun or
+/// when the surface faces away from it or is in shadow.
+fn sun_light(hit_record: &HitRecord, scene: &Config) -> Srgb {
+    let Some(sun) = &scene.sun else {
+        return Srgb::new(0.0, 0.0, 0.0);
+    };
+    let to_sun = sun.direction.unit_vector();
+    let cos_theta = hit_record.normal.dot(&to_sun);
+    if cos_theta <= 0.0 {
+        return Srgb::new(0.0, 0.0, 0.0);
+    }
+    let shadow_ray = Ray::shadow(hit_record.point, to_sun);
+    let t_min = shadow_ray.self_intersection_t(0.001);
+    if World::new(&scene.objects).hit(&shadow_ray, t_min, f32::MAX).is_some() {
+        return Srgb::new(0.0, 0.0, 0.0);
+    }
+    let weight = cos_theta * sun.intensity;
+    Srgb::new(
+        sun.color.red * weight,
+        sun.color.green * weight,
+        sun.color.blue * weight,
+    )
 }
 
+/// Traces a camera ray. With `sample_lights`, diffuse hits gather light directly from the lights
+/// (next-event estimation), and the bounce leaving them no longer counts hitting a light so it is
+/// not added twice. `count_emission` is false for exactly those bounces.
 fn ray_color(
     ray: &Ray,
     scene: &Config,
-    lights: &Vec<Cube>,
-    max_depth: usize,
+    lights: &[Cube],
     depth: usize,
+    sample_lights: bool,
+    count_emission: bool,
 ) -> Srgb {
-    let mut rng = rand::thread_rng();
     if depth == 0 {
         return Srgb::new(0.0, 0.0, 0.0);
     }
-    let hit = hit_world(&scene.objects, ray, 0.001, f32::MAX);
+    let t_min = ray.self_intersection_t(0.001);
+    let world = World::new(&scene.objects);
+    let hit = world.hit(ray, t_min, f32::MAX);
     match hit {
+        // Stuck in a corner, where little light gets anyway.
+        Some(hit_record) if hit_record.t < STALL_FACTOR * t_min => Srgb::new(0.0, 0.0, 0.0),
+        Some(hit_record)
+            if !scene
+                .bounce_budget
+                .allows(hit_record.material, scene.max_depth.saturating_sub(depth) + 1) =>
+        {
+            Srgb::new(0.0, 0.0, 0.0)
+        }
         Some(hit_record) => {
             let scattered = hit_record.material.scatter(ray, &hit_record);
             match scattered {
                 Some((scattered_ray, albedo)) => {
-                    let mut light_red = 0.0;
-                    let mut light_green = 0.0;
-                    let mut light_blue = 0.0;
-                    let mut prob = 0.1;
-                    if let Material::Glass(_) = hit_record.material {
-                        prob = 0.05;
-                    }
-                    if !lights.is_empty()
-                        && rng.gen::<f32>() > (1.0 - lights.len() as f32 * prob)
-                        && depth > (max_depth - 2)
-                    {
-                        for light in lights {
-                            let light_ray = Ray::new(
-                                hit_record.point,
-                                light.origin - hit_record.point,
-                                0.0,
-                                0.0,
-                                0,
-                            );
-                            let target_color = ray_color(&light_ray, scene, lights, 2, 1);
-                            let factor = falloff(hit_record.t);
-                            light_red += albedo.red * target_color.red * factor;
-                            light_green += albedo.green * target_color.green * factor;
-                            light_blue += albedo.blue * target_color.blue * factor;
-                        }
-                        light_red /= lights.len() as f32;
-                        light_green /= lights.len() as f32;
-                        light_blue /= lights.len() as f32;
+                    let diffuse = matches!(
+                        hit_record.material,
+                        Material::Lambertian(_) | Material::Texture(_)
+                    );
+                    let next_event = sample_lights
+                        && diffuse
+                        && rand::thread_rng().gen::<f32>() < scene.light_sample_probability;
+                    let mut direct = if next_event {
+                        sample_light(&hit_record, scene, lights)
+                    } else {
+                        Srgb::new(0.0, 0.0, 0.0)
+                    };
+                    // Bounces cannot find the sun, so it is always sampled directly.
+                    if diffuse {
+                        direct += sun_light(&hit_record, scene);
                     }
                     match scattered_ray {
                         Some(sr) => {
-                            let target_color = ray_color(&sr, scene, lights, max_depth, depth - 1);
+                            let target_color =
+                                ray_color(&sr, scene, lights, depth - 1, sample_lights, !next_event);
+                            let emitted = hit_record.material.emitted();
                             Srgb::new(
-                                (light_red + albedo.red * target_color.red).clamp(0.0, 1.0),
-                                (light_green + albedo.green * target_color.green).clamp(0.0, 1.0),
-                                (light_blue + albedo.blue * target_color.blue).clamp(0.0, 1.0),
+                                (emitted.red + albedo.red * (direct.red + target_color.red))
+                                    .clamp(0.0, 1.0),
+                                (emitted.green + albedo.green * (direct.green + target_color.green))
+                                    .clamp(0.0, 1.0),
+                                (emitted.blue + albedo.blue * (direct.blue + target_color.blue))
+                                    .clamp(0.0, 1.0),
                             )
                         }
-                        None => albedo,
+                        None if count_emission => albedo,
+                        None => Srgb::new(0.0, 0.0, 0.0),
                     }
                 }
                 None => {
@@ -153,18 +221,20 @@ fn f_max(a: f32, b: f32) -> f32 {
     })
 }
 
-fn render_line(pixels: &mut [u8], scene: &Config, lights: &Vec<Cube>, y: usize) {
-    let mut rng = rand::thread_rng();
-
+/// Renders pixels `xs` of line `y` into `pixels`, which holds just those pixels.
+fn render_line(pixels: &mut [u8], scene: &Config, lights: &[Cube], y: usize, xs: Range<usize>) {
     let bounds = (scene.width, scene.height);
 
-    for x in 0..bounds.0 {
+    for x in xs.clone() {
+        // Seeded by position, so a pixel gets the same camera rays whichever region it is
+        // rendered as part of.
+        let mut rng = StdRng::seed_from_u64((y * bounds.0 + x) as u64);
         let mut pixel_colors: Vec<f32> = vec![0.0; 3];
         for _s in 0..scene.samples_per_pixel {
             let u = (x as f32 + rng.gen::<f32>()) / (bounds.0 as f32 - 1.0);
             let v = (bounds.1 as f32 - (y as f32 + rng.gen::<f32>())) / (bounds.1 as f32 - 1.0);
             let r = scene.camera.get_ray(u, v);
-            let c = ray_color(&r, scene, lights, scene.max_depth, scene.max_depth);
+            let c = ray_color(&r, scene, lights, scene.max_depth, true, true);
             /*
             pixel_colors[0] += c.red;
             pixel_colors[1] += c.green;
@@ -182,40 +252,340 @@ fn render_line(pixels: &mut [u8], scene: &Config, lights: &Vec<Cube>, y: usize)
             (scale * pixel_colors[2]).sqrt(),
         );
         */
-        let color = Srgb::new(
-            pixel_colors[0].sqrt(),
-            pixel_colors[1].sqrt(),
-            pixel_colors[2].sqrt(),
-        );
-        let pixel: [u8; 3] = color.into_format().into();
-        pixels[x * 3] = pixel[0];
-        pixels[x * 3 + 1] = pixel[1];
-        pixels[x * 3 + 2] = pixel[2];
+        let pixel = linear_to_srgb8(LinSrgb::new(
+            pixel_colors[0],
+            pixel_colors[1],
+            pixel_colors[2],
+        ));
+        let i = (x - xs.start) * 3;
+        pixels[i] = pixel[0];
+        pixels[i + 1] = pixel[1];
+        pixels[i + 2] = pixel[2];
     }
 }
 
-fn find_lights(world: &[Cube]) -> Vec<Cube> {
-    world
-        .iter()
-        .filter(|s| matches!(s.material, Material::Light(_)))
+fn find_lights(scene: &Config) -> Vec<Cube> {
+    scene
+        .objects_by_material(|m| matches!(m, Material::Light(_)))
+        .into_iter()
         .cloned()
         .collect()
 }
 
-pub fn render(filename: &str, scene: &Config) {
+/// Number of camera rays `render` traces for this scene, not counting bounces.
+pub fn render_ray_count(scene: &Config) -> usize {
+    scene.width * scene.height * scene.samples_per_pixel as usize
+}
+
+/// Renders the pixels with x in `xs` of the rows from `y0` on into `pixels`, which holds
+/// as many whole rows as fit.
+fn render_rows(pixels: &mut [u8], scene: &Config, y0: usize, xs: Range<usize>) {
+    let row_width = xs.len();
+    if row_width == 0 {
+        return;
+    }
+    let bands: Vec<(usize, &mut [u8])> = pixels.chunks_mut(row_width * 3).enumerate().collect();
+
+    let lights = find_lights(scene);
+
+    bands.into_par_iter().for_each(|(i, band)| {
+        render_line(band, scene, &lights, y0 + i, xs.clone());
+    });
+}
+
+/// Renders the pixels with x in `x0..x1` and y in `y0..y1`, as rgb rows from the
+/// top. Pixels come out the same as in a render of the whole image.
+pub fn render_region(
+    scene: &Config,
+    x0: usize,
+    y0: usize,
+    x1: usize,
+    y1: usize,
+) -> Result<Vec<u8>, RaytracerError> {
+    if x0 > x1 || y0 > y1 || x1 > scene.width || y1 > scene.height {
+        return Err(RaytracerError::Geometry(format!(
+            "Region ({}, {}) to ({}, {}) is not within the {}x{} image",
+            x0, y0, x1, y1, scene.width, scene.height
+        )));
+    }
+    let mut pixels = vec![0; (x1 - x0) * (y1 - y0) * 3];
+    render_rows(&mut pixels, scene, y0, x0..x1);
+    Ok(pixels)
+}
+
+/// Renders the whole image into `pixels`, as rgb rows from the top, so a caller rendering
+/// many frames can reuse one buffer. `pixels` has to hold exactly the image.
+pub fn render_into(scene: &Config, pixels: &mut [u8]) -> Result<(), RaytracerError> {
+    let size = scene.width * scene.height * 3;
+    if pixels.len() != size {
+        return Err(RaytracerError::Geometry(format!(
+            "Buffer of {} bytes does not fit the {}x{} image, which takes {}",
+            pixels.len(),
+            scene.width,
+            scene.height,
+            size
+        )));
+    }
+    render_rows(pixels, scene, 0, 0..scene.width);
+    Ok(())
+}
+
+pub fn render(filename: &str, scene: &Config) -> Result<TimingReport, RaytracerError> {
     let image_width = scene.width;
     let image_height = scene.height;
 
+    let start = Instant::now();
     let mut pixels = vec![0; image_width * image_height * 3];
-    let bands: Vec<(usize, &mut [u8])> = pixels.chunks_mut(image_width * 3).enumerate().collect();
+    render_into(scene, &mut pixels)?;
+    let elapsed = start.elapsed();
+    log::info!("Frame time: {}ms", elapsed.as_millis());
 
-    let lights = find_lights(&scene.objects);
+    write_image(filename, &pixels, (image_width, image_height), scene)?;
+    Ok(TimingReport::new(elapsed, Vec::new()))
+}
 
-    let start = Instant::now();
-    bands.into_par_iter().for_each(|(i, band)| {
-        render_line(band, scene, &lights, i);
+#[cfg(test)]
+use crate::frequency::Frequency;
+
+#[cfg(test)]
+fn color_variance(samples: &[Srgb]) -> (f32, f32) {
+    let brightness: Vec<f32> = samples.iter().map(|c| c.red + c.green + c.blue).collect();
+    let mean = brightness.iter().sum::<f32>() / brightness.len() as f32;
+    let variance =
+        brightness.iter().map(|b| (b - mean).powi(2)).sum::<f32>() / brightness.len() as f32;
+    (mean, variance)
+}
+
+#[test]
+fn test_next_event_estimation_variance() {
+    use crate::materials::{Lambertian, Light};
+    let ground = Cube::new(
+        Point3D::new(0.0, -1.0, 0.0),
+        10.0,
+        1.0,
+        10.0,
+        Material::Lambertian(Lambertian::new(Srgb::new(0.5, 0.5, 0.5))),
+        0,
+    );
+    let lamp = Cube::new(
+        Point3D::new(2.0, 3.0, 0.0),
+        0.5,
+        0.5,
+        0.5,
+        Material::Light(Light::new(Srgb::new(1.0, 1.0, 1.0), 0.0, 1, Frequency::from_mhz(2400.0))),
+        1,
+    );
+    let mut scene = crate::config::test_config(10, 10, vec![ground, lamp]);
+    scene.sky = None;
+    let lights = find_lights(&scene);
+    let ray = Ray::new(
+        Point3D::new(0.0, 5.0, 0.0),
+        Point3D::new(0.0, -1.0, 0.0),
+        0.0,
+        0.0,
+        Frequency::from_hz(0),
+    );
+    let path_only: Vec<Srgb> = (0..2000)
+        .map(|_| ray_color(&ray, &scene, &lights, 3, false, true))
+        .collect();
+    let next_event: Vec<Srgb> = (0..2000)
+        .map(|_| ray_color(&ray, &scene, &lights, 3, true, true))
+        .collect();
+    let (path_mean, path_variance) = color_variance(&path_only);
+    let (next_event_mean, next_event_variance) = color_variance(&next_event);
+    assert!(path_mean > 0.0);
+    assert!(next_event_mean > 0.0);
+    assert!(
+        next_event_variance < path_variance / 10.0,
+        "{} vs {}",
+        next_event_variance,
+        path_variance
+    );
+}
+
+#[test]
+fn test_render_region() {
+    use crate::materials::{Light, Metal};
+    // Materials that scatter without randomness, so only the camera rays could differ.
+    let ground = Cube::new(
+        Point3D::new(5.0, -1.0, 5.0),
+        10.0,
+        1.0,
+        10.0,
+        Material::Metal(Metal::new(Srgb::new(0.8, 0.6, 0.4), 0.0, 0.0)),
+        0,
+    );
+    let lamp = Cube::new(
+        Point3D::new(5.0, 1.0, 5.0),
+        1.0,
+        1.0,
+        1.0,
+        Material::Light(Light::new(Srgb::new(1.0, 1.0, 1.0), 0.0, 1, Frequency::from_mhz(2400.0))),
+        1,
+    );
+    let scene = crate::config::test_config(12, 10, vec![ground, lamp]);
+    let full = render_region(&scene, 0, 0, 12, 10).unwrap();
+    let region = render_region(&scene, 3, 2, 9, 7).unwrap();
+    assert_eq!(region.len(), 6 * 5 * 3);
+    for y in 0..5 {
+        let start = ((y + 2) * 12 + 3) * 3;
+        assert_eq!(region[y * 18..(y + 1) * 18], full[start..start + 18], "row {}", y);
+    }
+    assert!(render_region(&scene, 3, 2, 13, 7).is_err());
+    assert!(render_region(&scene, 5, 2, 4, 7).is_err());
+}
+
+#[test]
+fn test_render_into() {
+    use crate::materials::{Light, Metal};
+    let ground = Cube::new(
+        Point3D::new(5.0, -1.0, 5.0),
+        10.0,
+        1.0,
+        10.0,
+        Material::Metal(Metal::new(Srgb::new(0.8, 0.6, 0.4), 0.0, 0.0)),
+        0,
+    );
+    let lamp = Cube::new(
+        Point3D::new(5.0, 1.0, 5.0),
+        1.0,
+        1.0,
+        1.0,
+        Material::Light(Light::new(Srgb::new(1.0, 1.0, 1.0), 0.0, 1, Frequency::from_mhz(2400.0))),
+        1,
+    );
+    let scene = crate::config::test_config(12, 10, vec![ground, lamp]);
+    let path = std::env::temp_dir().join(format!("render_into_{}.png", std::process::id()));
+    render(path.to_str().unwrap(), &scene).unwrap();
+    let rendered = image::open(&path).unwrap().to_rgb8().into_raw();
+    std::fs::remove_file(&path).unwrap();
+    let mut pixels = vec![0; 12 * 10 * 3];
+    render_into(&scene, &mut pixels).unwrap();
+    assert_eq!(pixels, rendered);
+    assert!(matches!(
+        render_into(&scene, &mut vec![0; 12 * 9 * 3]),
+        Err(RaytracerError::Geometry(_))
+    ));
+}
+
+#[test]
+fn test_samples_per_pixel() {
+    use crate::materials::{Light, Metal};
+    let ground = Cube::new(
+        Point3D::new(5.0, -1.0, 5.0),
+        10.0,
+        1.0,
+        10.0,
+        Material::Metal(Metal::new(Srgb::new(0.8, 0.6, 0.4), 0.0, 0.0)),
+        0,
+    );
+    let lamp = Cube::new(
+        Point3D::new(5.0, 1.0, 5.0),
+        1.0,
+        1.0,
+        1.0,
+        Material::Light(Light::new(Srgb::new(1.0, 1.0, 1.0), 0.0, 1, Frequency::from_mhz(2400.0))),
+        1,
+    );
+    let mut scene = crate::config::test_config(12, 10, vec![ground, lamp]);
+    scene.samples_per_pixel = 1;
+    let single = render_region(&scene, 0, 0, 12, 10).unwrap();
+    // Each pixel starts with the same camera ray and keeps the brightest sample, so more
+    // samples can only brighten it, and do where a pixel straddles the edge of the lamp.
+    scene.samples_per_pixel = 16;
+    let many = render_region(&scene, 0, 0, 12, 10).unwrap();
+    assert!(single.iter().zip(many.iter()).all(|(s, m)| s <= m));
+    assert_ne!(single, many);
+}
+
+#[test]
+fn test_sample_light() {
+    use crate::materials::{Lambertian, Light};
+    let ground = Material::Lambertian(Lambertian::new(Srgb::new(0.5, 0.5, 0.5)));
+    let lamp = Cube::new(
+        Point3D::new(2.0, 3.0, 0.0),
+        0.5,
+        0.5,
+        0.5,
+        Material::Light(Light::new(Srgb::new(1.0, 1.0, 1.0), 0.0, 1, Frequency::from_mhz(2400.0))),
+        1,
+    );
+    let mut scene = crate::config::test_config(10, 10, vec![lamp]);
+    let lights = find_lights(&scene);
+    let hit_record = HitRecord {
+        t: 1.0,
+        point: Point3D::new(0.0, 0.0, 0.0),
+        normal: Point3D::new(0.0, 1.0, 0.0),
+        front_face: true,
+        thickness: 0.0,
+        material: &ground,
+        u: 0.0,
+        v: 0.0,
+    };
+    let direct = sample_light(&hit_record, &scene, &lights);
+    assert!(direct.red > 0.0 && direct.green > 0.0 && direct.blue > 0.0);
+    // A wall in between casts a shadow.
+    let wall = Cube::new(Point3D::new(1.0, 1.5, 0.0), 0.1, 3.0, 3.0, ground.clone(), 2);
+    scene.objects.push(wall);
+    assert_eq!(sample_light(&hit_record, &scene, &lights), Srgb::new(0.0, 0.0, 0.0));
+}
+
+#[test]
+fn test_sun_light() {
+    use crate::config::Sun;
+    use crate::materials::Lambertian;
+    let ground = Material::Lambertian(Lambertian::new(Srgb::new(0.5, 0.5, 0.5)));
+    let mut scene = crate::config::test_config(10, 10, vec![]);
+    let hit_record = HitRecord {
+        t: 1.0,
+        point: Point3D::new(0.0, 0.0, 0.0),
+        normal: Point3D::new(0.0, 1.0, 0.0),
+        front_face: true,
+        thickness: 0.0,
+        material: &ground,
+        u: 0.0,
+        v: 0.0,
+    };
+    assert_eq!(sun_light(&hit_record, &scene), Srgb::new(0.0, 0.0, 0.0));
+    scene.sun = Some(Sun {
+        direction: Point3D::new(1.0, 1.0, 0.0),
+        color: Srgb::new(1.0, 0.9, 0.8),
+        intensity: 2.0,
     });
-    println!("Frame time: {}ms", start.elapsed().as_millis());
+    let lit = sun_light(&hit_record, &scene);
+    assert_approx_eq::assert_approx_eq!(lit.red, 2.0 * 0.5f32.sqrt(), 1e-5);
+    // A roof between the surface and the sun casts a shadow.
+    let roof = Cube::new(Point3D::new(3.0, 3.0, 0.0), 2.0, 0.1, 2.0, ground.clone(), 0);
+    scene.objects.push(roof);
+    let shadowed = sun_light(&hit_record, &scene);
+    assert_eq!(shadowed, Srgb::new(0.0, 0.0, 0.0));
+    assert!(lit.red + lit.green + lit.blue > shadowed.red + shadowed.green + shadowed.blue);
+}
 
-    write_image(filename, &pixels, (image_width, image_height)).expect("error writing image");
+#[test]
+fn test_lambertian_emission() {
+    use crate::materials::Lambertian;
+    // Looking up at a ceiling, with no lights and no sky: only its own glow shows.
+    let mut ceiling = Lambertian::new(Srgb::new(0.5, 0.5, 0.5));
+    let slab = Cube::new(
+        Point3D::new(0.0, 5.0, 0.0),
+        20.0,
+        0.1,
+        20.0,
+        Material::Lambertian(ceiling),
+        0,
+    );
+    let mut scene = crate::config::test_config(10, 10, vec![slab]);
+    let ray = Ray::new(
+        Point3D::new(0.0, 2.0, 0.0),
+        Point3D::new(0.0, 1.0, 0.0),
+        0.0,
+        0.0,
+        Frequency::from_hz(0),
+    );
+    assert_eq!(ray_color(&ray, &scene, &[], 10, false, true), Srgb::new(0.0, 0.0, 0.0));
+    ceiling.emission = Srgb::new(0.1, 0.1, 0.1);
+    scene.objects[0].material = Material::Lambertian(ceiling);
+    let glow = ray_color(&ray, &scene, &[], 10, false, true);
+    assert!(glow.red >= 0.1 && glow.green >= 0.1 && glow.blue >= 0.1);
 }
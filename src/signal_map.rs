@@ -2,58 +2,80 @@ use byteorder::LittleEndian;
 use byteorder::WriteBytesExt; // This trait adds methods to writeable types
 use image::codecs::png::PngEncoder;
 use image::ImageEncoder;
-use palette::Srgb;
+use palette::{FromColor, Hsv, LinSrgb, Srgb};
+use rand::Rng;
+use rand_distr::StandardNormal;
 use rayon::prelude::*;
+use serde::Serialize;
+use std::collections::VecDeque;
 use std::fs::File;
-use std::time::Instant;
+use std::io::BufRead;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-use crate::config::Config;
+use crate::config::{resize_image, Aggregation, Config, GeoPosition, Subsampling};
 use crate::cube::Cube;
-use crate::materials::Material;
+use crate::error::RaytracerError;
+use crate::frequency::Frequency;
 use crate::materials::Scatterable;
+use crate::materials::{linear_to_srgb8, Aperture, BeamConfig, Material};
 use crate::point3d::Point3D;
 use crate::ray::HitRecord;
 use crate::ray::Hittable;
 use crate::ray::Ray;
+use crate::ray::STALL_FACTOR;
+use crate::timing::{StationTiming, TimingReport};
+use crate::world::World;
 
-fn find_lights(world: &[Cube]) -> Vec<Cube> {
-    world
-        .iter()
-        .filter(|s| matches!(s.material, Material::Light(_)))
+fn find_lights(scene: &Config) -> Vec<Cube> {
+    scene
+        .objects_by_material(|m| matches!(m, Material::Light(_)))
+        .into_iter()
         .cloned()
         .collect()
 }
 
-fn write_image(filename: &str, pixels: &[u8], bounds: (usize, usize)) -> Result<(), &'static str> {
-    let output = File::create(filename).map_err(|_| "Could not write file")?;
+/// The stations `options.stations` selects.
+fn selected_stations(scene: &Config, options: &SigmapOptions) -> Vec<Cube> {
+    let mut stations = find_lights(scene);
+    if !options.stations.is_empty() {
+        stations.retain(|s| options.stations.contains(&s.id));
+    }
+    stations
+}
+
+/// Writes a `bounds` sized image from a buffer laid out by `set_pixel`, which
+/// leaves the first row unused, resized by the scene's `image_scale`.
+fn write_image(
+    filename: &str,
+    pixels: &[u8],
+    bounds: (usize, usize),
+    scene: &Config,
+) -> std::io::Result<()> {
+    let (pixels, bounds) = resize_image(
+        &pixels[bounds.0 * 3..bounds.0 * (bounds.1 + 1) * 3],
+        bounds,
+        scene.image_scale,
+        scene.image_filter,
+    );
+    let output = create_output(filename.to_string())?;
     let encoder = PngEncoder::new(output);
     encoder
         .write_image(
-            pixels,
+            &pixels,
             bounds.0 as u32,
             bounds.1 as u32,
             image::ExtendedColorType::Rgb8,
         )
-        .map_err(|_| "Could not write image")?;
+        .map_err(std::io::Error::other)?;
     Ok(())
 }
 
-fn hit_world<'material>(
-    world: &'material Vec<&Cube>,
-    r: &Ray,
-    t_min: f32,
-    t_max: f32,
-) -> Option<HitRecord<'material>> {
-    let mut closest_so_far = t_max;
-    let mut hit_record = None;
-    for obj in world {
-        if let Some(hit) = obj.hit(r, t_min, closest_so_far) {
-            //println!("Hit at {}",hit.point.y());
-            closest_so_far = hit.t;
-            hit_record = Some(hit);
-        }
-    }
-    hit_record
+/// Creates an output file, naming it in the error if that fails.
+fn create_output(path: String) -> std::io::Result<File> {
+    File::create(&path)
+        .map_err(|e| std::io::Error::new(e.kind(), format!("Unable to create {}: {}", path, e)))
 }
 
 fn coord_to_angle(x: f32, y: f32) -> f32 {
@@ -66,317 +88,4752 @@ fn coord_to_angle(x: f32, y: f32) -> f32 {
 
 const BEAM_FALLOFF: f32 = 50.0;
 const SUBSAMPLING: usize = 2;
+
+/// How a station spreads its strength over the directions it sends in.
+enum BeamPattern<'a> {
+    /// This many sectors evenly spread around the station.
+    Sectors(i64),
+    /// Individually steered beams, each launching its own rays.
+    Steered(&'a [BeamConfig]),
+}
+
+impl BeamPattern<'_> {
+    /// Launch strength of each ray sent along `direction`.
+    fn strengths(&self, direction: Point3D, base_strength: f32) -> Vec<f32> {
+        match self {
+            BeamPattern::Sectors(beams) => {
+                let flat_factor = (direction.x().powi(2) + direction.z().powi(2)).sqrt();
+                // Straight up or down has no azimuth, and lies in every sector.
+                if flat_factor == 0.0 {
+                    return vec![base_strength];
+                }
+                let angle =
+                    coord_to_angle(direction.x() / flat_factor, direction.z() / flat_factor);
+                let beam_centricity = ((angle * (*beams as f32)) % (2.0 * std::f32::consts::PI))
+                    .abs()
+                    / std::f32::consts::PI;
+                let correct_centricity = if beam_centricity > 1.0 {
+                    beam_centricity - 2.0
+                } else {
+                    beam_centricity
+                };
+                vec![
+                    (base_strength - BEAM_FALLOFF)
+                        + BEAM_FALLOFF * (1.0 - correct_centricity.powi(2)),
+                ]
+            }
+            BeamPattern::Steered(beams) => beams
+                .iter()
+                .map(|beam| {
+                    let cos = direction.dot(&beam.boresight()).clamp(-1.0, 1.0);
+                    let centricity = cos.acos() / std::f32::consts::PI;
+                    base_strength + beam.gain - BEAM_FALLOFF * centricity.powi(2)
+                })
+                .collect(),
+        }
+    }
+
+    /// Rays launched along each direction.
+    fn len(&self) -> usize {
+        match self {
+            BeamPattern::Sectors(_) => 1,
+            BeamPattern::Steered(beams) => beams.len(),
+        }
+    }
+}
+
+/// Ground points the rays from `origin` to grid point `(x, y)` aim at: one per
+/// subsample, each repeated `rays_per_target` times. Repeats after the first aim
+/// at a random spot within their subsample, so they cross the beam pattern at a
+/// slightly different phase.
+fn subsample_targets(
+    origin: Point3D,
+    (x, y): (usize, usize),
+    pixel_size: (f32, f32),
+    subsampling: Subsampling,
+    rays_per_target: usize,
+) -> Vec<Point3D> {
+    let mut rng = rand::thread_rng();
+    let mut res: Vec<Point3D> = vec![];
+    match subsampling {
+        Subsampling::Grid => {
+            let interval = 1.0 / (SUBSAMPLING as f32);
+            for i in 0..SUBSAMPLING {
+                for j in 0..SUBSAMPLING {
+                    for repeat in 0..rays_per_target.max(1) {
+                        let (jitter_x, jitter_y) = if repeat == 0 {
+                            (0.0, 0.0)
+                        } else {
+                            (rng.gen::<f32>() * interval, rng.gen::<f32>() * interval)
+                        };
+                        res.push(Point3D::new(
+                            (x as f32 + (i as f32) * interval + jitter_x - 0.5) * pixel_size.0,
+                            0.0,
+                            (y as f32 + (j as f32) * interval + jitter_y - 0.5) * pixel_size.1,
+                        ));
+                    }
+                }
+            }
+        }
+        Subsampling::Azimuthal => {
+            // The same number of subsamples in a row through the center of the grid
+            // point, across the direction from `origin`, so the beam pattern is
+            // sampled at twice the angular resolution.
+            let center = Point3D::new(x as f32 * pixel_size.0, 0.0, y as f32 * pixel_size.1);
+            let (dx, dz) = (center.x() - origin.x(), center.z() - origin.z());
+            let across = if dx == 0.0 && dz == 0.0 {
+                Point3D::new(1.0, 0.0, 0.0)
+            } else {
+                Point3D::new(-dz, 0.0, dx).unit_vector()
+            };
+            let samples = SUBSAMPLING.pow(2);
+            let width = pixel_size.0.min(pixel_size.1);
+            for k in 0..samples {
+                for repeat in 0..rays_per_target.max(1) {
+                    let jitter = if repeat == 0 { 0.5 } else { rng.gen::<f32>() };
+                    let offset = (k as f32 + jitter) / samples as f32 - 0.5;
+                    res.push(center + across * (offset * width));
+                }
+            }
+        }
+    }
+    res
+}
+
+/// Rays from an antenna at `origin` towards each of `targets`, one per beam in
+/// `pattern`. With an `aperture`, each ray leaves from its own point on it.
 fn rays_to(
     origin: Point3D,
-    x: usize,
-    y: usize,
-    beams: i64,
+    aperture: Aperture,
+    targets: &[Point3D],
+    pattern: &BeamPattern,
     base_strength: f32,
-    frequency: i64,
+    frequency: Frequency,
 ) -> Vec<Ray> {
     let mut res: Vec<Ray> = vec![];
-    let interval = 1.0 / (SUBSAMPLING as f32);
-    for i in 0..SUBSAMPLING {
-        for j in 0..SUBSAMPLING {
-            let direction = (Point3D::new(
-                x as f32 + (i as f32) * interval - 0.5,
-                0.0,
-                y as f32 + (j as f32) * interval - 0.5,
-            ) - origin)
-                .unit_vector();
-            let flat_factor = (direction.x().powi(2) + direction.z().powi(2)).sqrt();
-            let angle = coord_to_angle(direction.x() / flat_factor, direction.z() / flat_factor);
-            let beam_centricity = ((angle * (beams as f32)) % (2.0 * std::f32::consts::PI)).abs()
-                / std::f32::consts::PI;
-            let correct_centricity = if beam_centricity > 1.0 {
-                beam_centricity - 2.0
-            } else {
-                beam_centricity
-            };
-            let strength =
-                (base_strength - BEAM_FALLOFF) + BEAM_FALLOFF * (1.0 - correct_centricity.powi(2));
-            res.push(Ray::new(origin, direction, strength, 0.0, frequency));
+    for target in targets {
+        let direction = (*target - origin).unit_vector();
+        for strength in pattern.strengths(direction, base_strength) {
+            let ray_origin = aperture.ray_origin(origin, direction);
+            let direction = (*target - ray_origin).unit_vector();
+            res.push(Ray::new(ray_origin, direction, strength, 0.0, frequency));
         }
     }
     res
 }
 
-//static DOWN: Point3D = Point3D::new(0.0, -1.0, 0.0);
-fn generate_signal(
-    station: &Cube,
-    scene: &Config,
-    do_times: bool,
-    do_angles: bool,
-) -> (Vec<f32>, Vec<f32>, Vec<f32>) {
-    let mut signals = vec![-140.0; (scene.width + 1) * (scene.height + 1)];
-    let mut times = if do_times {
-        vec![0.0; (scene.width + 1) * (scene.height + 1)]
-    } else {
-        vec![]
-    };
-    let mut angles = if do_times {
-        vec![0.0; (scene.width + 1) * (scene.height + 1)]
-    } else {
-        vec![]
-    };
-    let objects: Vec<&Cube> = scene
-        .objects
-        .iter()
-        .filter(|x| !matches!(x.material, Material::Light(_)))
-        .collect();
-    let (beams, base_strength, frequency) = match station.material {
-        Material::Light(l) => Ok((l.beams, l.strength, l.frequency)),
-        _ => Err(""),
+/// Value recorded for pixels that no ray reached.
+const NO_SIGNAL: f32 = -140.0;
+
+fn is_no_signal(signal: f32) -> bool {
+    signal <= NO_SIGNAL
+}
+
+// Per-pixel classification written to the `.reason` file.
+const REASON_NEVER_HIT: u8 = 0;
+const REASON_BELOW_FLOOR: u8 = 1;
+const REASON_SERVED: u8 = 2;
+
+/// Selects the optional outputs `generate_sigmap` writes next to the `.data` file.
+#[derive(Debug, Clone, Default)]
+pub struct SigmapOptions {
+    pub times: bool,
+    // Arrival azimuths, see `arrival_azimuth`, in radians unless `angle_degrees`.
+    pub angles: bool,
+    pub png: bool,
+    pub diagnose: bool,
+    pub sectors: bool,
+    pub angle_png: bool,
+    pub time_png: bool,
+    // Write to `<base>/<id>/<frequency>.*` rather than `<base>_<id>_<frequency>.*`.
+    pub subdirectories: bool,
+    // Also write a map combining all stations.
+    pub combine: Option<Combine>,
+    // Refuse to overwrite existing output files.
+    pub no_clobber: bool,
+    // Leave points no ray reached at `NO_SIGNAL` instead of filling them in
+    // from their neighbours.
+    pub no_homogenize: bool,
+    // With `sweep_frequencies`, also write which frequency serves each point best.
+    pub best_band: bool,
+    // Write the `.angles` files in degrees instead of radians.
+    pub angle_degrees: bool,
+    // Write the `.data` files in mW instead of dBm, with 0.0 for no signal.
+    pub linear: bool,
+    // Only trace the stations with these ids, or all of them if empty.
+    pub stations: Vec<i64>,
+    // Write how many rays reached each point to `.count` files.
+    pub counts: bool,
+    // Write where every ray was recorded, with its strength, to this PLY file.
+    pub point_cloud: Option<String>,
+    // Also write each map as square tiles of this many points, see `tile_grid`.
+    pub tile_size: Option<usize>,
+    // Write the RMS delay spread at each point to `.ds` files, see `delay_spread`.
+    pub delay_spread: bool,
+}
+
+/// How `generate_sigmap` merges the stations into a combined map.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Combine {
+    /// Strongest signal over all stations, written to `<base>_combined.*`.
+    Max,
+    /// Strongest signal over the stations on each frequency, written to
+    /// `<base>_combined_<frequency>.*`.
+    MaxPerFrequency,
+}
+
+impl SigmapOptions {
+    fn records_angles(&self) -> bool {
+        self.angles || self.angle_png
     }
-    .expect("Station does not have light material");
-    //for i in 0..scene.nr_probes {
-    for target_x in 0..scene.width {
-        for target_y in 0..scene.height {
-            assert!(base_strength > -130.0);
-            for mut ray in rays_to(
-                station.origin,
-                target_x,
-                target_y,
-                beams,
-                base_strength,
-                frequency,
-            ) {
-                //println!("Launching ray at {:?}",direction);
-                for _i in 0..scene.max_depth {
-                    if let Some(hit_record) = hit_world(&objects, &ray, 0.00001, f32::MAX) {
-                        if hit_record.point.y() < 0.001
-                            && hit_record.point.x() < scene.width as f32
-                            && hit_record.point.z() < scene.height as f32
-                        {
-                            //We hit the ground, record the signal
-                            let x = hit_record.point.x() as usize;
-                            let y = hit_record.point.z() as usize;
-                            if ray.strength < base_strength {
-                                //println!( "hit the ground! {:?} = ({},{}), {} at {}", hit_record.point, x, y, ray.strength_at(hit_record.t), hit_record.t);
-                            }
-                            let coord = x + y * scene.width;
-                            if coord > signals.len() {
-                                println!(
-                                    "Got out of bounds coordinate {} {} ({} {})!",
-                                    x, y, scene.width, scene.height
-                                );
-                            } else if signals[coord] < ray.strength_at(hit_record.t) {
-                                signals[coord] = ray.strength_at(hit_record.t);
-                                if do_times {
-                                    times[coord] = ray.ray_time + hit_record.t;
-                                }
-                                if do_angles {
-                                    angles[coord] = ((hit_record.point.x() - x as f32)
-                                        / (hit_record.point.z() - y as f32))
-                                        .atan();
-                                }
-                            }
-                        }
-                        match hit_record.material.scatter(&ray, &hit_record) {
-                            Some((possibly_new_ray, _)) => match possibly_new_ray {
-                                Some(new_ray) => {
-                                    if hit_record.point.y() > 0.01 {
-                                        //println!("Scattering at {} {}!",ray.origin.distance(&new_ray.origin),hit_record.t);
-                                    }
-                                    ray = new_ray;
-                                }
-                                None => {
-                                    break;
-                                }
-                            },
-                            None => {
-                                //println!("Ray did not scatter at {} {} {}",hit_record.point.x(),hit_record.point.y(),hit_record.point.z());
-                                break;
-                            }
-                        }
-                    }
-                }
+
+    fn records_times(&self) -> bool {
+        self.times || self.time_png
+    }
+}
+
+/// The signal a station produces on the traced grid, see `generate_signal`.
+pub struct SignalResult {
+    signals: Vec<f32>,
+    times: Vec<f32>,
+    angles: Vec<f32>,
+    // Arrival elevations, see `arrival_elevation`, kept along with the angles.
+    elevations: Vec<f32>,
+    reasons: Vec<u8>,
+    // Number of rays reaching each point.
+    counts: Vec<u32>,
+    // Every recorded position with its strength, when writing a point cloud.
+    point_cloud: Option<Vec<(Point3D, f32)>>,
+    // Power-weighted excess delays of the rays reaching each point, see `excess_delay`.
+    delays: Vec<DelayMoments>,
+    // Where the rays were launched, which the excess delays count from.
+    station: Point3D,
+    // Summed power in mW of the rays reaching each point, for `Aggregation::SumLinear`.
+    powers: Vec<f32>,
+}
+
+impl SignalResult {
+    fn new(
+        grid_size: usize,
+        options: &SigmapOptions,
+        aggregation: Aggregation,
+        station: Point3D,
+    ) -> SignalResult {
+        SignalResult {
+            signals: vec![NO_SIGNAL; grid_size],
+            times: if options.records_times() {
+                vec![0.0; grid_size]
+            } else {
+                vec![]
+            },
+            angles: if options.records_angles() {
+                vec![0.0; grid_size]
+            } else {
+                vec![]
+            },
+            elevations: if options.records_angles() {
+                vec![0.0; grid_size]
+            } else {
+                vec![]
+            },
+            reasons: if options.diagnose {
+                vec![REASON_NEVER_HIT; grid_size]
+            } else {
+                vec![]
+            },
+            counts: if options.counts {
+                vec![0; grid_size]
+            } else {
+                vec![]
+            },
+            point_cloud: options.point_cloud.as_ref().map(|_| vec![]),
+            delays: if options.delay_spread {
+                vec![DelayMoments::default(); grid_size]
+            } else {
+                vec![]
+            },
+            station,
+            powers: match aggregation {
+                Aggregation::Max => vec![],
+                Aggregation::SumLinear => vec![0.0; grid_size],
+            },
+        }
+    }
+
+    /// Records `ray` reaching grid point `coord`, `t` along it.
+    fn record(&mut self, coord: usize, ray: &Ray, t: f32, noise_floor: f32) {
+        let strength = ray.strength_at(t);
+        if !self.reasons.is_empty() {
+            let reason = if strength < noise_floor {
+                REASON_BELOW_FLOOR
+            } else {
+                REASON_SERVED
+            };
+            self.reasons[coord] = self.reasons[coord].max(reason);
+        }
+        if !self.counts.is_empty() {
+            self.counts[coord] += 1;
+        }
+        if let Some(points) = &mut self.point_cloud {
+            points.push((ray.at(t), strength));
+        }
+        if !self.delays.is_empty() {
+            self.delays[coord].add(Tap {
+                delay: excess_delay(self.station, ray, t),
+                strength,
+            });
+        }
+        if !self.powers.is_empty() {
+            self.powers[coord] += dbm_to_mw(strength);
+        }
+        // With summed powers, the times and angles still come from the strongest ray.
+        if self.signals[coord] < strength {
+            self.signals[coord] = strength;
+            if !self.times.is_empty() {
+                self.times[coord] = ray.ray_time + t;
+            }
+            if !self.angles.is_empty() {
+                self.angles[coord] = arrival_azimuth(ray.direction);
+                self.elevations[coord] = arrival_elevation(ray.direction);
+            }
+        }
+    }
+
+    /// Replaces the strongest signals with the summed powers, if they were kept. Each
+    /// point was aimed at by `rays_per_point` rays along nearly the same path, which
+    /// share the power of that path rather than each carrying all of it.
+    fn sum_powers(&mut self, noise_floor: f32, rays_per_point: usize) {
+        for (coord, power) in self.powers.iter().enumerate() {
+            if *power <= 0.0 {
+                continue;
+            }
+            self.signals[coord] = mw_to_dbm(*power / rays_per_point as f32);
+            // Rays below the noise floor can add up to a usable signal.
+            if !self.reasons.is_empty() && self.signals[coord] >= noise_floor {
+                self.reasons[coord] = REASON_SERVED;
             }
         }
     }
-    (signals, times, angles)
 }
 
-fn signal_to_color(signal: f32) -> (u8, u8, u8) {
-    let value = (((signal + 100.0) / 100.0) * 3.0).clamp(0.0, 1.0);
-    let color = Srgb::new(
-        (value - 2.0).clamp(0.0, 1.0),
-        (value - 1.0).clamp(0.0, 1.0),
-        value.clamp(0.0, 1.0),
-    );
-    color.into_format().into()
+/// A ray arriving at a point: its delay in nanoseconds and its strength in dBm.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tap {
+    pub delay: f32,
+    pub strength: f32,
 }
 
-fn signal_to_pixels(signal: &[f32], pixels: &mut [u8], dim_x: usize, dim_y: usize) {
-    //println!("{} {}", signal[dim_x/2 + dim_y*dim_y/2],signal[dim_x/2+1 + dim_y*dim_y/2]);
-    for x in 0..dim_x {
-        for y in 0..dim_y {
-            let (r, g, b) = signal_to_color(signal[x + y * dim_x]);
-            //println!("{} {}: {}", x, y, r);
-            set_pixel(pixels, (dim_x, dim_y), (x, y), (r, g, b));
+/// Sums over the taps reaching a point, weighted by their power in mW, from which
+/// their mean delay and delay spread follow without keeping the taps themselves.
+#[derive(Debug, Clone, Copy, Default)]
+struct DelayMoments {
+    power: f64,
+    delay: f64,
+    delay_squared: f64,
+}
+
+impl DelayMoments {
+    fn add(&mut self, tap: Tap) {
+        let power = dbm_to_mw(tap.strength) as f64;
+        let delay = tap.delay as f64;
+        self.power += power;
+        self.delay += power * delay;
+        self.delay_squared += power * delay * delay;
+    }
+
+    fn merge(&mut self, other: &DelayMoments) {
+        self.power += other.power;
+        self.delay += other.delay;
+        self.delay_squared += other.delay_squared;
+    }
+
+    /// RMS spread of the delays around their mean, or 0 without taps.
+    fn spread(&self) -> f32 {
+        if self.power <= 0.0 {
+            return 0.0;
         }
+        let mean = self.delay / self.power;
+        // Rounding can leave a tiny negative variance for a single tap.
+        (self.delay_squared / self.power - mean * mean)
+            .max(0.0)
+            .sqrt() as f32
     }
 }
 
-fn set_pixel(
-    pixels: &mut [u8],
-    (dim_x, dim_y): (usize, usize),
-    (x, y): (usize, usize),
-    (r, g, b): (u8, u8, u8),
-) {
-    let y = dim_y - y;
-    pixels[3 * x + 3 * y * dim_x] = r;
-    pixels[3 * x + 3 * y * dim_x + 1] = g;
-    pixels[3 * x + 3 * y * dim_x + 2] = b;
+/// Delay in nanoseconds of `ray` landing after travelling `t` further, beyond the time a
+/// straight line from `station` to where it lands takes. Rays for one point land at
+/// slightly different places, so their total delays differ even along the direct path,
+/// while their excess delays are all 0 there.
+fn excess_delay(station: Point3D, ray: &Ray, t: f32) -> f32 {
+    let direct = (ray.at(t) - station).length();
+    (ray.ray_time + t - direct).max(0.0) / METERS_PER_NS
 }
-fn draw_rectangle(
-    pixels: &mut [u8],
-    dim_x: usize,
-    dim_y: usize,
-    side_x: usize,
-    side_y: usize,
-    origin_x: usize,
-    origin_y: usize,
-) {
-    println!(
-        "Drawing rectangle with origin ({},{}) and dimension ({},{})",
-        origin_x, origin_y, side_x, side_x
-    );
-    for x in (origin_x - side_x)..(origin_x + side_x) {
-        for y in (origin_y - side_y)..(origin_y + side_y) {
-            set_pixel(pixels, (dim_x, dim_y), (x, y), (255, 0, 0));
-        }
+
+/// RMS delay spread of `taps` in nanoseconds: the spread of their delays around the
+/// mean delay, each weighted by its power. 0 for no taps.
+pub fn delay_spread(taps: &[Tap]) -> f32 {
+    let mut moments = DelayMoments::default();
+    for tap in taps {
+        moments.add(*tap);
     }
+    moments.spread()
 }
-fn write_floats(v: &Vec<f32>, f: &mut std::fs::File) -> std::io::Result<()> {
-    for float in v {
-        f.write_f32::<LittleEndian>(*float)?;
+
+/// Horizontal direction a ray travelling along `direction` arrives from, in radians
+/// in [-pi, pi] counting from east (+x) towards north (+z).
+fn arrival_azimuth(direction: Point3D) -> f32 {
+    (-direction.z()).atan2(-direction.x())
+}
+
+/// Angle above the horizon a ray travelling along `direction` arrives from, in radians.
+fn arrival_elevation(direction: Point3D) -> f32 {
+    let horizontal = (direction.x().powi(2) + direction.z().powi(2)).sqrt();
+    (-direction.y()).atan2(horizontal)
+}
+
+/// Azimuth and elevation, in radians, and strength in dBm of the strongest ray reaching
+/// point `(x, y)` of the traced grid of `scene`, or None if no ray did or the result
+/// recorded no angles.
+pub fn best_arrival(
+    result: &SignalResult,
+    scene: &Config,
+    x: usize,
+    y: usize,
+) -> Option<(f32, f32, f32)> {
+    let coord = x + y * scene.width * scene.supersampling.max(1);
+    if result.angles.is_empty() || is_no_signal(result.signals[coord]) {
+        return None;
     }
-    Ok(())
+    Some((
+        result.angles[coord],
+        result.elevations[coord],
+        result.signals[coord],
+    ))
 }
-fn add_buildings(objects: &[Cube], pixels: &mut [u8], image_width: usize, image_height: usize) {
-    for ob in objects.iter() {
-        if !matches!(ob.material, Material::Light(_)) && ob.origin.y() > 1.0 {
-            draw_rectangle(
-                pixels,
-                image_width,
-                image_height,
-                ob.dim_x as usize,
-                ob.dim_z as usize,
-                ob.origin.x() as i64 as usize,
-                ob.origin.z() as i64 as usize,
-            );
-        }
+
+/// Size in meters of a grid point of the traced grid, along x and z.
+fn grid_cell_size(scene: &Config) -> (f32, f32) {
+    let factor = scene.supersampling.max(1) as f32;
+    let (size_x, size_z) = scene.pixel_size();
+    (size_x / factor, size_z / factor)
+}
+
+/// Index of the grid point containing the ground position of `point`, if it is
+/// on the map.
+fn grid_coord(point: Point3D, scene: &Config) -> Option<usize> {
+    let point = scene.map_position(point);
+    if point.x() < 0.0
+        || point.z() < 0.0
+        || point.x() >= scene.world_width
+        || point.z() >= scene.world_height
+    {
+        return None;
     }
+    let factor = scene.supersampling.max(1);
+    let (dim_x, dim_y) = (scene.width * factor, scene.height * factor);
+    let (cell_x, cell_z) = grid_cell_size(scene);
+    // Rounding can put a point just inside the far edge in the next cell.
+    let x = ((point.x() / cell_x) as usize).min(dim_x - 1);
+    let y = ((point.z() / cell_z) as usize).min(dim_y - 1);
+    Some(x + y * dim_x)
 }
 
-const HOMOGENIZATION_FALLOFF: f32 = 3.0;
+//static DOWN: Point3D = Point3D::new(0.0, -1.0, 0.0);
+/// Whether a ray travelling along `direction` arrives within the receivers' elevation window.
+fn in_elevation_window(direction: Point3D, scene: &Config) -> bool {
+    // A descending ray arrives from above the horizon.
+    let elevation = (-direction.y() / direction.length()).asin().to_degrees();
+    elevation >= scene.elevation_min && elevation <= scene.elevation_max
+}
 
-fn homogenize_signals(signals: &mut Vec<f32>, dim_x: usize) {
-    //This method finds places where there is no signal, and sets it to the value of a nearby point where there is.
+/// Angle in degrees between a ray travelling along `direction` and the surface with
+/// `normal` it hits, 90 when it comes straight in.
+fn grazing_angle(direction: Point3D, normal: Point3D) -> f32 {
+    (direction.dot(&normal).abs() / (direction.length() * normal.length()))
+        .min(1.0)
+        .asin()
+        .to_degrees()
+}
 
-    //We cannot simply set a part of the signal map to another part in a parallel fashion.
-    //This may locally lead to some slight weirdness, but that's the price we pay.
-    let copied_signals = signals.clone();
-    signals.par_iter_mut().enumerate().for_each(|(i, s)| {
-        if (*s - -140.0).abs() < f32::EPSILON {
-            //unwrapping: let coord = x + y * scene.width;
-            let y = i / dim_x;
-            let x = i % dim_x;
+/// Follows a single ray through its bounces, calling `record` with the grid point, the ray
+/// segment, its `t` and the position wherever it reaches the receivers within the elevation
+/// window.
+fn trace_signal_ray(
+    ray: Ray,
+    objects: &[&Cube],
+    scene: &Config,
+    record: &mut impl FnMut(usize, &Ray, f32, Point3D),
+) -> usize {
+    trace_bounces(ray, objects, scene, scene.max_depth, record)
+}
 
-            //We go out to 5 spaces away
-            'circles: for j in 1..=2 {
-                if x < j || x > dim_x - j || y < j || y > dim_x - j {
-                    //Don't go past the edge. This makes the edges a bit ragged.
-                    break;
-                }
-                //Select the lower left corner of the square around (x,y) at distance j
-                let mut x_ = x - j;
-                let mut y_ = y - j;
-                //Go right
-                for _ in 1..=(j + 1) {
-                    if copied_signals[x_ + y_ * dim_x] > -140.0 {
-                        *s = copied_signals[x_ + y_ * dim_x] - HOMOGENIZATION_FALLOFF * (j as f32);
-                        break 'circles;
-                    }
-                    x_ += 1;
-                }
-                //Go up
-                for _ in 1..=(j + 1) {
-                    if copied_signals[x_ + y_ * dim_x] > -140.0 {
-                        *s = copied_signals[x_ + y_ * dim_x] - HOMOGENIZATION_FALLOFF * (j as f32);
-                        break 'circles;
-                    }
-                    y_ += 1;
-                }
-                //Go left on top
-                for _ in 1..=(j + 1) {
-                    if copied_signals[x_ + y_ * dim_x] > -140.0 {
-                        *s = copied_signals[x_ + y_ * dim_x] - HOMOGENIZATION_FALLOFF * (j as f32);
-                        break 'circles;
-                    }
-                    x_ -= 1;
+/// The ray diffracted over the far edge of the roof `hit_record` is on, towards the street
+/// behind it, if the scene has diffraction and the roof is tall enough.
+fn diffracted_ray(
+    ray: &Ray,
+    hit_record: &HitRecord,
+    objects: &[&Cube],
+    scene: &Config,
+) -> Option<Ray> {
+    let diffraction = scene.diffraction?;
+    if hit_record.normal.y() < 0.99
+        || hit_record.point.y() < diffraction.min_height.max(scene.ground_epsilon)
+    {
+        return None;
+    }
+    let horizontal = Point3D::new(ray.direction.x(), 0.0, ray.direction.z());
+    if horizontal.length() == 0.0 {
+        return None;
+    }
+    let horizontal = horizontal.unit_vector();
+    // Just below the roof, the first thing hit going on is the inside of the far wall.
+    let below_roof = hit_record.point - Point3D::new(0.0, 2.0 * scene.t_min, 0.0);
+    let probe = Ray::new(below_roof, horizontal, ray.strength, 0.0, ray.frequency);
+    let world = World::new(objects);
+    let edge = world.hit(&probe, 0.0, f32::MAX)?;
+    let origin = Point3D::new(edge.point.x(), hit_record.point.y(), edge.point.z())
+        + horizontal * (2.0 * scene.t_min);
+    let angle = rand::thread_rng().gen::<f32>() * diffraction.max_angle.to_radians();
+    Some(Ray::new(
+        origin,
+        horizontal * angle.cos() - Point3D::new(0.0, angle.sin(), 0.0),
+        ray.strength - diffraction.loss,
+        ray.ray_time + hit_record.t + edge.t,
+        ray.frequency,
+    ))
+}
+
+/// `trace_signal_ray` for a ray with `max_depth` bounces left.
+fn trace_bounces(
+    mut ray: Ray,
+    objects: &[&Cube],
+    scene: &Config,
+    max_depth: usize,
+    record: &mut impl FnMut(usize, &Ray, f32, Point3D),
+) -> usize {
+    let world = World::new(objects);
+    let mut depth = 0;
+    let mut penetrations = 0;
+    let mut hits = 0;
+    while depth < max_depth {
+        let t_min = ray.self_intersection_t(scene.t_min);
+        let hit = world.hit(&ray, t_min, f32::MAX);
+        let receivable = in_elevation_window(ray.direction, scene);
+        if receivable && scene.receiver_height > 0.0 {
+            // Record where the ray passes the receivers on its way to the next hit.
+            let t = (scene.receiver_height - ray.origin.y()) / ray.direction.y();
+            if t > t_min && hit.as_ref().is_none_or(|h| t < h.t) {
+                let point = ray.at(t);
+                if let Some(coord) = grid_coord(point, scene) {
+                    record(coord, &ray, t, point);
                 }
-                //Go down on the left
-                for _ in 1..=(j + 1) {
-                    if copied_signals[x_ + y_ * dim_x] > -140.0 {
-                        *s = copied_signals[x_ + y_ * dim_x] - HOMOGENIZATION_FALLOFF * (j as f32);
-                        break 'circles;
-                    }
-                    y_ -= 1;
+            }
+        }
+        let Some(hit_record) = hit else {
+            break;
+        };
+        hits += 1;
+        let on_ground = hit_record.point.y() < scene.ground_epsilon;
+        let ground_loss = match &scene.land_cover {
+            Some(land_cover) if on_ground => land_cover.loss_at(
+                scene.map_position(hit_record.point),
+                scene.world_width,
+                scene.world_height,
+            ),
+            _ => 0.0,
+        };
+        // Applies to the recorded signal as well as to the reflected ray.
+        ray.strength -= ground_loss;
+        if receivable
+            && scene.receiver_height <= 0.0
+            && on_ground
+            && grazing_angle(ray.direction, hit_record.normal) >= scene.min_grazing_angle
+        {
+            //We hit the ground, record the signal
+            if let Some(coord) = grid_coord(hit_record.point, scene) {
+                record(coord, &ray, hit_record.t, hit_record.point);
+            }
+        }
+        if let Material::Wall(_) = hit_record.material {
+            if hit_record.front_face {
+                penetrations += 1;
+                if scene.max_penetrations.is_some_and(|max| penetrations > max) {
+                    break;
                 }
             }
+        } else {
+            depth += 1;
         }
-    });
+        if hit_record.t < STALL_FACTOR * t_min {
+            break;
+        }
+        // Bounces so far, including those before a recursive call.
+        let bounce = scene.max_depth.saturating_sub(max_depth) + depth.max(1);
+        if !scene.bounce_budget.allows(hit_record.material, bounce) {
+            break;
+        }
+        if depth < max_depth {
+            if let Some(diffracted) = diffracted_ray(&ray, &hit_record, objects, scene) {
+                hits += trace_bounces(diffracted, objects, scene, max_depth - depth, record);
+            }
+        }
+        let mut scattered: Vec<Ray> = match scene.ground_reflection_loss(ray.frequency) {
+            Some(loss) if on_ground => vec![Ray::new(
+                hit_record.point,
+                ray.direction.reflect(&hit_record.normal),
+                ray.strength - loss,
+                ray.ray_time + hit_record.t,
+                ray.frequency,
+            )],
+            _ => hit_record
+                .material
+                .scatter_multi(&ray, &hit_record)
+                .into_iter()
+                .filter_map(|(new_ray, _)| new_ray)
+                .collect(),
+        };
+        // Rays split off by the material are followed on their own.
+        while scattered.len() > 1 {
+            let split = scattered.pop().expect("More than one ray");
+            hits += trace_bounces(split, objects, scene, max_depth - depth, record);
+        }
+        match scattered.pop() {
+            Some(new_ray) => {
+                ray = new_ray;
+            }
+            None => {
+                break;
+            }
+        }
+    }
+    hits
 }
 
-pub fn generate_sigmap(
-    filename_base: &str,
-    scene: &Config,
-    do_times: bool,
-    do_angles: bool,
-    do_png: bool,
-) {
-    let image_width = scene.width;
-    let image_height = scene.height;
+fn signal_objects(scene: &Config) -> Vec<&Cube> {
+    scene.objects_by_material(|m| !matches!(m, Material::Light(_)))
+}
 
-    let stations = find_lights(&scene.objects);
-    let start = Instant::now();
-    stations.par_iter().for_each(|s| {
-        //for s in stations.iter() {
-        let (mut signals, times, angles) = generate_signal(s, scene, do_times, do_angles);
-        homogenize_signals(&mut signals, image_width);
-        let freq = match s.material {
-            Material::Light(l) => Ok(l.frequency),
-            _ => Err(""),
-        }
-        .expect("Station does not have light material");
-        let filename =
-            filename_base.to_string() + "_" + &(s.id as i32).to_string() + "_" + &freq.to_string();
-        //let s = serde_json::to_string(&signals).expect("Failed to serialize");
-        //std::fs::write(filename.clone() + ".json", &s).expect("Could not write to file");
-        let mut signals_file =
-            File::create(filename.clone() + ".data").expect("Failed to create data file");
-        write_floats(&signals, &mut signals_file).expect("Could not write data");
-        if do_times {
-            let mut times_file =
-                File::create(filename.clone() + ".times").expect("Failed to create times file");
+/// Slack in meters on the range beyond which `station_objects` drops objects.
+const PRECULL_MARGIN: f32 = 1.0;
 
-            write_floats(&times, &mut times_file).expect("Could not write times");
-        }
-        if do_angles {
-            let mut angles_file =
-                File::create(filename.clone() + ".angles").expect("Failed to create angles file");
+/// Path length beyond which even a line of sight ray from `station`, at the strongest
+/// any of its beams launches, arrives below the noise floor. The signal maps leave out
+/// whatever rays record beyond it, so they do not depend on objects that far out.
+fn useful_range(station: &Cube, scene: &Config) -> f32 {
+    let Material::Light(light) = &station.material else {
+        return f32::MAX;
+    };
+    let gain = light
+        .beam_configs
+        .iter()
+        .map(|beam| beam.gain)
+        .fold(0.0, f32::max);
+    let ray = Ray::new(
+        station.origin,
+        station.origin,
+        light.strength + gain,
+        0.0,
+        light.frequency,
+    );
+    ray.distance_to_strength(scene.noise_floor)
+}
 
-            write_floats(&angles, &mut angles_file).expect("Could not write angles");
-        }
-        if do_png {
-            let mut pixels: Vec<u8> = vec![0; (image_width + 1) * (image_height + 1) * 3];
-            signal_to_pixels(&signals, &mut pixels, image_width, image_height);
-            add_buildings(&scene.objects, &mut pixels, image_width, image_height);
-            write_image(&(filename + ".png"), &pixels, (image_width, image_height))
-                .expect("error writing image");
-        }
-    });
-    println!("Frame time: {}ms", start.elapsed().as_millis());
+/// The objects signal rays from `station` can hit within its `useful_range`. Rays leave
+/// from anywhere on the aperture, so that is added to the range. What a ray records after
+/// hitting anything further out is left out of the maps anyway.
+fn station_objects<'a>(station: &Cube, scene: &'a Config) -> Vec<&'a Cube> {
+    let aperture = station_aperture(station);
+    let range = useful_range(station, scene)
+        + (aperture.width.powi(2) + aperture.height.powi(2)).sqrt() / 2.0
+        + PRECULL_MARGIN;
+    signal_objects(scene)
+        .into_iter()
+        .filter(|object| {
+            // The distance to the bounding sphere, which also holds for rotated cubes.
+            let half_diagonal =
+                (object.dim_x.powi(2) + object.dim_y.powi(2) + object.dim_z.powi(2)).sqrt();
+            (object.origin - station.origin).length() - half_diagonal <= range
+        })
+        .collect()
+}
+
+fn station_params(station: &Cube) -> (i64, f32, Frequency) {
+    match &station.material {
+        Material::Light(l) => Ok((l.beams, l.strength, l.frequency)),
+        _ => Err(""),
+    }
+    .expect("Station does not have light material")
+}
+
+fn beam_pattern(station: &Cube) -> BeamPattern<'_> {
+    match &station.material {
+        Material::Light(l) if !l.beam_configs.is_empty() => BeamPattern::Steered(&l.beam_configs),
+        _ => BeamPattern::Sectors(station_params(station).0),
+    }
+}
+
+fn station_aperture(station: &Cube) -> Aperture {
+    match &station.material {
+        Material::Light(l) => l.aperture,
+        _ => Aperture::default(),
+    }
+}
+
+/// Rays `station` launches toward grid point (`target_x`, `target_y`).
+fn station_rays(station: &Cube, scene: &Config, target_x: usize, target_y: usize) -> Vec<Ray> {
+    let (_, base_strength, frequency) = station_params(station);
+    // The targets are laid out relative to the corner of the map.
+    let corner = station.origin - scene.map_position(station.origin);
+    let targets: Vec<Point3D> = subsample_targets(
+        scene.map_position(station.origin),
+        (target_x, target_y),
+        grid_cell_size(scene),
+        scene.subsampling,
+        scene.rays_per_target,
+    )
+    .into_iter()
+    .map(|target| target + corner)
+    .collect();
+    rays_to(
+        station.origin,
+        station_aperture(station),
+        &targets,
+        &beam_pattern(station),
+        base_strength,
+        frequency,
+    )
+}
+
+/// Traces a station's rays onto a grid of `scene.supersampling` points per
+/// pixel in each direction.
+pub fn generate_signal(station: &Cube, scene: &Config, options: &SigmapOptions) -> SignalResult {
+    trace_station(station, scene, &station_objects(station, scene), options)
+}
+
+/// `generate_signal` with the rays hitting `objects`.
+fn trace_station(
+    station: &Cube,
+    scene: &Config,
+    objects: &[&Cube],
+    options: &SigmapOptions,
+) -> SignalResult {
+    let factor = scene.supersampling.max(1);
+    let (dim_x, dim_y) = (scene.width * factor, scene.height * factor);
+    let grid_size = (dim_x + 1) * (dim_y + 1);
+    let mut result = SignalResult::new(grid_size, options, scene.aggregation, station.origin);
+    let range = useful_range(station, scene);
+    //for i in 0..scene.nr_probes {
+    for target_y in 0..dim_y {
+        for target_x in 0..dim_x {
+            for ray in station_rays(station, scene, target_x, target_y) {
+                trace_signal_ray(ray, objects, scene, &mut |coord, ray, t, _| {
+                    if ray.ray_time + t <= range {
+                        result.record(coord, ray, t, scene.noise_floor)
+                    }
+                });
+            }
+        }
+    }
+    result.sum_powers(scene.noise_floor, rays_per_point(scene));
+    result
+}
+
+/// Rows of the grid `stream_signal` keeps open up to the row it is tracing. A ray lands at
+/// most a row before the one it is aimed at, so the map comes out as with `generate_signal`.
+const STREAM_LAG: usize = 2;
+
+/// Checks that every ray of `scene` goes straight to its target, so that `stream_signal`
+/// can write a row once the rows after it are being traced. Reflections, diffraction and
+/// receivers above the ground land on rows anywhere on the way.
+fn check_streamable(scene: &Config) -> Result<(), RaytracerError> {
+    let unsupported = if scene.supersampling > 1 {
+        "supersampling".to_string()
+    } else if scene.max_depth > 1 {
+        format!(
+            "reflections, max_depth must be 1 instead of {}",
+            scene.max_depth
+        )
+    } else if scene.diffraction.is_some() {
+        "diffraction".to_string()
+    } else if scene.receiver_height > 0.0 {
+        "a receiver_height above the ground".to_string()
+    } else {
+        return Ok(());
+    };
+    Err(RaytracerError::Validation(format!(
+        "Streaming does not support {}",
+        unsupported
+    )))
+}
+
+/// Traces `station` like `generate_signal`, a row of targets at a time, and writes each row
+/// of the map to `out` in the `.data` layout as soon as no more rays are aimed near it, so
+/// only a few rows are kept in memory. The rows are masked but not homogenized. Scenes
+/// whose rays do not go straight to their targets are rejected, see `check_streamable`.
+pub fn stream_signal(
+    station: &Cube,
+    scene: &Config,
+    out: &mut impl std::io::Write,
+    linear: bool,
+) -> Result<(), RaytracerError> {
+    check_streamable(scene)?;
+    let (dim_x, dim_y) = (scene.width, scene.height);
+    let options = SigmapOptions::default();
+    let objects = station_objects(station, scene);
+    let range = useful_range(station, scene);
+    let mut rows: VecDeque<SignalResult> = VecDeque::new();
+    // Grid row of the first of `rows`.
+    let mut first_row = 0;
+    let mut stray = 0;
+    let mut write_row = |row: Option<SignalResult>, y: usize| {
+        let mut signals = match row {
+            Some(mut row) => {
+                row.sum_powers(scene.noise_floor, rays_per_point(scene));
+                row.signals
+            }
+            None => vec![NO_SIGNAL; dim_x],
+        };
+        for (x, signal) in signals.iter_mut().enumerate() {
+            if masked_out(scene, x, y) {
+                *signal = NO_SIGNAL;
+            }
+        }
+        write_signals(&signals[..dim_x], out, linear)
+    };
+    for target_y in 0..dim_y {
+        for target_x in 0..dim_x {
+            for ray in station_rays(station, scene, target_x, target_y) {
+                trace_signal_ray(ray, &objects, scene, &mut |coord, ray, t, _| {
+                    if ray.ray_time + t > range {
+                        return;
+                    }
+                    let row = coord / dim_x;
+                    if row < first_row || row > target_y + 1 {
+                        stray += 1;
+                        return;
+                    }
+                    while rows.len() <= row - first_row {
+                        rows.push_back(SignalResult::new(
+                            dim_x,
+                            &options,
+                            scene.aggregation,
+                            station.origin,
+                        ));
+                    }
+                    rows[row - first_row].record(coord % dim_x, ray, t, scene.noise_floor);
+                });
+            }
+        }
+        if stray > 0 {
+            return Err(RaytracerError::Geometry(format!(
+                "{} rays aimed at row {} landed outside the rows being streamed",
+                stray, target_y
+            )));
+        }
+        while first_row + STREAM_LAG <= target_y {
+            write_row(rows.pop_front(), first_row)?;
+            first_row += 1;
+        }
+    }
+    while first_row < dim_y {
+        write_row(rows.pop_front(), first_row)?;
+        first_row += 1;
+    }
+    // Like the maps of `generate_signal`, the file has room for an extra row and column.
+    write_signals(&vec![NO_SIGNAL; dim_x + dim_y + 1], out, linear)?;
+    Ok(())
+}
+
+/// Breakdown of the strongest path from a station to a probe, in meters and dB.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LinkBudget {
+    pub probe: usize,
+    pub station: i64,
+    // Straight-line distance from the station to the probe.
+    pub distance: f32,
+    // Length of the path the signal travelled, bounces included.
+    pub path_length: f32,
+    pub beam_loss: f32,
+    pub free_space_loss: f32,
+    pub bounce_loss: f32,
+    pub received: f32,
+}
+
+/// Link budget of the serving station, the one received strongest, at each probe in
+/// `scene.probes`. Probes no station reaches are left out.
+pub fn link_budget(scene: &Config) -> Vec<LinkBudget> {
+    let probe_coords: Vec<Option<usize>> =
+        scene.probes.iter().map(|p| grid_coord(*p, scene)).collect();
+    let factor = scene.supersampling.max(1);
+    let stations = find_lights(scene);
+    let per_station: Vec<Vec<Option<LinkBudget>>> = stations
+        .par_iter()
+        .map(|station| {
+            let mut best: Vec<Option<LinkBudget>> = vec![None; scene.probes.len()];
+            let objects = signal_objects(scene);
+            let (_, base_strength, _) = station_params(station);
+            for target_x in 0..scene.width * factor {
+                for target_y in 0..scene.height * factor {
+                    for ray in station_rays(station, scene, target_x, target_y) {
+                        let launch_strength = ray.strength;
+                        trace_signal_ray(ray, &objects, scene, &mut |coord, ray, t, _| {
+                            let received = ray.strength_at(t);
+                            for (probe, probe_coord) in probe_coords.iter().enumerate() {
+                                if *probe_coord != Some(coord)
+                                    || best[probe].as_ref().is_some_and(|b| b.received >= received)
+                                {
+                                    continue;
+                                }
+                                let path_length = ray.ray_time + t;
+                                best[probe] = Some(LinkBudget {
+                                    probe,
+                                    station: station.id,
+                                    distance: (scene.probes[probe] - station.origin).length(),
+                                    path_length,
+                                    beam_loss: base_strength - launch_strength,
+                                    free_space_loss: ray.free_space_fallof(path_length),
+                                    bounce_loss: launch_strength - ray.strength,
+                                    received,
+                                });
+                            }
+                        });
+                    }
+                }
+            }
+            best
+        })
+        .collect();
+    (0..scene.probes.len())
+        .filter_map(|probe| {
+            per_station
+                .iter()
+                .filter_map(|best| best[probe].clone())
+                .max_by(|a, b| {
+                    a.received
+                        .partial_cmp(&b.received)
+                        .unwrap_or(std::cmp::Ordering::Equal)
+                })
+        })
+        .collect()
+}
+
+/// Signal strength measured at a point of the map, in meters from its corner.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Measurement {
+    pub x: f32,
+    pub y: f32,
+    pub dbm: f32,
+}
+
+/// Reads measurements from csv lines of `x,y,dbm`. A first line that is not
+/// numeric is taken as a header.
+pub fn read_measurements<R: std::io::Read>(reader: R) -> Result<Vec<Measurement>, RaytracerError> {
+    let mut measurements = Vec::new();
+    for (i, line) in std::io::BufReader::new(reader).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields: Vec<Result<f32, _>> = line.split(',').map(|f| f.trim().parse()).collect();
+        match fields[..] {
+            [Ok(x), Ok(y), Ok(dbm)] => measurements.push(Measurement { x, y, dbm }),
+            [Err(_), ..] | [_, Err(_), ..] | [_, _, Err(_)] if i == 0 => {}
+            _ => {
+                return Err(RaytracerError::Parse(format!(
+                    "Line {} is not x,y,dbm: {}",
+                    i + 1,
+                    line
+                )))
+            }
+        }
+    }
+    Ok(measurements)
+}
+
+/// How much of a map is served at a threshold.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CoverageStats {
+    // Points with a signal at or above the threshold.
+    pub served: usize,
+    pub total: usize,
+    pub percentage: f32,
+    // Area of the served points in square meters.
+    pub served_area: f32,
+}
+
+/// Coverage of a `scene.width` by `scene.height` map of `signals` in dBm at `threshold`
+/// dBm, with each point covering `scene.pixel_size()`.
+pub fn coverage_stats(
+    signals: &[f32],
+    scene: &Config,
+    threshold: f32,
+) -> Result<CoverageStats, RaytracerError> {
+    let total = scene.width * scene.height;
+    if signals.len() < total {
+        return Err(RaytracerError::Geometry(format!(
+            "A map of {} points does not fit a {}x{} grid",
+            signals.len(),
+            scene.width,
+            scene.height
+        )));
+    }
+    let served = signals[..total]
+        .iter()
+        .filter(|s| !is_no_signal(**s) && **s >= threshold)
+        .count();
+    let (size_x, size_z) = scene.pixel_size();
+    Ok(CoverageStats {
+        served,
+        total,
+        percentage: 100.0 * served as f32 / total.max(1) as f32,
+        served_area: served as f32 * size_x * size_z,
+    })
+}
+
+/// A measurement next to the simulated signal at its position.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Residual {
+    pub measurement: Measurement,
+    pub simulated: f32,
+}
+
+/// How well a simulated map matches measurements, in dB. The error is simulated
+/// minus measured.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ComparisonStats {
+    // Measurements on the map where the simulation has signal.
+    pub count: usize,
+    pub mean_error: f32,
+    pub rmse: f32,
+    // Pearson correlation of simulated and measured strengths. `None` when it is not
+    // defined: with fewer than two measurements, or when either side does not vary.
+    pub correlation: Option<f32>,
+    pub residuals: Vec<Residual>,
+}
+
+/// Bilinearly interpolated signal at `(x, y)` meters, between the centers of the
+/// pixels around it. `None` off the map or next to a pixel without signal.
+fn sample_signal(signals: &[f32], scene: &Config, x: f32, y: f32) -> Option<f32> {
+    if x < 0.0 || y < 0.0 || x > scene.world_width || y > scene.world_height {
+        return None;
+    }
+    let (size_x, size_y) = scene.pixel_size();
+    let max = ((scene.width - 1) as f32, (scene.height - 1) as f32);
+    let gx = (x / size_x - 0.5).clamp(0.0, max.0);
+    let gy = (y / size_y - 0.5).clamp(0.0, max.1);
+    let (x0, y0) = (gx.floor() as usize, gy.floor() as usize);
+    let (x1, y1) = (
+        (x0 + 1).min(scene.width - 1),
+        (y0 + 1).min(scene.height - 1),
+    );
+    let (fx, fy) = (gx - x0 as f32, gy - y0 as f32);
+    let corners = [
+        signals[x0 + y0 * scene.width],
+        signals[x1 + y0 * scene.width],
+        signals[x0 + y1 * scene.width],
+        signals[x1 + y1 * scene.width],
+    ];
+    if corners.iter().any(|s| is_no_signal(*s)) {
+        return None;
+    }
+    let bottom = corners[0] * (1.0 - fx) + corners[1] * fx;
+    let top = corners[2] * (1.0 - fx) + corners[3] * fx;
+    Some(bottom * (1.0 - fy) + top * fy)
+}
+
+/// Compares a `scene.width` by `scene.height` map of `signals` in dBm to measurements.
+pub fn compare_to_measurements(
+    signals: &[f32],
+    scene: &Config,
+    measurements: &[Measurement],
+) -> ComparisonStats {
+    let residuals: Vec<Residual> = measurements
+        .iter()
+        .filter_map(|m| {
+            sample_signal(signals, scene, m.x, m.y).map(|simulated| Residual {
+                measurement: *m,
+                simulated,
+            })
+        })
+        .collect();
+    let count = residuals.len();
+    let n = count.max(1) as f32;
+    let errors: Vec<f32> = residuals
+        .iter()
+        .map(|r| r.simulated - r.measurement.dbm)
+        .collect();
+    let mean_error = errors.iter().sum::<f32>() / n;
+    let rmse = (errors.iter().map(|e| e * e).sum::<f32>() / n).sqrt();
+    let mean_simulated = residuals.iter().map(|r| r.simulated).sum::<f32>() / n;
+    let mean_measured = residuals.iter().map(|r| r.measurement.dbm).sum::<f32>() / n;
+    let (mut covariance, mut var_simulated, mut var_measured) = (0.0, 0.0, 0.0);
+    for r in residuals.iter() {
+        let (ds, dm) = (
+            r.simulated - mean_simulated,
+            r.measurement.dbm - mean_measured,
+        );
+        covariance += ds * dm;
+        var_simulated += ds * ds;
+        var_measured += dm * dm;
+    }
+    let correlation = if var_simulated > 0.0 && var_measured > 0.0 {
+        Some(covariance / (var_simulated * var_measured).sqrt())
+    } else {
+        None
+    };
+    ComparisonStats {
+        count,
+        mean_error,
+        rmse,
+        correlation,
+        residuals,
+    }
+}
+
+/// Writes the residuals of a comparison as csv.
+pub fn write_residuals<W: std::io::Write>(
+    mut writer: W,
+    stats: &ComparisonStats,
+) -> std::io::Result<()> {
+    writeln!(writer, "x,y,measured,simulated,error")?;
+    for r in stats.residuals.iter() {
+        let m = r.measurement;
+        writeln!(
+            writer,
+            "{},{},{},{},{}",
+            m.x,
+            m.y,
+            m.dbm,
+            r.simulated,
+            r.simulated - m.dbm
+        )?;
+    }
+    Ok(())
+}
+
+/// Per-point change in dB from map `a` to map `b`, positive where `b` is
+/// stronger. Points without signal count as exactly `NO_SIGNAL`, so a point
+/// neither map reaches has a difference of 0 and coverage gained or lost is
+/// measured from there.
+pub fn diff_maps(a: &[f32], b: &[f32]) -> Vec<f32> {
+    a.iter()
+        .zip(b)
+        .map(|(a, b)| b.max(NO_SIGNAL) - a.max(NO_SIGNAL))
+        .collect()
+}
+
+// Difference in dB at which the diff png is fully saturated.
+const DIFF_RANGE: f32 = 20.0;
+
+/// Diverging color for a difference in dB: white when unchanged, going to blue
+/// for a loss and red for a gain.
+fn diff_to_color(diff: f32) -> (u8, u8, u8) {
+    let level = (diff / DIFF_RANGE).clamp(-1.0, 1.0);
+    let fade = 1.0 - level.abs();
+    let color = if level < 0.0 {
+        LinSrgb::new(fade, fade, 1.0)
+    } else {
+        LinSrgb::new(1.0, fade, fade)
+    };
+    let [r, g, b] = linear_to_srgb8(color);
+    (r, g, b)
+}
+
+/// Writes the change from `before` to `after`, both `scene.width` by
+/// `scene.height` maps in dBm, to `<filename>.data` and `<filename>.png`. The png
+/// is black where neither map has signal.
+pub fn write_diff(
+    filename: &str,
+    before: &[f32],
+    after: &[f32],
+    scene: &Config,
+) -> Result<(), RaytracerError> {
+    // A `.data` file holds a row and column more than the map, which come last.
+    let size = scene.width * scene.height;
+    if before.len() < size || after.len() < size {
+        return Err(RaytracerError::Geometry(format!(
+            "Maps of {} and {} points cannot be compared on a {}x{} grid",
+            before.len(),
+            after.len(),
+            scene.width,
+            scene.height
+        )));
+    }
+    let (before, after) = (&before[..size], &after[..size]);
+    let diff = diff_maps(before, after);
+    let mut file = create_output(filename.to_string() + ".data")?;
+    write_floats(&diff, &mut file)?;
+    let (dim_x, dim_y) = (scene.width, scene.height);
+    let mut pixels: Vec<u8> = vec![0; (dim_x + 1) * (dim_y + 1) * 3];
+    for x in 0..dim_x {
+        for y in 0..dim_y {
+            let coord = x + y * dim_x;
+            if before[coord] > NO_SIGNAL || after[coord] > NO_SIGNAL {
+                set_pixel(
+                    &mut pixels,
+                    (dim_x, dim_y),
+                    (x, y),
+                    diff_to_color(diff[coord]),
+                );
+            }
+        }
+    }
+    write_image(
+        &(filename.to_string() + ".png"),
+        &pixels,
+        (dim_x, dim_y),
+        scene,
+    )?;
+    Ok(())
+}
+
+/// Side of a web map tile in pixels.
+const TILE_SIZE: usize = 256;
+
+/// A signal map resampled onto the Web Mercator pixels of one zoom level.
+#[derive(Debug)]
+pub struct MercatorMap {
+    pub zoom: u8,
+    /// Global pixel coordinates of the top left pixel, counted like those of slippy
+    /// map tiles from the north west corner of the world.
+    pub origin: (usize, usize),
+    pub width: usize,
+    pub height: usize,
+    pub signals: Vec<f32>,
+}
+
+/// Global Web Mercator pixel coordinates of a point at `zoom`.
+fn geo_to_mercator(lat: f64, lon: f64, zoom: u8) -> (f64, f64) {
+    let size = (TILE_SIZE << zoom) as f64;
+    let lat = lat.to_radians();
+    let x = (lon + 180.0) / 360.0 * size;
+    let y = (1.0 - (lat.tan() + 1.0 / lat.cos()).ln() / std::f64::consts::PI) / 2.0 * size;
+    (x, y)
+}
+
+/// Latitude and longitude at global Web Mercator pixel coordinates at `zoom`.
+fn mercator_to_geo(x: f64, y: f64, zoom: u8) -> (f64, f64) {
+    let size = (TILE_SIZE << zoom) as f64;
+    let lon = x / size * 360.0 - 180.0;
+    let lat = (std::f64::consts::PI * (1.0 - 2.0 * y / size))
+        .sinh()
+        .atan()
+        .to_degrees();
+    (lat, lon)
+}
+
+/// Resamples a `scene.width` by `scene.height` map onto the Web Mercator pixels
+/// covering it at `zoom`, taking for each pixel the signal map pixel its center
+/// falls in. Pixels off the map get `NO_SIGNAL`.
+pub fn reproject_mercator(
+    signals: &[f32],
+    scene: &Config,
+    zoom: u8,
+) -> Result<MercatorMap, RaytracerError> {
+    let reference = scene.geo_reference.as_ref().ok_or_else(|| {
+        RaytracerError::Validation("Reprojecting needs a geo_reference in the config".to_string())
+    })?;
+    // A `.data` file holds a row and column more than the map, which come last.
+    let size = scene.width * scene.height;
+    if signals.len() < size {
+        return Err(RaytracerError::Geometry(format!(
+            "A map of {} points does not fit a {}x{} grid",
+            signals.len(),
+            scene.width,
+            scene.height
+        )));
+    }
+    let signals = &signals[..size];
+    let corner = scene.grid_origin;
+    // The map is a rectangle in latitude and longitude, so its corners bound it.
+    let (south, west) = reference.to_geo(corner);
+    let (north, east) =
+        reference.to_geo(corner + Point3D::new(scene.world_width, 0.0, scene.world_height));
+    let (left, top) = geo_to_mercator(north, west, zoom);
+    let (right, bottom) = geo_to_mercator(south, east, zoom);
+    let origin = (left.floor() as usize, top.floor() as usize);
+    let width = right.ceil() as usize - origin.0;
+    let height = bottom.ceil() as usize - origin.1;
+    let (size_x, size_z) = scene.pixel_size();
+    let mut reprojected = vec![NO_SIGNAL; width * height];
+    for py in 0..height {
+        for px in 0..width {
+            let (lat, lon) = mercator_to_geo(
+                (origin.0 + px) as f64 + 0.5,
+                (origin.1 + py) as f64 + 0.5,
+                zoom,
+            );
+            let position = GeoPosition {
+                lat,
+                lon,
+                height: 0.0,
+            };
+            let point = scene.map_position(position.to_local(reference));
+            if point.x() < 0.0
+                || point.z() < 0.0
+                || point.x() >= scene.world_width
+                || point.z() >= scene.world_height
+            {
+                continue;
+            }
+            let x = ((point.x() / size_x) as usize).min(scene.width - 1);
+            let y = ((point.z() / size_z) as usize).min(scene.height - 1);
+            reprojected[px + py * width] = signals[x + y * scene.width];
+        }
+    }
+    Ok(MercatorMap {
+        zoom,
+        origin,
+        width,
+        height,
+        signals: reprojected,
+    })
+}
+
+/// Cuts `map` into `<directory>/<zoom>/<x>/<y>.png` tiles for a web map viewer,
+/// transparent where there is no signal. Returns the number of tiles written.
+pub fn write_mercator_tiles(directory: &str, map: &MercatorMap) -> Result<usize, RaytracerError> {
+    let first = (map.origin.0 / TILE_SIZE, map.origin.1 / TILE_SIZE);
+    let last = (
+        (map.origin.0 + map.width - 1) / TILE_SIZE,
+        (map.origin.1 + map.height - 1) / TILE_SIZE,
+    );
+    let mut count = 0;
+    for tile_x in first.0..=last.0 {
+        let column = Path::new(directory)
+            .join(map.zoom.to_string())
+            .join(tile_x.to_string());
+        std::fs::create_dir_all(&column)?;
+        for tile_y in first.1..=last.1 {
+            let mut tile = image::RgbaImage::new(TILE_SIZE as u32, TILE_SIZE as u32);
+            for (x, y, pixel) in tile.enumerate_pixels_mut() {
+                let global_x = tile_x * TILE_SIZE + x as usize;
+                let global_y = tile_y * TILE_SIZE + y as usize;
+                if global_x < map.origin.0 || global_y < map.origin.1 {
+                    continue;
+                }
+                let (px, py) = (global_x - map.origin.0, global_y - map.origin.1);
+                if px >= map.width || py >= map.height {
+                    continue;
+                }
+                let signal = map.signals[px + py * map.width];
+                if !is_no_signal(signal) {
+                    let (r, g, b) = signal_to_color(signal);
+                    *pixel = image::Rgba([r, g, b, 255]);
+                }
+            }
+            tile.save(column.join(format!("{}.png", tile_y)))
+                .map_err(std::io::Error::other)?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Number of rays a beam launches towards each point of the traced grid, see
+/// `subsample_targets`.
+fn rays_per_point(scene: &Config) -> usize {
+    SUBSAMPLING.pow(2) * scene.rays_per_target.max(1)
+}
+
+/// Number of rays `generate_sigmap` traces for this scene.
+pub fn signal_ray_count(scene: &Config) -> usize {
+    let factor = scene.supersampling.max(1);
+    scene.width
+        * factor
+        * scene.height
+        * factor
+        * rays_per_point(scene)
+        * find_lights(scene)
+            .iter()
+            .map(|s| beam_pattern(s).len())
+            .sum::<usize>()
+}
+
+/// Average time it takes to trace one signal ray, measured by tracing `samples` rays from the
+/// first station to random targets. `None` if the scene has no stations.
+pub fn time_per_signal_ray(scene: &Config, samples: usize) -> Option<Duration> {
+    let mut rng = rand::thread_rng();
+    let stations = find_lights(scene);
+    let station = stations.first()?;
+    let factor = scene.supersampling.max(1);
+    let (dim_x, dim_y) = (scene.width * factor, scene.height * factor);
+    let mut result = SignalResult::new(
+        (dim_x + 1) * (dim_y + 1),
+        &SigmapOptions::default(),
+        scene.aggregation,
+        station.origin,
+    );
+    let objects = signal_objects(scene);
+    let start = Instant::now();
+    let mut traced = 0;
+    while traced < samples.max(1) {
+        let rays = station_rays(
+            station,
+            scene,
+            rng.gen_range(0..dim_x.max(1)),
+            rng.gen_range(0..dim_y.max(1)),
+        );
+        traced += rays.len();
+        for ray in rays {
+            trace_signal_ray(ray, &objects, scene, &mut |coord, ray, t, _| {
+                result.record(coord, ray, t, scene.noise_floor)
+            });
+        }
+    }
+    Some(start.elapsed() / traced as u32)
+}
+
+/// Linear color of a signal on the ramp from black at -100 dBm to blue.
+fn signal_to_linear(signal: f32) -> LinSrgb {
+    let value = (((signal + 100.0) / 100.0) * 3.0).clamp(0.0, 1.0);
+    LinSrgb::new(
+        (value - 2.0).clamp(0.0, 1.0),
+        (value - 1.0).clamp(0.0, 1.0),
+        value.clamp(0.0, 1.0),
+    )
+}
+
+/// Signal color with the same sRGB encoding as the rendered view.
+fn signal_to_color(signal: f32) -> (u8, u8, u8) {
+    let [r, g, b] = linear_to_srgb8(signal_to_linear(signal));
+    (r, g, b)
+}
+
+/// Colors an arrival azimuth as a hue around the color wheel, fading to black
+/// as the signal gets weaker.
+fn angle_to_color(angle: f32, signal: f32) -> (u8, u8, u8) {
+    let hue = angle.to_degrees().rem_euclid(360.0);
+    let level = ((signal - NO_SIGNAL) / -NO_SIGNAL).clamp(0.0, 1.0);
+    let color = Srgb::from_color(Hsv::new(hue, level, level));
+    color.into_format().into()
+}
+
+// Speed of light in meters per nanosecond.
+const METERS_PER_NS: f32 = 0.299_792_46;
+
+/// Colors a propagation delay from blue when immediate to red at `max_delay`
+/// nanoseconds or more. Pixels without signal are black.
+fn delay_to_color(delay: f32, signal: f32, max_delay: f32) -> (u8, u8, u8) {
+    if is_no_signal(signal) {
+        return (0, 0, 0);
+    }
+    let fraction = (delay / max_delay).clamp(0.0, 1.0);
+    let color = Srgb::from_color(Hsv::new(240.0 * (1.0 - fraction), 1.0, 1.0));
+    color.into_format().into()
+}
+
+/// Colors the path lengths in `times` by their delay, see `delay_to_color`.
+fn delays_to_pixels(
+    signal: &[f32],
+    times: &[f32],
+    max_delay: f32,
+    pixels: &mut [u8],
+    dim_x: usize,
+    dim_y: usize,
+) {
+    for x in 0..dim_x {
+        for y in 0..dim_y {
+            let coord = x + y * dim_x;
+            let color = delay_to_color(times[coord] / METERS_PER_NS, signal[coord], max_delay);
+            set_pixel(pixels, (dim_x, dim_y), (x, y), color);
+        }
+    }
+}
+
+/// Colors a map of `signal`, by strength or with `angles` by arrival angle. Points
+/// without signal get `no_signal_color`, so holes stand out from weak coverage.
+fn signal_to_pixels(
+    signal: &[f32],
+    angles: Option<&[f32]>,
+    pixels: &mut [u8],
+    dim_x: usize,
+    dim_y: usize,
+    no_signal_color: [u8; 3],
+) {
+    //println!("{} {}", signal[dim_x/2 + dim_y*dim_y/2],signal[dim_x/2+1 + dim_y*dim_y/2]);
+    for x in 0..dim_x {
+        for y in 0..dim_y {
+            let (r, g, b) = match angles {
+                _ if is_no_signal(signal[x + y * dim_x]) => no_signal_color.into(),
+                Some(angles) => angle_to_color(angles[x + y * dim_x], signal[x + y * dim_x]),
+                None => signal_to_color(signal[x + y * dim_x]),
+            };
+            //println!("{} {}: {}", x, y, r);
+            set_pixel(pixels, (dim_x, dim_y), (x, y), (r, g, b));
+        }
+    }
+}
+
+/// Colors a grid supersampled by `factor` into a `dim_x` by `dim_y` image,
+/// averaging the colors of the points in each pixel. Pixels without signal at any
+/// of their points get `no_signal_color`; in others, points without signal count
+/// as the bottom of the ramp.
+fn supersampled_to_pixels(
+    signal: &[f32],
+    pixels: &mut [u8],
+    dim_x: usize,
+    dim_y: usize,
+    factor: usize,
+    no_signal_color: [u8; 3],
+) {
+    for x in 0..dim_x {
+        for y in 0..dim_y {
+            // Averaged in linear light, before encoding.
+            let mut total = LinSrgb::new(0.0, 0.0, 0.0);
+            let mut covered = false;
+            for sub_x in (x * factor)..((x + 1) * factor) {
+                for sub_y in (y * factor)..((y + 1) * factor) {
+                    let point = signal[sub_x + sub_y * dim_x * factor];
+                    covered |= !is_no_signal(point);
+                    total += signal_to_linear(point);
+                }
+            }
+            let [r, g, b] = if covered {
+                linear_to_srgb8(total / (factor * factor) as f32)
+            } else {
+                no_signal_color
+            };
+            set_pixel(pixels, (dim_x, dim_y), (x, y), (r, g, b));
+        }
+    }
+}
+
+fn set_pixel(
+    pixels: &mut [u8],
+    (dim_x, dim_y): (usize, usize),
+    (x, y): (usize, usize),
+    (r, g, b): (u8, u8, u8),
+) {
+    let y = dim_y - y;
+    pixels[3 * x + 3 * y * dim_x] = r;
+    pixels[3 * x + 3 * y * dim_x + 1] = g;
+    pixels[3 * x + 3 * y * dim_x + 2] = b;
+}
+fn blend_pixel(
+    pixels: &mut [u8],
+    (dim_x, dim_y): (usize, usize),
+    (x, y): (usize, usize),
+    color: (u8, u8, u8),
+    alpha: f32,
+) {
+    let y = dim_y - y;
+    let base = 3 * x + 3 * y * dim_x;
+    for (channel, value) in [color.0, color.1, color.2].into_iter().enumerate() {
+        let old = pixels[base + channel] as f32;
+        pixels[base + channel] = (old + (value as f32 - old) * alpha).round() as u8;
+    }
+}
+
+// Offset from the beam center, as a fraction of the distance to the next beam,
+// at which the pattern in `rays_to` has dropped by 3dB.
+fn half_power_centricity() -> f32 {
+    (3.0 / BEAM_FALLOFF).sqrt()
+}
+
+const SECTOR_ALPHA: f32 = 0.3;
+
+/// Overlays a translucent wedge centered on `azimuth` (radians from +x towards +z,
+/// as in `rays_to`) spanning `beamwidth` radians out to `range`. `origin` and
+/// `range` are in meters, with pixels of `pixel_size` meters.
+fn draw_sector(
+    pixels: &mut [u8],
+    (dim_x, dim_y): (usize, usize),
+    pixel_size: (f32, f32),
+    origin: Point3D,
+    azimuth: f32,
+    beamwidth: f32,
+    range: f32,
+) {
+    let x_min = ((origin.x() - range) / pixel_size.0)
+        .floor()
+        .clamp(0.0, dim_x as f32) as usize;
+    let x_max = ((origin.x() + range) / pixel_size.0)
+        .ceil()
+        .clamp(0.0, dim_x as f32) as usize;
+    let y_min = ((origin.z() - range) / pixel_size.1)
+        .floor()
+        .clamp(0.0, dim_y as f32) as usize;
+    let y_max = ((origin.z() + range) / pixel_size.1)
+        .ceil()
+        .clamp(0.0, dim_y as f32) as usize;
+    for x in x_min..x_max {
+        for y in y_min..y_max {
+            let dx = (x as f32 + 0.5) * pixel_size.0 - origin.x();
+            let dz = (y as f32 + 0.5) * pixel_size.1 - origin.z();
+            if (dx * dx + dz * dz).sqrt() > range {
+                continue;
+            }
+            let offset = (dz.atan2(dx) - azimuth + std::f32::consts::PI)
+                .rem_euclid(2.0 * std::f32::consts::PI)
+                - std::f32::consts::PI;
+            if offset.abs() <= beamwidth / 2.0 {
+                blend_pixel(
+                    pixels,
+                    (dim_x, dim_y),
+                    (x, y),
+                    (255, 255, 255),
+                    SECTOR_ALPHA,
+                );
+            }
+        }
+    }
+}
+
+fn add_sectors(stations: &[Cube], scene: &Config, pixels: &mut [u8]) {
+    for station in stations {
+        if let Material::Light(l) = &station.material {
+            let range = Ray::new(station.origin, station.origin, l.strength, 0.0, l.frequency)
+                .distance_to_strength(scene.noise_floor);
+            let full_circle = 2.0 * std::f32::consts::PI;
+            for beam in l.beam_configs.iter() {
+                draw_sector(
+                    pixels,
+                    (scene.width, scene.height),
+                    scene.pixel_size(),
+                    scene.map_position(station.origin),
+                    beam.azimuth.to_radians(),
+                    full_circle * half_power_centricity(),
+                    range,
+                );
+            }
+            if !l.beam_configs.is_empty() {
+                continue;
+            }
+            let spacing = full_circle / l.beams as f32;
+            for beam in 0..l.beams {
+                draw_sector(
+                    pixels,
+                    (scene.width, scene.height),
+                    scene.pixel_size(),
+                    scene.map_position(station.origin),
+                    beam as f32 * spacing,
+                    spacing * half_power_centricity(),
+                    range,
+                );
+            }
+        }
+    }
+}
+
+fn draw_rectangle(
+    pixels: &mut [u8],
+    dim_x: usize,
+    dim_y: usize,
+    side_x: usize,
+    side_y: usize,
+    origin_x: usize,
+    origin_y: usize,
+) {
+    log::debug!(
+        "Drawing rectangle with origin ({},{}) and dimension ({},{})",
+        origin_x,
+        origin_y,
+        side_x,
+        side_x
+    );
+    for x in (origin_x - side_x)..(origin_x + side_x) {
+        for y in (origin_y - side_y)..(origin_y + side_y) {
+            set_pixel(pixels, (dim_x, dim_y), (x, y), (255, 0, 0));
+        }
+    }
+}
+fn write_floats(v: &[f32], f: &mut impl std::io::Write) -> std::io::Result<()> {
+    for float in v {
+        f.write_f32::<LittleEndian>(*float)?;
+    }
+    Ok(())
+}
+
+fn write_counts(v: &[u32], f: &mut impl std::io::Write) -> std::io::Result<()> {
+    for count in v {
+        f.write_u32::<LittleEndian>(*count)?;
+    }
+    Ok(())
+}
+
+/// Signal-to-noise ratio in dB of each point of a map in dBm, keeping `NO_SIGNAL`
+/// where there is no signal.
+pub fn snr_map(signals: &[f32], noise_dbm: f32) -> Vec<f32> {
+    signals
+        .iter()
+        .map(|s| {
+            if is_no_signal(*s) {
+                NO_SIGNAL
+            } else {
+                s - noise_dbm
+            }
+        })
+        .collect()
+}
+
+/// Splits a `dim_x` by `dim_y` grid into `tile_size` by `tile_size` tiles, each
+/// with its column and row: tile `(i, j)` holds points `i * tile_size` up to
+/// `(i + 1) * tile_size` along x and likewise along y, laid out like the grid with x
+/// running fastest. Tiles over the far edges are padded with `NO_SIGNAL`, so all
+/// have the same size.
+pub fn tile_grid(
+    signals: &[f32],
+    dim_x: usize,
+    dim_y: usize,
+    tile_size: usize,
+) -> Vec<((usize, usize), Vec<f32>)> {
+    let mut tiles = vec![];
+    for j in 0..dim_y.div_ceil(tile_size) {
+        for i in 0..dim_x.div_ceil(tile_size) {
+            let mut tile = vec![NO_SIGNAL; tile_size * tile_size];
+            for y in 0..tile_size.min(dim_y - j * tile_size) {
+                let start = i * tile_size + (j * tile_size + y) * dim_x;
+                let width = tile_size.min(dim_x - i * tile_size);
+                tile[y * tile_size..y * tile_size + width]
+                    .copy_from_slice(&signals[start..start + width]);
+            }
+            tiles.push(((i, j), tile));
+        }
+    }
+    tiles
+}
+
+/// Name of tile `(i, j)` of the map written to `<filename>.data`.
+fn tile_filename(filename: &str, (i, j): (usize, usize)) -> String {
+    format!("{}_tile_{}_{}.data", filename, i, j)
+}
+
+/// Writes `points`, positions with the strength in dBm recorded there, as an ascii
+/// PLY point cloud with a `dbm` property next to the coordinates.
+pub fn write_point_cloud(
+    out: &mut impl std::io::Write,
+    points: &[(Point3D, f32)],
+) -> std::io::Result<()> {
+    writeln!(out, "ply")?;
+    writeln!(out, "format ascii 1.0")?;
+    writeln!(out, "element vertex {}", points.len())?;
+    for property in ["x", "y", "z", "dbm"] {
+        writeln!(out, "property float {}", property)?;
+    }
+    writeln!(out, "end_header")?;
+    for (point, strength) in points {
+        writeln!(
+            out,
+            "{} {} {} {}",
+            point.x(),
+            point.y(),
+            point.z(),
+            strength
+        )?;
+    }
+    out.flush()
+}
+
+/// Reads a map written as `.data`.
+pub fn read_data(path: &Path) -> std::io::Result<Vec<f32>> {
+    Ok(std::fs::read(path)?
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect())
+}
+
+pub fn dbm_to_mw(dbm: f32) -> f32 {
+    10f32.powf(dbm / 10.0)
+}
+
+pub fn mw_to_dbm(mw: f32) -> f32 {
+    10.0 * mw.log10()
+}
+
+/// Writes signal strengths in dBm, or in mW if `linear` is set.
+fn write_signals(
+    signals: &[f32],
+    f: &mut impl std::io::Write,
+    linear: bool,
+) -> std::io::Result<()> {
+    if !linear {
+        return write_floats(signals, f);
+    }
+    let linear: Vec<f32> = signals
+        .iter()
+        .map(|s| if is_no_signal(*s) { 0.0 } else { dbm_to_mw(*s) })
+        .collect();
+    write_floats(&linear, f)
+}
+fn add_buildings(scene: &Config, pixels: &mut [u8]) {
+    let (size_x, size_z) = scene.pixel_size();
+    for ob in scene.objects.iter() {
+        if !matches!(ob.material, Material::Light(_)) && ob.origin.y() > 1.0 {
+            let origin = scene.map_position(ob.origin);
+            draw_rectangle(
+                pixels,
+                scene.width,
+                scene.height,
+                (ob.dim_x / size_x) as usize,
+                (ob.dim_z / size_z) as usize,
+                (origin.x() / size_x) as i64 as usize,
+                (origin.z() / size_z) as i64 as usize,
+            );
+        }
+    }
+}
+
+const STATION_COLOR: (u8, u8, u8) = (255, 255, 0);
+
+/// Top-down plan of the scene at the signal map's resolution, as rgb rows from
+/// the top like the map pngs: buildings in red, the stations' sectors and each
+/// station as a yellow square. Nothing is traced.
+pub fn render_plan(scene: &Config) -> Vec<u8> {
+    let pixels = plan_pixels(scene);
+    // Without the first row, which `set_pixel` leaves unused.
+    pixels[scene.width * 3..scene.width * (scene.height + 1) * 3].to_vec()
+}
+
+/// Writes `render_plan` to `filename`.
+pub fn write_plan(filename: &str, scene: &Config) -> Result<(), RaytracerError> {
+    write_image(
+        filename,
+        &plan_pixels(scene),
+        (scene.width, scene.height),
+        scene,
+    )?;
+    Ok(())
+}
+
+/// The plan laid out by `set_pixel`.
+fn plan_pixels(scene: &Config) -> Vec<u8> {
+    let (dim_x, dim_y) = (scene.width, scene.height);
+    let mut pixels: Vec<u8> = vec![0; (dim_x + 1) * (dim_y + 1) * 3];
+    add_buildings(scene, &mut pixels);
+    let stations = find_lights(scene);
+    add_sectors(&stations, scene, &mut pixels);
+    let (size_x, size_z) = scene.pixel_size();
+    for station in stations.iter() {
+        let origin = scene.map_position(station.origin);
+        let x = (origin.x() / size_x).floor() as i64;
+        let y = (origin.z() / size_z).floor() as i64;
+        for (px, py) in (x - 1..=x + 1).flat_map(|px| (y - 1..=y + 1).map(move |py| (px, py))) {
+            if (0..dim_x as i64).contains(&px) && (0..dim_y as i64).contains(&py) {
+                set_pixel(
+                    &mut pixels,
+                    (dim_x, dim_y),
+                    (px as usize, py as usize),
+                    STATION_COLOR,
+                );
+            }
+        }
+    }
+    pixels
+}
+
+/// Sets the points of the traced grid whose center lies outside `scene.mask` to
+/// `NO_SIGNAL`.
+fn apply_mask(signals: &mut [f32], scene: &Config) {
+    if scene.mask.is_none() {
+        return;
+    }
+    let dim_x = scene.width * scene.supersampling.max(1);
+    for (i, signal) in signals.iter_mut().enumerate() {
+        if masked_out(scene, i % dim_x, i / dim_x) {
+            *signal = NO_SIGNAL;
+        }
+    }
+}
+
+/// Whether point `(x, y)` of the traced grid lies outside the scene's mask.
+fn masked_out(scene: &Config, x: usize, y: usize) -> bool {
+    let (cell_x, cell_z) = grid_cell_size(scene);
+    let origin = scene.grid_origin;
+    scene.mask.as_ref().is_some_and(|mask| {
+        !mask.contains(
+            origin.x() + (x as f32 + 0.5) * cell_x,
+            origin.z() + (y as f32 + 0.5) * cell_z,
+        )
+    })
+}
+
+const HOMOGENIZATION_FALLOFF: f32 = 3.0;
+
+fn homogenize_signals(signals: &mut Vec<f32>, dim_x: usize) {
+    //This method finds places where there is no signal, and sets it to the value of a nearby point where there is.
+
+    //We cannot simply set a part of the signal map to another part in a parallel fashion.
+    //This may locally lead to some slight weirdness, but that's the price we pay.
+    let copied_signals = signals.clone();
+    signals.par_iter_mut().enumerate().for_each(|(i, s)| {
+        if (*s - NO_SIGNAL).abs() < f32::EPSILON {
+            //unwrapping: let coord = x + y * scene.width;
+            let y = i / dim_x;
+            let x = i % dim_x;
+
+            //We go out to 5 spaces away
+            'circles: for j in 1..=2 {
+                if x < j || x > dim_x - j || y < j || y > dim_x - j {
+                    //Don't go past the edge. This makes the edges a bit ragged.
+                    break;
+                }
+                //Select the lower left corner of the square around (x,y) at distance j
+                let mut x_ = x - j;
+                let mut y_ = y - j;
+                //Go right
+                for _ in 1..=(j + 1) {
+                    if copied_signals[x_ + y_ * dim_x] > NO_SIGNAL {
+                        *s = copied_signals[x_ + y_ * dim_x] - HOMOGENIZATION_FALLOFF * (j as f32);
+                        break 'circles;
+                    }
+                    x_ += 1;
+                }
+                //Go up
+                for _ in 1..=(j + 1) {
+                    if copied_signals[x_ + y_ * dim_x] > NO_SIGNAL {
+                        *s = copied_signals[x_ + y_ * dim_x] - HOMOGENIZATION_FALLOFF * (j as f32);
+                        break 'circles;
+                    }
+                    y_ += 1;
+                }
+                //Go left on top
+                for _ in 1..=(j + 1) {
+                    if copied_signals[x_ + y_ * dim_x] > NO_SIGNAL {
+                        *s = copied_signals[x_ + y_ * dim_x] - HOMOGENIZATION_FALLOFF * (j as f32);
+                        break 'circles;
+                    }
+                    x_ -= 1;
+                }
+                //Go down on the left
+                for _ in 1..=(j + 1) {
+                    if copied_signals[x_ + y_ * dim_x] > NO_SIGNAL {
+                        *s = copied_signals[x_ + y_ * dim_x] - HOMOGENIZATION_FALLOFF * (j as f32);
+                        break 'circles;
+                    }
+                    y_ -= 1;
+                }
+            }
+        }
+    });
+}
+
+/// Reduces a result supersampled by `factor` to `dim_x` by `dim_y` points, keeping
+/// the strongest point in each block, as if it had been traced at that size.
+fn reduce_supersampled(
+    fine: &SignalResult,
+    dim_x: usize,
+    dim_y: usize,
+    factor: usize,
+) -> SignalResult {
+    let grid_size = (dim_x + 1) * (dim_y + 1);
+    let mut reduced = SignalResult {
+        signals: vec![NO_SIGNAL; grid_size],
+        times: if fine.times.is_empty() {
+            vec![]
+        } else {
+            vec![0.0; grid_size]
+        },
+        angles: if fine.angles.is_empty() {
+            vec![]
+        } else {
+            vec![0.0; grid_size]
+        },
+        elevations: if fine.elevations.is_empty() {
+            vec![]
+        } else {
+            vec![0.0; grid_size]
+        },
+        reasons: if fine.reasons.is_empty() {
+            vec![]
+        } else {
+            vec![REASON_NEVER_HIT; grid_size]
+        },
+        counts: if fine.counts.is_empty() {
+            vec![]
+        } else {
+            vec![0; grid_size]
+        },
+        point_cloud: None,
+        delays: if fine.delays.is_empty() {
+            vec![]
+        } else {
+            vec![DelayMoments::default(); grid_size]
+        },
+        station: fine.station,
+        powers: vec![],
+    };
+    for x in 0..dim_x {
+        for y in 0..dim_y {
+            let coord = x + y * dim_x;
+            for sub_x in (x * factor)..((x + 1) * factor) {
+                for sub_y in (y * factor)..((y + 1) * factor) {
+                    let sub_coord = sub_x + sub_y * dim_x * factor;
+                    if !reduced.reasons.is_empty() {
+                        reduced.reasons[coord] =
+                            reduced.reasons[coord].max(fine.reasons[sub_coord]);
+                    }
+                    if !reduced.counts.is_empty() {
+                        reduced.counts[coord] += fine.counts[sub_coord];
+                    }
+                    if !reduced.delays.is_empty() {
+                        reduced.delays[coord].merge(&fine.delays[sub_coord]);
+                    }
+                    if fine.signals[sub_coord] > reduced.signals[coord] {
+                        reduced.signals[coord] = fine.signals[sub_coord];
+                        if !reduced.times.is_empty() {
+                            reduced.times[coord] = fine.times[sub_coord];
+                        }
+                        if !reduced.angles.is_empty() {
+                            reduced.angles[coord] = fine.angles[sub_coord];
+                            reduced.elevations[coord] = fine.elevations[sub_coord];
+                        }
+                    }
+                }
+            }
+        }
+    }
+    reduced
+}
+
+/// Size of a `dim_x` by `dim_y` map rescaled by `scale`.
+fn scaled_dims(dim_x: usize, dim_y: usize, scale: f32) -> (usize, usize) {
+    (
+        ((dim_x as f32 * scale).round() as usize).max(1),
+        ((dim_y as f32 * scale).round() as usize).max(1),
+    )
+}
+
+/// Resamples a `dim_x` by `dim_y` signal grid by `scale`, averaging the points
+/// with signal that fall into each output point. Returns the grid and its size.
+fn rescale_signals(
+    signals: &[f32],
+    dim_x: usize,
+    dim_y: usize,
+    scale: f32,
+) -> (Vec<f32>, usize, usize) {
+    let (new_x, new_y) = scaled_dims(dim_x, dim_y, scale);
+    let mut rescaled = vec![NO_SIGNAL; new_x * new_y];
+    for x in 0..new_x {
+        for y in 0..new_y {
+            let x_range = (x * dim_x / new_x)..((x + 1) * dim_x).div_ceil(new_x);
+            let y_range = (y * dim_y / new_y)..((y + 1) * dim_y).div_ceil(new_y);
+            let mut total = 0.0;
+            let mut count = 0;
+            for source_y in y_range {
+                for source_x in x_range.clone() {
+                    let value = signals[source_x + source_y * dim_x];
+                    if value > NO_SIGNAL {
+                        total += value;
+                        count += 1;
+                    }
+                }
+            }
+            if count > 0 {
+                rescaled[x + y * new_x] = total / count as f32;
+            }
+        }
+    }
+    (rescaled, new_x, new_y)
+}
+
+/// Base name, without extension, of the files written for `station`.
+fn station_filename(filename_base: &str, station: &Cube, options: &SigmapOptions) -> String {
+    let (_, _, freq) = station_params(station);
+    if options.subdirectories {
+        Path::new(filename_base)
+            .join((station.id as i32).to_string())
+            .join(freq.to_string())
+            .to_string_lossy()
+            .into_owned()
+    } else {
+        filename_base.to_string() + "_" + &(station.id as i32).to_string() + "_" + &freq.to_string()
+    }
+}
+
+/// Base name, without extension, of the combined map for `frequency`, or for all
+/// frequencies if `None`.
+pub fn combined_filename(
+    filename_base: &str,
+    frequency: Option<Frequency>,
+    options: &SigmapOptions,
+) -> String {
+    let suffix = match frequency {
+        Some(freq) => format!("combined_{}", freq),
+        None => "combined".to_string(),
+    };
+    shared_filename(filename_base, &suffix, options)
+}
+
+/// Base name of an output covering all stations, `<base>/<name>` with
+/// `options.subdirectories` and `<base>_<name>` otherwise.
+fn shared_filename(filename_base: &str, name: &str, options: &SigmapOptions) -> String {
+    if options.subdirectories {
+        Path::new(filename_base)
+            .join(name)
+            .to_string_lossy()
+            .into_owned()
+    } else {
+        filename_base.to_string() + "_" + name
+    }
+}
+
+/// All files `generate_sigmap` writes for this scene and these options.
+pub fn output_files(filename_base: &str, scene: &Config, options: &SigmapOptions) -> Vec<String> {
+    let stations = selected_stations(scene, options);
+    let mut files = Vec::new();
+    for station in stations.iter() {
+        let filename = station_filename(filename_base, station, options);
+        files.push(filename.clone() + ".data");
+        if scene.bandwidth_hz.is_some() {
+            files.push(filename.clone() + ".snr");
+        }
+        if let Some(tile_size) = options.tile_size {
+            for j in 0..scene.height.div_ceil(tile_size) {
+                for i in 0..scene.width.div_ceil(tile_size) {
+                    files.push(tile_filename(&filename, (i, j)));
+                }
+            }
+        }
+        let optional = [
+            (options.times, ".times"),
+            (options.angles, ".angles"),
+            (options.diagnose, ".reason"),
+            (options.counts, ".count"),
+            (options.delay_spread, ".ds"),
+            (options.png, ".png"),
+            (options.angle_png, "_angles.png"),
+            (options.time_png, "_times.png"),
+        ];
+        for (enabled, suffix) in optional {
+            if enabled {
+                files.push(filename.clone() + suffix);
+            }
+        }
+        for scale in scene.output_scales.iter() {
+            let (dim_x, dim_y) = scaled_dims(scene.width, scene.height, *scale);
+            let scaled_name = format!("{}_{}x{}", filename, dim_x, dim_y);
+            files.push(scaled_name.clone() + ".data");
+            if options.png {
+                files.push(scaled_name + ".png");
+            }
+        }
+    }
+    if let Some(path) = &options.point_cloud {
+        files.push(path.clone());
+    }
+    let mut frequencies: Vec<Option<Frequency>> = match options.combine {
+        None => vec![],
+        Some(Combine::Max) => vec![None],
+        Some(Combine::MaxPerFrequency) => {
+            stations.iter().map(|s| Some(station_params(s).2)).collect()
+        }
+    };
+    frequencies.sort();
+    frequencies.dedup();
+    for frequency in frequencies {
+        let filename = combined_filename(filename_base, frequency, options);
+        files.push(filename.clone() + ".data");
+        if options.png {
+            files.push(filename + ".png");
+        }
+    }
+    files
+}
+
+/// Traces every station and writes its maps, returning how long that took. With
+/// `options.no_clobber`, fails before tracing if any of the output files exists.
+pub fn generate_sigmap(
+    filename_base: &str,
+    scene: &Config,
+    options: &SigmapOptions,
+) -> Result<TimingReport, RaytracerError> {
+    generate_station_maps(filename_base, scene, options, false).map(|(_, timing)| timing)
+}
+
+/// What became of one scene of `generate_batch`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BatchOutcome {
+    Done,
+    /// All of its outputs were there already.
+    Skipped,
+    Failed(String),
+}
+
+/// Reads, migrates and validates the scene in `path`.
+fn load_scene(path: &Path) -> Result<Config, RaytracerError> {
+    let mut scene = Config::from_path(path)?;
+    scene.migrate()?;
+    scene.validate()?;
+    Ok(scene)
+}
+
+/// Generates the maps of every json, RON or YAML scene in `directory`, in name order, with
+/// `<output_directory>/<scene name>` as the output file name. Scenes whose outputs
+/// all exist are skipped, so an interrupted batch can simply be started again, and a
+/// scene that fails, even by panicking, does not stop the others.
+pub fn generate_batch(
+    directory: &Path,
+    output_directory: &Path,
+    options: &SigmapOptions,
+) -> Result<Vec<(String, BatchOutcome)>, RaytracerError> {
+    let mut scenes: Vec<_> = std::fs::read_dir(directory)
+        .map_err(|e| {
+            std::io::Error::new(
+                e.kind(),
+                format!("Unable to read {}: {}", directory.display(), e),
+            )
+        })?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.is_file()
+                && path
+                    .extension()
+                    .is_some_and(|e| ["json", "ron", "yaml", "yml"].iter().any(|x| e == *x))
+        })
+        .collect();
+    scenes.sort();
+    std::fs::create_dir_all(output_directory)?;
+    let mut outcomes = Vec::new();
+    for path in scenes {
+        let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+        let filename_base = output_directory.join(&name);
+        let filename_base = filename_base.to_str().ok_or_else(|| {
+            RaytracerError::Validation("Output path is not valid UTF-8".to_string())
+        })?;
+        let run = || -> Result<BatchOutcome, RaytracerError> {
+            let scene = load_scene(&path)?;
+            let outputs = output_files(filename_base, &scene, options);
+            if !outputs.is_empty() && outputs.iter().all(|f| Path::new(f).exists()) {
+                return Ok(BatchOutcome::Skipped);
+            }
+            generate_sigmap(filename_base, &scene, options)?;
+            Ok(BatchOutcome::Done)
+        };
+        let outcome = match std::panic::catch_unwind(std::panic::AssertUnwindSafe(run)) {
+            Ok(Ok(outcome)) => outcome,
+            Ok(Err(e)) => BatchOutcome::Failed(e.to_string()),
+            Err(panic) => BatchOutcome::Failed(
+                panic
+                    .downcast_ref::<&str>()
+                    .map(|s| s.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "panicked".to_string()),
+            ),
+        };
+        match &outcome {
+            BatchOutcome::Done => log::info!("{}: done", name),
+            BatchOutcome::Skipped => log::info!("{}: skipped, outputs exist", name),
+            BatchOutcome::Failed(e) => log::error!("{}: {}", name, e),
+        }
+        outcomes.push((name, outcome));
+    }
+    Ok(outcomes)
+}
+
+/// Traces `station` and finishes its map: filled in, masked and reduced to
+/// pixels. With supersampling, the grid before reducing comes along.
+fn station_signal(
+    station: &Cube,
+    scene: &Config,
+    options: &SigmapOptions,
+) -> (SignalResult, Option<Vec<f32>>) {
+    let factor = scene.supersampling.max(1);
+    let mut result = generate_signal(station, scene, options);
+    if !options.no_homogenize {
+        homogenize_signals(&mut result.signals, scene.width * factor);
+    }
+    apply_mask(&mut result.signals, scene);
+    if factor > 1 {
+        let fine_signals = result.signals.clone();
+        let mut reduced = reduce_supersampled(&result, scene.width, scene.height, factor);
+        reduced.point_cloud = result.point_cloud.take();
+        (reduced, Some(fine_signals))
+    } else {
+        (result, None)
+    }
+}
+
+/// Map of each station as (frequency, signals).
+type StationMaps = Vec<(Frequency, Vec<f32>)>;
+
+/// Does the work of `generate_sigmap`, also returning the map of each station
+/// if `keep_maps` is set.
+fn generate_station_maps(
+    filename_base: &str,
+    scene: &Config,
+    options: &SigmapOptions,
+    keep_maps: bool,
+) -> Result<(StationMaps, TimingReport), RaytracerError> {
+    scene.validate()?;
+    let stations = selected_stations(scene, options);
+    if stations.is_empty() {
+        return Err(no_stations_error(options));
+    }
+    if options.no_clobber {
+        if let Some(existing) = output_files(filename_base, scene, options)
+            .into_iter()
+            .find(|f| Path::new(f).exists())
+        {
+            return Err(already_exists_error(&existing));
+        }
+    }
+    let image_width = scene.width;
+    let image_height = scene.height;
+
+    let start = Instant::now();
+    // Station maps kept for combining.
+    let maps: Mutex<StationMaps> = Mutex::new(Vec::new());
+    let station_timings: Mutex<Vec<StationTiming>> = Mutex::new(Vec::new());
+    let point_cloud: Mutex<Vec<(Point3D, f32)>> = Mutex::new(Vec::new());
+    stations.par_iter().try_for_each(|s| {
+        //for s in stations.iter() {
+        let station_start = Instant::now();
+        let factor = scene.supersampling.max(1);
+        let (result, fine_signals) = station_signal(s, scene, options);
+        let SignalResult {
+            signals,
+            times,
+            angles,
+            reasons,
+            counts,
+            point_cloud: points,
+            delays,
+            ..
+        } = result;
+        if let Some(points) = points {
+            point_cloud.lock().unwrap().extend(points);
+        }
+        let (_, _, freq) = station_params(s);
+        let filename = station_filename(filename_base, s, options);
+        if options.subdirectories {
+            if let Some(directory) = Path::new(&filename).parent() {
+                std::fs::create_dir_all(directory)?;
+            }
+        }
+        //let s = serde_json::to_string(&signals).expect("Failed to serialize");
+        //std::fs::write(filename.clone() + ".json", &s).expect("Could not write to file");
+        let mut signals_file = create_output(filename.clone() + ".data")?;
+        write_signals(&signals, &mut signals_file, options.linear)?;
+        if options.times {
+            let mut times_file = create_output(filename.clone() + ".times")?;
+
+            write_floats(&times, &mut times_file)?;
+        }
+        if options.angles {
+            let mut angles_file = create_output(filename.clone() + ".angles")?;
+            let angles: Vec<f32> = if options.angle_degrees {
+                angles.iter().map(|a| a.to_degrees()).collect()
+            } else {
+                angles.clone()
+            };
+
+            write_floats(&angles, &mut angles_file)?;
+        }
+        if options.diagnose {
+            std::fs::write(filename.clone() + ".reason", &reasons)?;
+        }
+        if let Some(noise) = scene.noise_dbm() {
+            let mut snr_file = create_output(filename.clone() + ".snr")?;
+            write_floats(&snr_map(&signals, noise), &mut snr_file)?;
+        }
+        if let Some(tile_size) = options.tile_size {
+            for (index, tile) in tile_grid(&signals, image_width, image_height, tile_size) {
+                let mut tile_file = create_output(tile_filename(&filename, index))?;
+                write_signals(&tile, &mut tile_file, options.linear)?;
+            }
+        }
+        if options.counts {
+            let mut counts_file = create_output(filename.clone() + ".count")?;
+            write_counts(&counts, &mut counts_file)?;
+        }
+        if options.delay_spread {
+            let spreads: Vec<f32> = delays.iter().map(DelayMoments::spread).collect();
+            let mut ds_file = create_output(filename.clone() + ".ds")?;
+            write_floats(&spreads, &mut ds_file)?;
+        }
+        if options.png {
+            let mut pixels: Vec<u8> = vec![0; (image_width + 1) * (image_height + 1) * 3];
+            match &fine_signals {
+                Some(fine_signals) => supersampled_to_pixels(
+                    fine_signals,
+                    &mut pixels,
+                    image_width,
+                    image_height,
+                    factor,
+                    scene.no_signal_color,
+                ),
+                None => signal_to_pixels(
+                    &signals,
+                    None,
+                    &mut pixels,
+                    image_width,
+                    image_height,
+                    scene.no_signal_color,
+                ),
+            }
+            add_buildings(scene, &mut pixels);
+            if options.sectors {
+                add_sectors(&stations, scene, &mut pixels);
+            }
+            write_image(
+                &(filename.clone() + ".png"),
+                &pixels,
+                (image_width, image_height),
+                scene,
+            )?;
+        }
+        if options.angle_png {
+            let mut pixels: Vec<u8> = vec![0; (image_width + 1) * (image_height + 1) * 3];
+            signal_to_pixels(
+                &signals,
+                Some(&angles),
+                &mut pixels,
+                image_width,
+                image_height,
+                scene.no_signal_color,
+            );
+            write_image(
+                &(filename.clone() + "_angles.png"),
+                &pixels,
+                (image_width, image_height),
+                scene,
+            )?;
+        }
+        if options.time_png {
+            let mut pixels: Vec<u8> = vec![0; (image_width + 1) * (image_height + 1) * 3];
+            delays_to_pixels(
+                &signals,
+                &times,
+                scene.max_delay,
+                &mut pixels,
+                image_width,
+                image_height,
+            );
+            write_image(
+                &(filename.clone() + "_times.png"),
+                &pixels,
+                (image_width, image_height),
+                scene,
+            )?;
+        }
+        for scale in scene.output_scales.iter() {
+            let (rescaled, dim_x, dim_y) =
+                rescale_signals(&signals, image_width, image_height, *scale);
+            let scaled_name = format!("{}_{}x{}", filename, dim_x, dim_y);
+            let mut scaled_file = create_output(scaled_name.clone() + ".data")?;
+            write_signals(&rescaled, &mut scaled_file, options.linear)?;
+            if options.png {
+                let mut pixels: Vec<u8> = vec![0; (dim_x + 1) * (dim_y + 1) * 3];
+                signal_to_pixels(
+                    &rescaled,
+                    None,
+                    &mut pixels,
+                    dim_x,
+                    dim_y,
+                    scene.no_signal_color,
+                );
+                write_image(&(scaled_name + ".png"), &pixels, (dim_x, dim_y), scene)?;
+            }
+        }
+        if keep_maps || options.combine.is_some() {
+            maps.lock().unwrap().push((freq, signals));
+        }
+        station_timings.lock().unwrap().push(StationTiming {
+            id: s.id,
+            frequency: freq,
+            seconds: station_start.elapsed().as_secs_f64(),
+        });
+        Ok::<(), RaytracerError>(())
+    })?;
+    let maps = maps.into_inner().unwrap();
+    if let Some(path) = &options.point_cloud {
+        let file = create_output(path.clone())?;
+        write_point_cloud(
+            &mut std::io::BufWriter::new(file),
+            &point_cloud.into_inner().unwrap(),
+        )?;
+    }
+    if let Some(combine) = options.combine {
+        let mut frequencies: Vec<Option<Frequency>> = match combine {
+            Combine::Max => vec![None],
+            Combine::MaxPerFrequency => maps.iter().map(|(freq, _)| Some(*freq)).collect(),
+        };
+        frequencies.sort();
+        frequencies.dedup();
+        for frequency in frequencies {
+            let group: Vec<&[f32]> = maps
+                .iter()
+                .filter(|(freq, _)| frequency.is_none_or(|f| f == *freq))
+                .map(|(_, signals)| signals.as_slice())
+                .collect();
+            let filename = combined_filename(filename_base, frequency, options);
+            write_combined(&filename, &combine_max(&group), &stations, scene, options)?;
+        }
+    }
+    let elapsed = start.elapsed();
+    log::info!("Frame time: {}ms", elapsed.as_millis());
+    let mut station_timings = station_timings.into_inner().unwrap();
+    station_timings.sort_by_key(|t| t.id);
+    Ok((maps, TimingReport::new(elapsed, station_timings)))
+}
+
+fn already_exists_error(path: &str) -> RaytracerError {
+    RaytracerError::Io(std::io::Error::new(
+        std::io::ErrorKind::AlreadyExists,
+        format!("{} already exists", path),
+    ))
+}
+
+fn no_stations_error(options: &SigmapOptions) -> RaytracerError {
+    RaytracerError::Validation(if options.stations.is_empty() {
+        "scene contains no Material::Light objects".to_string()
+    } else {
+        format!("scene contains none of the stations {:?}", options.stations)
+    })
+}
+
+/// Writes the `.data` file of each selected station with `stream_signal`, tracing the
+/// stations one after the other. Only `subdirectories`, `no_clobber`, `linear` and `stations`
+/// of `options` apply.
+pub fn stream_sigmap(
+    filename_base: &str,
+    scene: &Config,
+    options: &SigmapOptions,
+) -> Result<TimingReport, RaytracerError> {
+    scene.validate()?;
+    let stations = selected_stations(scene, options);
+    if stations.is_empty() {
+        return Err(no_stations_error(options));
+    }
+    check_streamable(scene)?;
+    let filenames: Vec<String> = stations
+        .iter()
+        .map(|s| station_filename(filename_base, s, options) + ".data")
+        .collect();
+    if options.no_clobber {
+        if let Some(existing) = filenames.iter().find(|f| Path::new(f).exists()) {
+            return Err(already_exists_error(existing));
+        }
+    }
+    let start = Instant::now();
+    let mut station_timings = Vec::new();
+    for (station, filename) in stations.iter().zip(filenames) {
+        let station_start = Instant::now();
+        if let Some(directory) = Path::new(&filename).parent() {
+            std::fs::create_dir_all(directory)?;
+        }
+        let mut out = std::io::BufWriter::new(create_output(filename)?);
+        stream_signal(station, scene, &mut out, options.linear)?;
+        std::io::Write::flush(&mut out)?;
+        station_timings.push(StationTiming {
+            id: station.id,
+            frequency: station_params(station).2,
+            seconds: station_start.elapsed().as_secs_f64(),
+        });
+    }
+    Ok(TimingReport::new(start.elapsed(), station_timings))
+}
+
+/// Runs `generate_sigmap` once for each of `frequencies`, with every station
+/// set to that frequency. The frequency is part of the output names, so each
+/// run writes its own set of files. The timing has an entry for each station on
+/// each frequency.
+pub fn sweep_frequencies(
+    filename_base: &str,
+    scene: &mut Config,
+    frequencies: &[Frequency],
+    options: &SigmapOptions,
+) -> Result<TimingReport, RaytracerError> {
+    scene.validate()?;
+    if selected_stations(scene, options).is_empty() {
+        return Err(no_stations_error(options));
+    }
+    if options.no_clobber {
+        // Check all runs up front rather than failing halfway through the sweep.
+        for frequency in frequencies {
+            scene.set_frequency(*frequency);
+            if let Some(existing) = output_files(filename_base, scene, options)
+                .into_iter()
+                .find(|f| Path::new(f).exists())
+            {
+                return Err(already_exists_error(&existing));
+            }
+        }
+        if let Some(existing) = best_band_files(filename_base, options)
+            .into_iter()
+            .find(|f| Path::new(f).exists())
+        {
+            return Err(already_exists_error(&existing));
+        }
+    }
+    let start = Instant::now();
+    let mut station_timings = Vec::new();
+    // Strongest signal over all stations on each frequency, for the best band.
+    let mut bands: Vec<(Frequency, Vec<f32>)> = Vec::new();
+    for frequency in frequencies {
+        log::info!("Sweeping {} MHz", frequency);
+        scene.set_frequency(*frequency);
+        let (maps, timing) =
+            generate_station_maps(filename_base, scene, options, options.best_band)?;
+        station_timings.extend(timing.stations);
+        if options.best_band {
+            let signals: Vec<&[f32]> = maps.iter().map(|(_, signals)| signals.as_slice()).collect();
+            bands.push((*frequency, combine_max(&signals)));
+        }
+    }
+    if options.best_band {
+        let (frequencies, signals) = best_band(&bands);
+        let [frequency_name, strength_name] = best_band_files(filename_base, options);
+        let mut file = create_output(frequency_name)?;
+        write_floats(&frequencies, &mut file)?;
+        let mut file = create_output(strength_name)?;
+        write_signals(&signals, &mut file, options.linear)?;
+    }
+    Ok(TimingReport::new(start.elapsed(), station_timings))
+}
+
+/// Per-point statistics over the runs of `monte_carlo`, in dBm.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MonteCarloMaps {
+    pub mean: Vec<f32>,
+    // Strength exceeded in 90% of the runs.
+    pub p10: Vec<f32>,
+}
+
+/// Traces the scene `runs` times with each station moved by a random offset with
+/// a standard deviation of `sigma` meters along each axis, and gathers the
+/// strongest signal over the stations per run into per-point statistics.
+pub fn monte_carlo(
+    scene: &Config,
+    runs: usize,
+    sigma: f32,
+    options: &SigmapOptions,
+) -> Result<MonteCarloMaps, RaytracerError> {
+    scene.validate()?;
+    let stations = selected_stations(scene, options);
+    if stations.is_empty() {
+        return Err(no_stations_error(options));
+    }
+    if runs == 0 {
+        return Err(RaytracerError::Validation(
+            "Monte Carlo needs at least one run".to_string(),
+        ));
+    }
+    let mut rng = rand::thread_rng();
+    let mut run_maps: Vec<Vec<f32>> = Vec::with_capacity(runs);
+    for run in 0..runs {
+        log::debug!("Monte Carlo run {} of {}", run + 1, runs);
+        let jittered: Vec<Cube> = stations
+            .iter()
+            .map(|station| {
+                let mut offset = || sigma * rng.sample::<f32, _>(StandardNormal);
+                let mut station = station.clone();
+                station.origin = station.origin + Point3D::new(offset(), offset(), offset());
+                station
+            })
+            .collect();
+        let maps: Vec<Vec<f32>> = jittered
+            .par_iter()
+            .map(|station| station_signal(station, scene, options).0.signals)
+            .collect();
+        let maps: Vec<&[f32]> = maps.iter().map(|m| m.as_slice()).collect();
+        run_maps.push(combine_max(&maps));
+    }
+    let size = run_maps[0].len();
+    let mut mean = vec![0.0; size];
+    let mut p10 = vec![0.0; size];
+    let mut values = vec![0.0; runs];
+    for i in 0..size {
+        for (value, map) in values.iter_mut().zip(run_maps.iter()) {
+            *value = map[i];
+        }
+        mean[i] = values.iter().sum::<f32>() / runs as f32;
+        values.sort_by(|a, b| a.total_cmp(b));
+        p10[i] = values[(runs - 1) / 10];
+    }
+    Ok(MonteCarloMaps { mean, p10 })
+}
+
+/// Runs `monte_carlo` and writes the mean and 10th percentile maps to
+/// `<base>_mc_mean.*` and `<base>_mc_p10.*`, returning how long that took.
+pub fn generate_monte_carlo(
+    filename_base: &str,
+    scene: &Config,
+    runs: usize,
+    sigma: f32,
+    options: &SigmapOptions,
+) -> Result<TimingReport, RaytracerError> {
+    let start = Instant::now();
+    let filenames = ["mc_mean", "mc_p10"].map(|name| shared_filename(filename_base, name, options));
+    if options.no_clobber {
+        // Both maps come from the same runs, so check them before tracing any.
+        if let Some(existing) = filenames
+            .iter()
+            .find(|f| Path::new(&(f.to_string() + ".data")).exists())
+        {
+            return Err(already_exists_error(&(existing.to_string() + ".data")));
+        }
+    }
+    let maps = monte_carlo(scene, runs, sigma, options)?;
+    for (filename, signals) in filenames.into_iter().zip([&maps.mean, &maps.p10]) {
+        if let Some(directory) = Path::new(&filename).parent() {
+            std::fs::create_dir_all(directory)?;
+        }
+        let mut file = create_output(filename.clone() + ".data")?;
+        write_signals(signals, &mut file, options.linear)?;
+        if options.png {
+            let (dim_x, dim_y) = (scene.width, scene.height);
+            let mut pixels: Vec<u8> = vec![0; (dim_x + 1) * (dim_y + 1) * 3];
+            signal_to_pixels(
+                signals,
+                None,
+                &mut pixels,
+                dim_x,
+                dim_y,
+                scene.no_signal_color,
+            );
+            add_buildings(scene, &mut pixels);
+            write_image(&(filename + ".png"), &pixels, (dim_x, dim_y), scene)?;
+        }
+    }
+    Ok(TimingReport::new(start.elapsed(), Vec::new()))
+}
+
+/// The best band map, with the frequency of the strongest band, and its strength.
+fn best_band_files(filename_base: &str, options: &SigmapOptions) -> [String; 2] {
+    let base = shared_filename(filename_base, "bestband", options);
+    [base.clone() + ".data", base + "_strength.data"]
+}
+
+/// Per point, the frequency in MHz of the strongest of `bands` and its signal.
+/// Points no band reaches get frequency 0.
+fn best_band(bands: &[(Frequency, Vec<f32>)]) -> (Vec<f32>, Vec<f32>) {
+    let size = bands.first().map_or(0, |(_, signals)| signals.len());
+    let mut frequencies = vec![0.0; size];
+    let mut best = vec![NO_SIGNAL; size];
+    for (frequency, signals) in bands {
+        for (i, signal) in signals.iter().enumerate() {
+            if *signal > best[i] {
+                best[i] = *signal;
+                frequencies[i] = frequency.mhz() as f32;
+            }
+        }
+    }
+    (frequencies, best)
+}
+
+/// Per-pixel maximum over equally sized signal maps.
+fn combine_max(maps: &[&[f32]]) -> Vec<f32> {
+    let mut combined = maps.first().map(|m| m.to_vec()).unwrap_or_default();
+    for map in maps.iter().skip(1) {
+        for (c, s) in combined.iter_mut().zip(map.iter()) {
+            *c = c.max(*s);
+        }
+    }
+    combined
+}
+
+fn write_combined(
+    filename: &str,
+    signals: &[f32],
+    stations: &[Cube],
+    scene: &Config,
+    options: &SigmapOptions,
+) -> std::io::Result<()> {
+    let (image_width, image_height) = (scene.width, scene.height);
+    let mut signals_file = create_output(filename.to_string() + ".data")?;
+    write_signals(signals, &mut signals_file, options.linear)?;
+    if options.png {
+        let mut pixels: Vec<u8> = vec![0; (image_width + 1) * (image_height + 1) * 3];
+        signal_to_pixels(
+            signals,
+            None,
+            &mut pixels,
+            image_width,
+            image_height,
+            scene.no_signal_color,
+        );
+        add_buildings(scene, &mut pixels);
+        if options.sectors {
+            add_sectors(stations, scene, &mut pixels);
+        }
+        write_image(
+            &(filename.to_string() + ".png"),
+            &pixels,
+            (image_width, image_height),
+            scene,
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+use crate::config::test_config;
+#[cfg(test)]
+use crate::materials::{Lambertian, Light, Metal, Wall};
+#[cfg(test)]
+use assert_approx_eq::assert_approx_eq;
+
+#[cfg(test)]
+fn test_ground(width: f32, height: f32) -> Cube {
+    Cube::new(
+        Point3D::new(width / 2.0, 0.0, height / 2.0),
+        width / 2.0,
+        0.0,
+        height / 2.0,
+        Material::Lambertian(Lambertian::new(Srgb::new(0.5, 0.5, 0.5))),
+        0,
+    )
+}
+
+#[cfg(test)]
+fn test_station(origin: Point3D, strength: f32, frequency: Frequency) -> Cube {
+    Cube::new(
+        origin,
+        0.5,
+        0.5,
+        0.5,
+        Material::Light(Light::new(Srgb::new(1.0, 1.0, 1.0), strength, 1, frequency)),
+        1,
+    )
+}
+
+#[test]
+fn test_ray_counts_per_point() {
+    // A flat open ground, with the station high above its center so the rays fall
+    // steeply everywhere.
+    let station = test_station(
+        Point3D::new(10.0, 30.0, 10.0),
+        30.0,
+        Frequency::from_mhz(2000.0),
+    );
+    let scene = test_config(20, 20, vec![test_ground(20.0, 20.0), station.clone()]);
+    let options = SigmapOptions {
+        counts: true,
+        ..Default::default()
+    };
+    let result = generate_signal(&station, &scene, &options);
+    let inner: Vec<u32> = (5..15)
+        .flat_map(|y| (5..15).map(move |x| x + y * 20))
+        .map(|coord| result.counts[coord])
+        .collect();
+    let (min, max) = (inner.iter().min().unwrap(), inner.iter().max().unwrap());
+    assert!(*min > 0);
+    assert!(*max <= 2 * *min, "counts from {} to {}", min, max);
+    assert!(generate_signal(&station, &scene, &SigmapOptions::default())
+        .counts
+        .is_empty());
+}
+
+#[test]
+fn test_coverage_stats() {
+    let mut scene = test_config(20, 10, vec![]);
+    scene.world_width = 40.0;
+    scene.world_height = 20.0;
+    assert_eq!(scene.pixel_size(), (2.0, 2.0));
+    // 100 of the 200 points above -90 dBm, 50 below it and 50 without signal, with
+    // the extra row and column of the .data layout.
+    let mut signals = vec![NO_SIGNAL; 21 * 11];
+    for (i, signal) in signals.iter_mut().take(200).enumerate() {
+        *signal = match i % 4 {
+            0 | 1 => -80.0,
+            2 => -100.0,
+            _ => NO_SIGNAL,
+        };
+    }
+    let stats = coverage_stats(&signals, &scene, -90.0).unwrap();
+    assert_eq!(stats.served, 100);
+    assert_eq!(stats.total, 200);
+    assert_eq!(stats.percentage, 50.0);
+    assert_eq!(stats.served_area, 400.0);
+    let stats = coverage_stats(&signals, &scene, -120.0).unwrap();
+    assert_eq!(stats.served_area, 600.0);
+    // A map from a smaller scene does not fit.
+    assert!(matches!(
+        coverage_stats(&signals[..150], &scene, -90.0),
+        Err(RaytracerError::Geometry(_))
+    ));
+}
+
+#[test]
+fn test_delay_spread() {
+    let tap = |delay, strength| Tap { delay, strength };
+    // Two taps of equal power lie 10 ns either side of their mean.
+    assert_approx_eq!(
+        delay_spread(&[tap(10.0, -60.0), tap(30.0, -60.0)]),
+        10.0,
+        1e-3
+    );
+    // A tap 10 dB weaker counts for a tenth, pulling the mean only 20/11 ns away from
+    // the strong one.
+    let spread = delay_spread(&[tap(10.0, -60.0), tap(30.0, -70.0)]);
+    assert_approx_eq!(spread, 20.0 * 10f32.sqrt() / 11.0, 1e-3);
+    assert_eq!(delay_spread(&[tap(50.0, -60.0)]), 0.0);
+    assert_eq!(delay_spread(&[]), 0.0);
+}
+
+#[test]
+fn test_delay_spread_direct_path() {
+    // Only the direct path, though the rays for a point land up to a meter apart.
+    let station = test_station(
+        Point3D::new(2.0, 10.0, 2.0),
+        30.0,
+        Frequency::from_mhz(2000.0),
+    );
+    let mut scene = test_config(20, 20, vec![test_ground(20.0, 20.0), station.clone()]);
+    scene.max_depth = 1;
+    scene.rays_per_target = 3;
+    let options = SigmapOptions {
+        delay_spread: true,
+        ..Default::default()
+    };
+    let result = generate_signal(&station, &scene, &options);
+    assert!(result.delays.iter().any(|d| d.power > 0.0));
+    for moments in result.delays.iter() {
+        assert!(moments.spread() < 0.01, "{} ns", moments.spread());
+    }
+}
+
+#[test]
+fn test_snr() {
+    let mut scene = test_config(2, 1, vec![]);
+    assert_eq!(scene.noise_dbm(), None);
+    scene.bandwidth_hz = Some(10e6);
+    scene.receiver_noise_figure_db = 7.0;
+    // -174 dBm/Hz, plus 70 dB for 10 MHz.
+    let narrow = scene.noise_dbm().unwrap();
+    assert_approx_eq!(narrow, -174.0 + 70.0 + 7.0, 0.1);
+    scene.bandwidth_hz = Some(20e6);
+    let wide = scene.noise_dbm().unwrap();
+    assert_approx_eq!(wide - narrow, 3.0103, 1e-3);
+    let signals = [-80.0, NO_SIGNAL];
+    let (narrow_snr, wide_snr) = (snr_map(&signals, narrow), snr_map(&signals, wide));
+    assert_approx_eq!(narrow_snr[0], -80.0 - narrow, 1e-4);
+    assert_approx_eq!(narrow_snr[0] - wide_snr[0], 3.0103, 1e-3);
+    assert_eq!(wide_snr[1], NO_SIGNAL);
+}
+
+#[test]
+fn test_station_objects() {
+    // A block 5 km away, beyond the reach of a weak station but not a strong one.
+    let far = Cube::new(
+        Point3D::new(5000.0, 10.0, 10.0),
+        5.0,
+        10.0,
+        5.0,
+        Material::Metal(Metal::new(Srgb::new(0.5, 0.5, 0.5), 0.0, 5.0)),
+        2,
+    );
+    let weak = test_station(
+        Point3D::new(10.0, 10.0, 10.0),
+        -20.0,
+        Frequency::from_mhz(2000.0),
+    );
+    let scene = test_config(20, 20, vec![test_ground(20.0, 20.0), far, weak.clone()]);
+    assert!(useful_range(&weak, &scene) < 5000.0);
+    let ids = |station: &Cube| -> Vec<i64> {
+        station_objects(station, &scene)
+            .iter()
+            .map(|o| o.id)
+            .collect()
+    };
+    assert_eq!(ids(&weak), vec![0]);
+    let strong = test_station(
+        Point3D::new(10.0, 10.0, 10.0),
+        40.0,
+        Frequency::from_mhz(2000.0),
+    );
+    assert_eq!(ids(&strong), vec![0, 2]);
+}
+
+#[test]
+fn test_precull_matches_uncluttered() {
+    // A metal wall across the far end of a mirror ground, beyond the range of a weak
+    // station. Rays aimed past it would reflect back onto the map below the noise floor.
+    let mirror = Material::Metal(Metal::new(Srgb::new(0.5, 0.5, 0.5), 0.0, 0.0));
+    let ground = Cube::new(
+        Point3D::new(20.0, 0.0, 5.0),
+        20.0,
+        0.0,
+        5.0,
+        mirror.clone(),
+        0,
+    );
+    let wall = Cube::new(Point3D::new(30.0, 5.0, 5.0), 0.5, 5.0, 5.0, mirror, 2);
+    let station = test_station(
+        Point3D::new(5.0, 3.0, 5.0),
+        -48.0,
+        Frequency::from_mhz(2000.0),
+    );
+    let mut uncluttered = test_config(40, 10, vec![ground.clone(), station.clone()]);
+    uncluttered.max_depth = 3;
+    let mut cluttered = test_config(40, 10, vec![ground, wall, station.clone()]);
+    cluttered.max_depth = 3;
+    assert!(useful_range(&station, &cluttered) < 20.0);
+    assert_eq!(station_objects(&station, &cluttered).len(), 1);
+    let options = SigmapOptions::default();
+    let expected = generate_signal(&station, &uncluttered, &options).signals;
+    assert_eq!(
+        generate_signal(&station, &cluttered, &options).signals,
+        expected
+    );
+    // Tracing the wall as well changes nothing either.
+    let all = signal_objects(&cluttered);
+    assert_eq!(all.len(), 2);
+    assert_eq!(
+        trace_station(&station, &cluttered, &all, &options).signals,
+        expected
+    );
+    assert!(expected.iter().any(|s| !is_no_signal(*s)));
+    // Nothing is recorded beyond the range, not even below the noise floor.
+    assert!(is_no_signal(expected[35 + 5 * 40]));
+}
+
+#[test]
+fn test_point_cloud() {
+    let path = std::env::temp_dir().join(format!("sigmap_point_cloud_{}.ply", std::process::id()));
+    let station = test_station(
+        Point3D::new(5.0, 10.0, 5.0),
+        30.0,
+        Frequency::from_mhz(2000.0),
+    );
+    let scene = test_config(10, 10, vec![test_ground(10.0, 10.0), station]);
+    let base = path.with_extension("");
+    let options = SigmapOptions {
+        point_cloud: Some(path.to_str().unwrap().to_string()),
+        ..Default::default()
+    };
+    generate_sigmap(base.to_str().unwrap(), &scene, &options).unwrap();
+    let ply = std::fs::read_to_string(&path).unwrap();
+    let (header, body) = ply.split_once("end_header\n").unwrap();
+    assert!(header.starts_with("ply\nformat ascii 1.0\n"));
+    let count: usize = header
+        .lines()
+        .find_map(|line| line.strip_prefix("element vertex "))
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(count > 0);
+    assert_eq!(body.lines().count(), count);
+    for line in body.lines() {
+        let values: Vec<f32> = line.split(' ').map(|v| v.parse().unwrap()).collect();
+        assert_eq!(values.len(), 4);
+        // Ground hits, on the map.
+        assert!(values[1].abs() < 0.1);
+        assert!((0.0..=10.0).contains(&values[0]) && (0.0..=10.0).contains(&values[2]));
+    }
+    for file in output_files(base.to_str().unwrap(), &scene, &options) {
+        std::fs::remove_file(file).unwrap();
+    }
+}
+
+#[test]
+fn test_tile_grid() {
+    let (dim_x, dim_y) = (512, 512);
+    let signals: Vec<f32> = (0..dim_x * dim_y).map(|i| -(i as f32) / 1000.0).collect();
+    let tiles = tile_grid(&signals, dim_x, dim_y, 256);
+    let indices: Vec<(usize, usize)> = tiles.iter().map(|(index, _)| *index).collect();
+    assert_eq!(indices, vec![(0, 0), (1, 0), (0, 1), (1, 1)]);
+    let mut reassembled = vec![0.0; dim_x * dim_y];
+    for ((i, j), tile) in tiles.iter() {
+        assert_eq!(tile.len(), 256 * 256);
+        for y in 0..256 {
+            for x in 0..256 {
+                reassembled[i * 256 + x + (j * 256 + y) * dim_x] = tile[x + y * 256];
+            }
+        }
+    }
+    assert_eq!(reassembled, signals);
+    // Tiles over the edge are padded.
+    let tiles = tile_grid(&signals[..6], 3, 2, 2);
+    assert_eq!(tiles.len(), 2);
+    assert_eq!(
+        tiles[1].1,
+        vec![signals[2], NO_SIGNAL, signals[5], NO_SIGNAL]
+    );
+}
+
+#[test]
+fn test_bounce_budget() {
+    use crate::config::BounceBudget;
+    use crate::materials::Glass;
+    // Three panes of glass that bend nothing above the ground, and a ray straight down
+    // through them, which reaches the ground on its seventh bounce.
+    let panes: Vec<Cube> = (0..3)
+        .map(|i| {
+            Cube::new(
+                Point3D::new(5.0, 2.0 + 2.0 * i as f32, 5.0),
+                5.0,
+                0.1,
+                5.0,
+                Material::Glass(Glass::new(1.0)),
+                i + 1,
+            )
+        })
+        .collect();
+    let mut objects = vec![test_ground(10.0, 10.0)];
+    objects.extend(panes);
+    let mut scene = test_config(10, 10, objects);
+    scene.max_depth = 10;
+    scene.bounce_budget = BounceBudget {
+        lambertian: Some(2),
+        ..Default::default()
+    };
+    let ray = Ray::new(
+        Point3D::new(5.5, 10.0, 5.5),
+        Point3D::new(0.0, -1.0, 0.0),
+        30.0,
+        0.0,
+        Frequency::from_mhz(2000.0),
+    );
+    let trace = |scene: &Config| {
+        let objects = signal_objects(scene);
+        let mut recorded = vec![];
+        let hits = trace_signal_ray(ray, &objects, scene, &mut |coord, _, _, _| {
+            recorded.push(coord)
+        });
+        (hits, recorded)
+    };
+    // The glass chain goes past the diffuse budget, but the ground ends it.
+    assert_eq!(trace(&scene), (7, vec![5 + 5 * 10]));
+    // Now the ray stops at the fourth glass surface it hits.
+    scene.bounce_budget.glass = Some(3);
+    assert_eq!(trace(&scene), (4, vec![]));
+}
+
+#[test]
+fn test_diagnose_shadow() {
+    // A tall wall at x in [8, 10] hides everything behind it from the station.
+    let wall = Cube::new(
+        Point3D::new(9.0, 20.0, 10.0),
+        1.0,
+        20.0,
+        10.0,
+        Material::Metal(Metal::new(Srgb::new(0.5, 0.5, 0.5), 0.0, 5.0)),
+        2,
+    );
+    let station = test_station(
+        Point3D::new(2.0, 10.0, 10.0),
+        30.0,
+        Frequency::from_mhz(2000.0),
+    );
+    let scene = test_config(20, 20, vec![test_ground(20.0, 20.0), wall, station.clone()]);
+    let options = SigmapOptions {
+        diagnose: true,
+        ..Default::default()
+    };
+    let result = generate_signal(&station, &scene, &options);
+    for x in 11..20 {
+        for y in 0..20 {
+            assert_eq!(
+                result.reasons[x + y * 20],
+                REASON_NEVER_HIT,
+                "({}, {})",
+                x,
+                y
+            );
+        }
+    }
+    assert_eq!(result.reasons[3 + 10 * 20], REASON_SERVED);
+}
+
+#[test]
+fn test_draw_sector_angular_range() {
+    let (dim_x, dim_y) = (40, 40);
+    let mut pixels = vec![0; (dim_x + 1) * (dim_y + 1) * 3];
+    let origin = Point3D::new(20.0, 10.0, 20.0);
+    let azimuth = std::f32::consts::FRAC_PI_4;
+    let beamwidth = 0.5;
+    draw_sector(
+        &mut pixels,
+        (dim_x, dim_y),
+        (1.0, 1.0),
+        origin,
+        azimuth,
+        beamwidth,
+        15.0,
+    );
+    let mut drawn = 0;
+    for x in 0..dim_x {
+        for y in 0..dim_y {
+            if pixels[3 * x + 3 * (dim_y - y) * dim_x] == 0 {
+                continue;
+            }
+            drawn += 1;
+            let dx = x as f32 + 0.5 - origin.x();
+            let dz = y as f32 + 0.5 - origin.z();
+            assert!((dz.atan2(dx) - azimuth).abs() <= beamwidth / 2.0);
+            assert!((dx * dx + dz * dz).sqrt() <= 15.0);
+        }
+    }
+    assert!(drawn > 0);
+}
+
+#[test]
+fn test_rescale_uniform() {
+    let signals = vec![-60.0; 8 * 6];
+    let (rescaled, dim_x, dim_y) = rescale_signals(&signals, 8, 6, 0.5);
+    assert_eq!((dim_x, dim_y), (4, 3));
+    assert!(rescaled.iter().all(|s| *s == -60.0));
+}
+
+#[test]
+fn test_rescale_ignores_no_signal() {
+    let signals = vec![-60.0, NO_SIGNAL, -80.0, NO_SIGNAL];
+    let (rescaled, _, _) = rescale_signals(&signals, 2, 2, 0.5);
+    assert_eq!(rescaled, vec![-70.0]);
+}
+
+#[test]
+fn test_angle_to_color() {
+    // 90 degrees is halfway from red to green on the hue wheel.
+    assert_eq!(
+        angle_to_color(std::f32::consts::FRAC_PI_2, 0.0),
+        (128, 255, 0)
+    );
+    assert_eq!(angle_to_color(0.0, 0.0), (255, 0, 0));
+    // Negative angles wrap around: -90 degrees is 270 degrees.
+    assert_eq!(
+        angle_to_color(-std::f32::consts::FRAC_PI_2, 0.0),
+        (128, 0, 255)
+    );
+    assert_eq!(angle_to_color(1.0, NO_SIGNAL), (0, 0, 0));
+}
+
+#[test]
+fn test_ground_epsilon_large_coordinates() {
+    let station = test_station(
+        Point3D::new(10.0, 50000.0, 10.0),
+        60.0,
+        Frequency::from_mhz(2000.0),
+    );
+    let mut scene = test_config(20, 20, vec![test_ground(20.0, 20.0), station.clone()]);
+    scene.ground_epsilon = 0.1;
+    scene.t_min = 0.01;
+    // Only count direct hits, not the ones found again after scattering.
+    scene.max_depth = 1;
+    let result = generate_signal(&station, &scene, &SigmapOptions::default());
+    for x in 1..20 {
+        for y in 1..20 {
+            assert!(result.signals[x + y * 20] > NO_SIGNAL, "({}, {})", x, y);
+        }
+    }
+}
+
+#[test]
+fn test_supersampling_output_size() {
+    let station = test_station(
+        Point3D::new(5.0, 10.0, 5.0),
+        30.0,
+        Frequency::from_mhz(2000.0),
+    );
+    let mut scene = test_config(10, 8, vec![test_ground(10.0, 8.0), station.clone()]);
+    scene.supersampling = 2;
+    let options = SigmapOptions {
+        times: true,
+        ..Default::default()
+    };
+    let fine = generate_signal(&station, &scene, &options);
+    assert_eq!(fine.signals.len(), 21 * 17);
+    let reduced = reduce_supersampled(&fine, 10, 8, 2);
+    assert_eq!(reduced.signals.len(), 11 * 9);
+    assert_eq!(reduced.times.len(), 11 * 9);
+    // Each point keeps the strongest of the four points it covers.
+    let block = [0, 1, 20, 21].map(|offset| fine.signals[2 * 3 + 2 * 4 * 20 + offset]);
+    assert_eq!(
+        reduced.signals[3 + 4 * 10],
+        block.into_iter().fold(NO_SIGNAL, f32::max)
+    );
+}
+
+#[test]
+fn test_supersampling_smooths_edges() {
+    // An edge between columns 2 and 3 of the fine grid cuts through the second pixel.
+    let mut fine = vec![NO_SIGNAL; 4 * 2];
+    for y in 0..2 {
+        for x in 0..3 {
+            fine[x + y * 4] = -70.0;
+        }
+    }
+    let mut pixels = vec![0; 3 * 2 * 3];
+    supersampled_to_pixels(&fine, &mut pixels, 2, 1, 2, [128, 128, 128]);
+    let inside = signal_to_color(-70.0).2;
+    let outside = signal_to_color(NO_SIGNAL).2;
+    // set_pixel puts row 0 at the end of the buffer.
+    let row = &pixels[2 * 3..];
+    assert_eq!(row[2], inside);
+    assert!(row[5] > outside && row[5] < inside);
+}
+
+#[test]
+fn test_subdirectories() {
+    let base = std::env::temp_dir().join(format!("sigmap_subdirectories_{}", std::process::id()));
+    let station = test_station(
+        Point3D::new(5.0, 10.0, 5.0),
+        30.0,
+        Frequency::from_mhz(2000.0),
+    );
+    let scene = test_config(10, 10, vec![test_ground(10.0, 10.0), station]);
+    let options = SigmapOptions {
+        subdirectories: true,
+        ..Default::default()
+    };
+    generate_sigmap(base.to_str().unwrap(), &scene, &options).unwrap();
+    assert!(base.join("1").join("2000.data").is_file());
+    std::fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_batch() {
+    let base = std::env::temp_dir().join(format!("sigmap_batch_{}", std::process::id()));
+    let (scenes, outputs) = (base.join("scenes"), base.join("out"));
+    std::fs::create_dir_all(&scenes).unwrap();
+    let station = test_station(
+        Point3D::new(5.0, 10.0, 5.0),
+        30.0,
+        Frequency::from_mhz(2000.0),
+    );
+    let scene = test_config(10, 10, vec![test_ground(10.0, 10.0), station]);
+    std::fs::write(scenes.join("a.json"), "{\"version\": ").unwrap();
+    std::fs::write(
+        scenes.join("b.json"),
+        serde_json::to_string(&scene).unwrap(),
+    )
+    .unwrap();
+    std::fs::write(scenes.join("notes.txt"), "not a scene").unwrap();
+    let options = SigmapOptions::default();
+    let outcomes = generate_batch(&scenes, &outputs, &options).unwrap();
+    assert_eq!(outcomes.len(), 2);
+    assert!(matches!(&outcomes[0], (name, BatchOutcome::Failed(_)) if name == "a"));
+    assert_eq!(outcomes[1], ("b".to_string(), BatchOutcome::Done));
+    assert!(outputs.join("b_1_2000.data").is_file());
+    // Run again, only the broken scene is tried again.
+    let outcomes = generate_batch(&scenes, &outputs, &options).unwrap();
+    assert!(matches!(outcomes[0].1, BatchOutcome::Failed(_)));
+    assert_eq!(outcomes[1].1, BatchOutcome::Skipped);
+    std::fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_receiver_height() {
+    let station = test_station(
+        Point3D::new(10.0, 10.0, 10.0),
+        30.0,
+        Frequency::from_mhz(2000.0),
+    );
+    let mut scene = test_config(20, 20, vec![test_ground(20.0, 20.0), station.clone()]);
+    scene.max_depth = 1;
+    let at_ground = generate_signal(&station, &scene, &SigmapOptions::default());
+    scene.receiver_height = 1.5;
+    let raised = generate_signal(&station, &scene, &SigmapOptions::default());
+    // The ray aimed at (15, 0, 10) crosses y = 1.5 at x = 14.25, 85% of the way.
+    let direction = Point3D::new(5.0, -10.0, 0.0);
+    let expected = Ray::new(
+        station.origin,
+        direction.unit_vector(),
+        30.0,
+        0.0,
+        Frequency::from_mhz(2000.0),
+    )
+    .strength_at(0.85 * direction.length());
+    assert_approx_eq!(raised.signals[14 + 10 * 20], expected, 1e-3);
+    assert!(at_ground.signals[14 + 10 * 20] < expected);
+}
+
+#[test]
+fn test_two_ray_ground_reflection() {
+    // Transmitter 10m up over a mirror-like ground, receivers 5m up. Along the
+    // beam center (+x) the recorded signal should follow the two-ray model terms.
+    let (tx_height, rx_height, reflection_loss, frequency) =
+        (10.0, 5.0, 3.0, Frequency::from_mhz(2000.0));
+    let ground = Cube::new(
+        Point3D::new(20.0, 0.0, 10.5),
+        20.0,
+        0.0,
+        10.5,
+        Material::Metal(Metal::new(Srgb::new(0.5, 0.5, 0.5), 0.0, reflection_loss)),
+        0,
+    );
+    let station = test_station(Point3D::new(5.0, tx_height, 10.5), 30.0, frequency);
+    let mut scene = test_config(40, 21, vec![ground, station.clone()]);
+    scene.receiver_height = rx_height;
+    let result = generate_signal(&station, &scene, &SigmapOptions::default());
+
+    let wavelength = frequency.wavelength();
+    let free_space =
+        |distance: f32| 30.0 - 20.0 * (4.0 * std::f32::consts::PI * distance / wavelength).log10();
+    let direct = |range: f32| free_space((range * range + (tx_height - rx_height).powi(2)).sqrt());
+    let reflected = |range: f32| {
+        free_space((range * range + (tx_height + rx_height).powi(2)).sqrt()) - reflection_loss
+    };
+    // Close in, the strongest arrival is the direct ray aimed at x = 25, which
+    // crosses the receivers halfway, 10m out.
+    let near = result.signals[15 + 10 * 40];
+    assert_approx_eq!(near, direct(10.0), 0.01);
+    assert!(near > reflected(10.0));
+    // Further out, only the ray reflected at x = 22 gets there, 25.5m out.
+    assert_approx_eq!(result.signals[30 + 10 * 40], reflected(25.5), 0.01);
+}
+
+#[test]
+fn test_ray_counts() {
+    let mut scene = test_config(
+        4,
+        3,
+        vec![
+            test_ground(4.0, 3.0),
+            test_station(
+                Point3D::new(1.0, 2.0, 1.0),
+                10.0,
+                Frequency::from_mhz(2400.0),
+            ),
+            test_station(
+                Point3D::new(3.0, 2.0, 2.0),
+                10.0,
+                Frequency::from_mhz(5000.0),
+            ),
+        ],
+    );
+    scene.samples_per_pixel = 5;
+    assert_eq!(signal_ray_count(&scene), 4 * 3 * 4 * 2);
+    assert_eq!(crate::raytracer::render_ray_count(&scene), 4 * 3 * 5);
+    scene.supersampling = 2;
+    assert_eq!(signal_ray_count(&scene), 8 * 6 * 4 * 2);
+    scene.rays_per_target = 3;
+    assert_eq!(signal_ray_count(&scene), 8 * 6 * 4 * 3 * 2);
+    assert!(time_per_signal_ray(&scene, 100).is_some());
+}
+
+#[test]
+fn test_link_budget_line_of_sight() {
+    let mut scene = test_config(
+        10,
+        10,
+        vec![
+            test_ground(10.0, 10.0),
+            test_station(
+                Point3D::new(0.0, 10.0, 5.0),
+                20.0,
+                Frequency::from_mhz(2400.0),
+            ),
+        ],
+    );
+    scene.probes = vec![Point3D::new(5.2, 0.0, 5.2)];
+    let budgets = link_budget(&scene);
+    assert_eq!(budgets.len(), 1);
+    let budget = &budgets[0];
+    assert_eq!(budget.station, 1);
+    // The strongest ray into the probe's cell lands on its corner nearest the station, at
+    // (5, 0, 5), straight along the beam.
+    assert_approx_eq!(budget.path_length, 125f32.sqrt(), 1e-3);
+    assert_approx_eq!(budget.distance, (5.2f32 * 5.2 + 100.0 + 0.04).sqrt(), 1e-3);
+    assert_approx_eq!(budget.beam_loss, 0.0, 1e-3);
+    assert_approx_eq!(budget.bounce_loss, 0.0, 1e-3);
+    // 20 log10(d / (lambda / 4 pi)) with lambda = c / 2.4GHz.
+    assert_approx_eq!(budget.free_space_loss, 61.021, 1e-2);
+    assert_approx_eq!(budget.received, 20.0 - 61.021, 1e-2);
+}
+
+#[cfg(test)]
+fn read_floats(path: &Path) -> Vec<f32> {
+    read_data(path).unwrap()
+}
+
+#[test]
+fn test_combine_max() {
+    let base = std::env::temp_dir().join(format!("sigmap_combine_{}", std::process::id()));
+    let mut west = test_station(
+        Point3D::new(2.0, 5.0, 5.0),
+        30.0,
+        Frequency::from_mhz(2000.0),
+    );
+    west.id = 1;
+    let mut east = test_station(
+        Point3D::new(8.0, 5.0, 5.0),
+        30.0,
+        Frequency::from_mhz(2000.0),
+    );
+    east.id = 2;
+    let scene = test_config(10, 10, vec![test_ground(10.0, 10.0), west, east]);
+    let options = SigmapOptions {
+        subdirectories: true,
+        combine: Some(Combine::Max),
+        ..Default::default()
+    };
+    generate_sigmap(base.to_str().unwrap(), &scene, &options).unwrap();
+    let west = read_floats(&base.join("1").join("2000.data"));
+    let east = read_floats(&base.join("2").join("2000.data"));
+    let combined = read_floats(&base.join("combined.data"));
+    assert_eq!(combined.len(), west.len());
+    for i in 0..combined.len() {
+        assert_eq!(combined[i], west[i].max(east[i]));
+    }
+    // Each station is strongest on its own side.
+    assert!(combined.iter().zip(west.iter()).any(|(c, w)| c != w));
+    assert!(combined.iter().zip(east.iter()).any(|(c, e)| c != e));
+    std::fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_elevation_window() {
+    let station = test_station(
+        Point3D::new(10.0, 1.0, 10.0),
+        30.0,
+        Frequency::from_mhz(2000.0),
+    );
+    let mut scene = test_config(20, 20, vec![test_ground(20.0, 20.0), station.clone()]);
+    scene.max_depth = 1;
+    let full = generate_signal(&station, &scene, &SigmapOptions::default());
+    scene.elevation_min = 0.0;
+    scene.elevation_max = 10.0;
+    let windowed = generate_signal(&station, &scene, &SigmapOptions::default());
+    // Two meters out the ray comes down at about 27 degrees, eight meters out at about 7.
+    let (steep, shallow) = (10 + 12 * 20, 10 + 18 * 20);
+    assert!(full.signals[steep] > NO_SIGNAL);
+    assert_eq!(windowed.signals[steep], NO_SIGNAL);
+    assert_eq!(windowed.signals[shallow], full.signals[shallow]);
+    assert!(windowed.signals[shallow] > NO_SIGNAL);
+}
+
+#[test]
+fn test_min_grazing_angle() {
+    let station = test_station(
+        Point3D::new(10.0, 1.0, 10.0),
+        30.0,
+        Frequency::from_mhz(2000.0),
+    );
+    let mut scene = test_config(20, 20, vec![test_ground(20.0, 20.0), station.clone()]);
+    scene.max_depth = 1;
+    let all = generate_signal(&station, &scene, &SigmapOptions::default());
+    scene.min_grazing_angle = 10.0;
+    let steep_only = generate_signal(&station, &scene, &SigmapOptions::default());
+    // Two meters out the ray comes down at about 27 degrees, eight meters out at about 7.
+    let (steep, shallow) = (10 + 12 * 20, 10 + 18 * 20);
+    assert!(all.signals[shallow] > NO_SIGNAL);
+    assert_eq!(steep_only.signals[shallow], NO_SIGNAL);
+    assert_eq!(steep_only.signals[steep], all.signals[steep]);
+    assert!(steep_only.signals[steep] > NO_SIGNAL);
+}
+
+#[test]
+fn test_half_meter_pixels() {
+    let station = test_station(
+        Point3D::new(5.0, 10.0, 5.0),
+        30.0,
+        Frequency::from_mhz(2000.0),
+    );
+    let mut scene = test_config(20, 20, vec![test_ground(10.0, 10.0), station.clone()]);
+    scene.world_width = 10.0;
+    scene.world_height = 10.0;
+    assert_eq!(scene.pixel_size(), (0.5, 0.5));
+    assert_eq!(
+        grid_coord(Point3D::new(3.2, 0.0, 4.7), &scene),
+        Some(6 + 9 * 20)
+    );
+    assert_eq!(grid_coord(Point3D::new(9.99, 0.0, 0.0), &scene), Some(19));
+    assert_eq!(grid_coord(Point3D::new(10.0, 0.0, 0.0), &scene), None);
+    // The 20x20 pixels together cover the 10x10m ground.
+    let result = generate_signal(&station, &scene, &SigmapOptions::default());
+    for x in 1..20 {
+        for y in 1..20 {
+            assert!(result.signals[x + y * 20] > NO_SIGNAL, "({}, {})", x, y);
+        }
+    }
+}
+
+#[test]
+fn test_delay_to_color() {
+    assert_eq!(delay_to_color(0.0, -50.0, 100.0), (0, 0, 255));
+    assert_eq!(delay_to_color(100.0, -50.0, 100.0), (255, 0, 0));
+    assert_eq!(delay_to_color(500.0, -50.0, 100.0), (255, 0, 0));
+    // Halfway is hue 120, green.
+    assert_eq!(delay_to_color(50.0, -50.0, 100.0), (0, 255, 0));
+    assert_eq!(delay_to_color(50.0, NO_SIGNAL, 100.0), (0, 0, 0));
+    // 30 meters of path takes about 100ns.
+    assert_approx_eq!(30.0 / METERS_PER_NS, 100.07, 0.01);
+}
+
+#[test]
+fn test_no_clobber() {
+    let base = std::env::temp_dir().join(format!("sigmap_no_clobber_{}", std::process::id()));
+    let station = test_station(
+        Point3D::new(5.0, 10.0, 5.0),
+        30.0,
+        Frequency::from_mhz(2000.0),
+    );
+    let mut scene = test_config(10, 10, vec![test_ground(10.0, 10.0), station]);
+    scene.output_scales = vec![0.5];
+    let mut options = SigmapOptions {
+        subdirectories: true,
+        png: true,
+        combine: Some(Combine::Max),
+        ..Default::default()
+    };
+    generate_sigmap(base.to_str().unwrap(), &scene, &options).unwrap();
+    let files = output_files(base.to_str().unwrap(), &scene, &options);
+    assert_eq!(files.len(), 6);
+    assert!(files.iter().all(|f| Path::new(f).is_file()), "{:?}", files);
+    // Overwriting is still the default.
+    generate_sigmap(base.to_str().unwrap(), &scene, &options).unwrap();
+    options.no_clobber = true;
+    let data = base.join("1").join("2000.data");
+    let modified = std::fs::metadata(&data).unwrap().modified().unwrap();
+    let error = generate_sigmap(base.to_str().unwrap(), &scene, &options).unwrap_err();
+    assert!(
+        matches!(&error, RaytracerError::Io(e) if e.kind() == std::io::ErrorKind::AlreadyExists),
+        "{}",
+        error
+    );
+    assert!(error.to_string().ends_with("already exists"), "{}", error);
+    assert_eq!(
+        std::fs::metadata(&data).unwrap().modified().unwrap(),
+        modified
+    );
+    std::fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_land_cover() {
+    use crate::config::{LandCover, LandCoverClass};
+    let station = test_station(
+        Point3D::new(10.0, 10.0, 10.0),
+        30.0,
+        Frequency::from_mhz(2000.0),
+    );
+    let mut scene = test_config(20, 20, vec![test_ground(20.0, 20.0), station.clone()]);
+    scene.max_depth = 1;
+    let uniform = generate_signal(&station, &scene, &SigmapOptions::default());
+    // Open ground on the west half, forest on the east half.
+    scene.land_cover = Some(LandCover {
+        image: (vec![200, 200, 200, 0, 120, 0], 2, 1, String::new()),
+        classes: vec![
+            LandCoverClass {
+                color: [255, 255, 255],
+                loss: 0.0,
+            },
+            LandCoverClass {
+                color: [0, 128, 0],
+                loss: 10.0,
+            },
+        ],
+    });
+    let covered = generate_signal(&station, &scene, &SigmapOptions::default());
+    for y in 1..20 {
+        for x in 1..9 {
+            assert_eq!(covered.signals[x + y * 20], uniform.signals[x + y * 20]);
+        }
+        for x in 11..20 {
+            let coord = x + y * 20;
+            assert_approx_eq!(covered.signals[coord], uniform.signals[coord] - 10.0, 1e-3);
+        }
+    }
+}
+
+#[test]
+fn test_rays_per_target() {
+    let station = test_station(
+        Point3D::new(10.0, 10.0, 10.0),
+        30.0,
+        Frequency::from_mhz(2000.0),
+    );
+    let mut scene = test_config(20, 20, vec![test_ground(20.0, 20.0), station.clone()]);
+    assert_eq!(
+        station_rays(&station, &scene, 3, 4).len(),
+        SUBSAMPLING.pow(2)
+    );
+    scene.rays_per_target = 5;
+    let rays = station_rays(&station, &scene, 3, 4);
+    assert_eq!(rays.len(), 5 * SUBSAMPLING.pow(2));
+    // The first ray of each subsample keeps its old direction, the repeats are spread out.
+    let single = test_config(20, 20, vec![]);
+    assert_eq!(
+        rays[0].direction,
+        station_rays(&station, &single, 3, 4)[0].direction
+    );
+    assert!(rays[1..5].iter().all(|r| r.direction != rays[0].direction));
+}
+
+#[test]
+fn test_write_image() {
+    let path = std::env::temp_dir().join(format!("sigmap_write_image_{}.png", std::process::id()));
+    let scene = test_config(2, 2, vec![]);
+    let mut pixels: Vec<u8> = vec![0; 3 * 3 * 3];
+    // The first row is unused, and must not end up in the image.
+    pixels[..6].fill(255);
+    for (x, y) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+        set_pixel(&mut pixels, (2, 2), (x, y), (x as u8, y as u8, 10));
+    }
+    write_image(path.to_str().unwrap(), &pixels, (2, 2), &scene).unwrap();
+    let png = image::open(&path).unwrap().to_rgb8();
+    assert_eq!(png.dimensions(), (2, 2));
+    for (x, y) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+        // Going up the map is going up the image.
+        assert_eq!(png.get_pixel(x, 1 - y).0, [x as u8, y as u8, 10]);
+    }
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_no_homogenize() {
+    let base = std::env::temp_dir().join(format!("sigmap_no_homogenize_{}", std::process::id()));
+    let station = test_station(
+        Point3D::new(2.5, 10.0, 5.0),
+        30.0,
+        Frequency::from_mhz(2000.0),
+    );
+    // The ground stops halfway, so the east half of the map gets no signal.
+    let scene = test_config(10, 10, vec![test_ground(5.0, 10.0), station]);
+    let mut options = SigmapOptions {
+        subdirectories: true,
+        png: true,
+        ..Default::default()
+    };
+    let gap = 6 + 5 * 10;
+    let read_gap = |base: &Path| {
+        let data = read_floats(&base.join("1").join("2000.data"));
+        let png = image::open(base.join("1").join("2000.png"))
+            .unwrap()
+            .to_rgb8();
+        (data[gap], png.get_pixel(6, 10 - 1 - 5).0)
+    };
+    generate_sigmap(base.to_str().unwrap(), &scene, &options).unwrap();
+    let (homogenized, homogenized_color) = read_gap(&base);
+    assert!(homogenized > NO_SIGNAL);
+    assert_ne!(homogenized_color, scene.no_signal_color);
+    options.no_homogenize = true;
+    generate_sigmap(base.to_str().unwrap(), &scene, &options).unwrap();
+    let (raw, raw_color) = read_gap(&base);
+    assert_eq!(raw, NO_SIGNAL);
+    assert_eq!(raw_color, scene.no_signal_color);
+    std::fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_sweep_frequencies() {
+    let base = std::env::temp_dir().join(format!("sigmap_sweep_{}", std::process::id()));
+    let station = test_station(
+        Point3D::new(5.0, 10.0, 5.0),
+        30.0,
+        Frequency::from_mhz(2000.0),
+    );
+    let mut scene = test_config(10, 10, vec![test_ground(10.0, 10.0), station]);
+    let options = SigmapOptions {
+        subdirectories: true,
+        best_band: true,
+        ..Default::default()
+    };
+    sweep_frequencies(
+        base.to_str().unwrap(),
+        &mut scene,
+        &[700.0, 2600.0, 3500.0].map(Frequency::from_mhz),
+        &options,
+    )
+    .unwrap();
+    let mut written: Vec<String> = std::fs::read_dir(base.join("1"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    written.sort();
+    assert_eq!(written, ["2600.data", "3500.data", "700.data"]);
+    // Lower frequencies lose less over the same distance.
+    let low = read_floats(&base.join("1").join("700.data"));
+    let high = read_floats(&base.join("1").join("3500.data"));
+    assert!(low[7 + 5 * 10] > high[7 + 5 * 10]);
+    let best = read_floats(&base.join("bestband.data"));
+    assert_eq!(best[7 + 5 * 10], 700.0);
+    let strength = read_floats(&base.join("bestband_strength.data"));
+    assert_eq!(strength[7 + 5 * 10], low[7 + 5 * 10]);
+    std::fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_max_penetrations() {
+    let wall = |x: f32| {
+        let material = Material::Wall(Wall::new(Srgb::new(0.5, 0.5, 0.5), 10.0));
+        Cube::new(Point3D::new(x, 5.0, 5.0), 0.1, 5.0, 5.0, material, 2)
+    };
+    let objects = [test_ground(20.0, 10.0), wall(3.0), wall(5.0), wall(7.0)];
+    let objects: Vec<&Cube> = objects.iter().collect();
+    let mut scene = test_config(20, 10, vec![]);
+    scene.max_depth = 1;
+    // Crosses all three walls on its way down to (10, 0, 5).
+    let ray = Ray::new(
+        Point3D::new(0.0, 5.0, 5.0),
+        Point3D::new(1.0, -0.5, 0.0),
+        30.0,
+        0.0,
+        Frequency::from_mhz(2000.0),
+    );
+    let received = |scene: &Config| {
+        let mut strengths = vec![];
+        trace_signal_ray(ray, &objects, scene, &mut |_, ray, _, _| {
+            strengths.push(ray.strength)
+        });
+        strengths
+    };
+    assert_eq!(received(&scene), vec![0.0]);
+    scene.max_penetrations = Some(3);
+    assert_eq!(received(&scene), vec![0.0]);
+    scene.max_penetrations = Some(2);
+    assert!(received(&scene).is_empty());
+}
+
+#[test]
+fn test_linear_output() {
+    assert_approx_eq!(dbm_to_mw(20.0), 100.0, 1e-3);
+    assert_approx_eq!(mw_to_dbm(dbm_to_mw(-73.5)), -73.5, 1e-4);
+    let path = std::env::temp_dir().join(format!("sigmap_linear_{}.data", std::process::id()));
+    let mut file = File::create(&path).unwrap();
+    write_signals(&[20.0, NO_SIGNAL, -30.0], &mut file, true).unwrap();
+    drop(file);
+    let written = read_floats(&path);
+    assert_approx_eq!(written[0], 100.0, 1e-3);
+    assert_eq!(written[1], 0.0);
+    assert_approx_eq!(mw_to_dbm(written[2]), -30.0, 1e-4);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_sum_linear() {
+    let ray = Ray::new(
+        Point3D::new(0.0, 10.0, 0.0),
+        Point3D::new(0.0, -1.0, 0.0),
+        30.0,
+        0.0,
+        Frequency::from_mhz(2000.0),
+    );
+    let single = ray.strength_at(10.0);
+    let options = SigmapOptions::default();
+    let mut max = SignalResult::new(4, &options, Aggregation::Max, ray.origin);
+    let mut sum = SignalResult::new(4, &options, Aggregation::SumLinear, ray.origin);
+    for result in [&mut max, &mut sum] {
+        result.record(0, &ray, 10.0, -110.0);
+        result.record(0, &ray, 10.0, -110.0);
+        result.sum_powers(-110.0, 1);
+    }
+    assert_eq!(max.signals[0], single);
+    // Two paths of one ray each add up.
+    assert_approx_eq!(sum.signals[0], single + 10.0 * 2f32.log10(), 1e-3);
+    assert_eq!(sum.signals[1], NO_SIGNAL);
+}
+
+#[test]
+fn test_sum_linear_single_path() {
+    // Only the direct path down to the ground. With one ray per target there is no
+    // jitter, so both runs trace the same rays.
+    let station = test_station(
+        Point3D::new(10.0, 30.0, 10.0),
+        30.0,
+        Frequency::from_mhz(2000.0),
+    );
+    let mut scene = test_config(20, 20, vec![test_ground(20.0, 20.0), station.clone()]);
+    scene.max_depth = 1;
+    scene.rays_per_target = 1;
+    let options = SigmapOptions {
+        counts: true,
+        ..Default::default()
+    };
+    let max = generate_signal(&station, &scene, &options);
+    scene.aggregation = Aggregation::SumLinear;
+    let sum = generate_signal(&station, &scene, &options);
+    assert_eq!(max.counts, sum.counts);
+    let rays = rays_per_point(&scene) as f32;
+    let mut served = 0;
+    for (coord, count) in sum.counts.iter().enumerate() {
+        if *count == 0 {
+            continue;
+        }
+        served += 1;
+        // Each ray carries a share of the point's power, so the sum lies between the
+        // strongest ray's share and the mean over the rays that landed. A point can catch a
+        // ray or two aimed at its neighbor. Counting every ray in full would add 6 dB.
+        let difference = sum.signals[coord] - max.signals[coord];
+        let (low, high) = (-10.0 * rays.log10(), 10.0 * (*count as f32 / rays).log10());
+        assert!(
+            difference >= low - 1e-3 && difference <= high + 1e-3,
+            "{} dB from {} rays at {}",
+            difference,
+            count,
+            coord
+        );
+    }
+    assert!(served >= 18 * 18);
+}
+
+#[test]
+fn test_no_lights() {
+    let base = std::env::temp_dir().join(format!("sigmap_no_lights_{}", std::process::id()));
+    let scene = test_config(10, 10, vec![test_ground(10.0, 10.0)]);
+    let error = generate_sigmap(base.to_str().unwrap(), &scene, &SigmapOptions::default());
+    assert!(matches!(
+        error,
+        Err(RaytracerError::Validation(message))
+            if message == "scene contains no Material::Light objects"
+    ));
+    assert!(!base.exists());
+}
+
+#[test]
+fn test_write_errors() {
+    let base = std::env::temp_dir().join(format!("sigmap_write_errors_{}", std::process::id()));
+    let mut station = test_station(
+        Point3D::new(5.0, 5.0, 5.0),
+        30.0,
+        Frequency::from_mhz(2000.0),
+    );
+    station.id = 1;
+    let scene = test_config(10, 10, vec![test_ground(10.0, 10.0), station]);
+    // The station map cannot be created in a directory that does not exist.
+    let missing = base.join("missing").join("map");
+    let error = generate_sigmap(missing.to_str().unwrap(), &scene, &SigmapOptions::default());
+    assert!(matches!(error, Err(RaytracerError::Io(_))));
+    // Nor can the combined map where a directory of that name is in the way.
+    let options = SigmapOptions {
+        subdirectories: true,
+        combine: Some(Combine::Max),
+        ..Default::default()
+    };
+    std::fs::create_dir_all(base.join("combined.data")).unwrap();
+    let error = generate_sigmap(base.to_str().unwrap(), &scene, &options).unwrap_err();
+    assert!(matches!(error, RaytracerError::Io(_)));
+    assert!(error.to_string().contains("combined.data"), "{}", error);
+    assert!(base.join("1").join("2000.data").exists());
+    std::fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn test_implausible_strength() {
+    let base = std::env::temp_dir().join(format!("sigmap_strength_{}", std::process::id()));
+    let mut station = test_station(
+        Point3D::new(5.0, 10.0, 5.0),
+        -200.0,
+        Frequency::from_mhz(2000.0),
+    );
+    station.id = 4;
+    let scene = test_config(10, 10, vec![test_ground(10.0, 10.0), station]);
+    let error = generate_sigmap(base.to_str().unwrap(), &scene, &SigmapOptions::default());
+    assert!(matches!(
+        error,
+        Err(RaytracerError::Validation(message))
+            if message == "Light strength outside -30 to 60 dBm: station 4 (-200 dBm)"
+    ));
+    assert!(!base.exists());
+}
+
+#[test]
+fn test_stream_signal() {
+    let station = test_station(
+        Point3D::new(4.0, 10.0, 6.0),
+        30.0,
+        Frequency::from_mhz(2000.0),
+    );
+    let mut scene = test_config(10, 8, vec![test_ground(10.0, 8.0), station.clone()]);
+    // Only direct rays, which never land on a row that was already written.
+    scene.max_depth = 1;
+    let batch = generate_signal(&station, &scene, &SigmapOptions::default());
+    let mut out: Vec<u8> = Vec::new();
+    stream_signal(&station, &scene, &mut out, false).unwrap();
+    let streamed: Vec<f32> = out
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect();
+    assert_eq!(streamed, batch.signals);
+    scene.supersampling = 2;
+    assert!(stream_signal(&station, &scene, &mut out, false).is_err());
+}
+
+#[test]
+fn test_stream_signal_reflections() {
+    // A metal wall across the map at z = 5.5 would send the rays aimed beyond it back
+    // onto rows that were written already, and the ground is a mirror too.
+    let mirror = Material::Metal(Metal::new(Srgb::new(0.5, 0.5, 0.5), 0.0, 0.0));
+    let ground = Cube::new(
+        Point3D::new(5.0, 0.0, 4.0),
+        5.0,
+        0.0,
+        4.0,
+        mirror.clone(),
+        0,
+    );
+    let wall = Cube::new(Point3D::new(5.0, 1.0, 5.5), 5.0, 1.0, 0.2, mirror, 2);
+    let station = test_station(
+        Point3D::new(5.0, 3.0, 1.0),
+        30.0,
+        Frequency::from_mhz(2000.0),
+    );
+    let mut scene = test_config(10, 8, vec![ground, wall, station.clone()]);
+    scene.max_depth = 3;
+    let mut out: Vec<u8> = Vec::new();
+    assert!(stream_signal(&station, &scene, &mut out, false).is_err());
+    assert!(out.is_empty());
+    // Without reflections the wall only shadows, and the map matches exactly.
+    scene.max_depth = 1;
+    let batch = generate_signal(&station, &scene, &SigmapOptions::default());
+    stream_signal(&station, &scene, &mut out, false).unwrap();
+    let streamed: Vec<f32> = out
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect();
+    assert_eq!(streamed, batch.signals);
+    assert!(streamed.iter().any(|s| is_no_signal(*s)));
+}
+
+#[test]
+fn test_steered_beams() {
+    let mut light = Light::new(
+        Srgb::new(1.0, 1.0, 1.0),
+        30.0,
+        1,
+        Frequency::from_mhz(2000.0),
+    );
+    light.beam_configs = vec![
+        BeamConfig {
+            azimuth: 0.0,
+            tilt: 10.0,
+            gain: 0.0,
+        },
+        BeamConfig {
+            azimuth: 0.0,
+            tilt: 45.0,
+            gain: 0.0,
+        },
+    ];
+    let station = Cube::new(
+        Point3D::new(0.0, 10.0, 5.0),
+        0.5,
+        0.5,
+        0.5,
+        Material::Light(light),
+        1,
+    );
+    let scene = test_config(100, 10, vec![test_ground(100.0, 10.0), station.clone()]);
+    assert_eq!(signal_ray_count(&scene), 100 * 10 * SUBSAMPLING.pow(2) * 2);
+    // Each direction gets a ray from both beams, the one tilted towards it strongest.
+    let near = station_rays(&station, &scene, 10, 5);
+    assert_eq!(near.len(), 2 * SUBSAMPLING.pow(2));
+    assert_eq!(near[0].direction, near[1].direction);
+    assert!(near[1].strength > near[0].strength + 1.0);
+    let far = station_rays(&station, &scene, 57, 5);
+    assert!(far[0].strength > far[1].strength + 1.0);
+}
+
+#[test]
+fn test_vertical_ray_strength() {
+    // A ray straight down has no azimuth to weigh against the sectors.
+    let down = Point3D::new(0.0, -1.0, 0.0);
+    for beams in [1, 3] {
+        assert_eq!(
+            BeamPattern::Sectors(beams).strengths(down, 30.0),
+            vec![30.0]
+        );
+    }
+    // Just off vertical, the strength is that of the sector the ray leans into.
+    let center = BeamPattern::Sectors(1).strengths(Point3D::new(1e-4, -1.0, 0.0), 30.0);
+    assert_eq!(center, vec![30.0]);
+}
+
+#[test]
+fn test_best_band() {
+    let bands = vec![
+        (Frequency::from_mhz(700.0), vec![-60.0, -90.0, NO_SIGNAL]),
+        (Frequency::from_mhz(3500.0), vec![-80.0, -70.0, NO_SIGNAL]),
+    ];
+    let (frequencies, signals) = best_band(&bands);
+    assert_eq!(frequencies, vec![700.0, 3500.0, 0.0]);
+    assert_eq!(signals, vec![-60.0, -70.0, NO_SIGNAL]);
+}
+
+#[test]
+fn test_corner_bounces() {
+    // A wall standing on the ground, with rays aimed at the edge between them.
+    let wall = Cube::new(
+        Point3D::new(6.0, 5.0, 5.0),
+        1.0,
+        5.0,
+        5.0,
+        Material::Lambertian(Lambertian::new(Srgb::new(0.5, 0.5, 0.5))),
+        2,
+    );
+    let objects = [test_ground(10.0, 10.0), wall];
+    let objects: Vec<&Cube> = objects.iter().collect();
+    let mut scene = test_config(10, 10, vec![]);
+    scene.max_depth = 1000;
+    for i in 0..100 {
+        let ray = Ray::new(
+            Point3D::new(0.0, 3.0, i as f32 / 10.0),
+            Point3D::new(5.0, -3.0, 5.0 - i as f32 / 10.0),
+            30.0,
+            0.0,
+            Frequency::from_mhz(2000.0),
+        );
+        let hits = trace_signal_ray(ray, &objects, &scene, &mut |_, _, _, _| {});
+        assert!(hits < 50, "{} hits", hits);
+    }
+}
+
+#[test]
+fn test_arrival_azimuth() {
+    let options = SigmapOptions {
+        angles: true,
+        ..Default::default()
+    };
+    let mut result = SignalResult::new(2, &options, Aggregation::Max, Point3D::new(0.0, 10.0, 0.0));
+    // Travelling west, so arriving from the east, and the other way around.
+    let from_east = Ray::new(
+        Point3D::new(10.0, 10.0, 0.0),
+        Point3D::new(-1.0, -1.0, 0.0),
+        30.0,
+        0.0,
+        Frequency::from_mhz(2000.0),
+    );
+    let from_west = Ray::new(
+        Point3D::new(-10.0, 10.0, 0.0),
+        Point3D::new(1.0, -1.0, 0.0),
+        30.0,
+        0.0,
+        Frequency::from_mhz(2000.0),
+    );
+    result.record(0, &from_east, 10.0, -110.0);
+    result.record(1, &from_west, 10.0, -110.0);
+    assert_eq!(result.angles[0], 0.0);
+    assert_approx_eq!(result.angles[1].abs(), std::f32::consts::PI);
+    assert_approx_eq!(
+        arrival_azimuth(Point3D::new(0.0, -1.0, -1.0)),
+        std::f32::consts::FRAC_PI_2
+    );
+}
+
+#[test]
+fn test_signal_to_color() {
+    assert_eq!(signal_to_color(-100.0), (0, 0, 0));
+    assert_eq!(signal_to_color(0.0), (0, 0, 255));
+    // At 0.3 linear intensity, sRGB encodes blue as 149.
+    assert_eq!(signal_to_color(-90.0), (0, 0, 149));
+}
+
+#[test]
+fn test_circle_mask() {
+    let mut scene = test_config(10, 10, vec![]);
+    scene.supersampling = 2;
+    let mut signals = vec![-50.0; 21 * 21];
+    apply_mask(&mut signals, &scene);
+    assert!(signals.iter().all(|s| *s == -50.0));
+    scene.mask = Some(crate::config::Mask::Circle {
+        x: 5.0,
+        z: 5.0,
+        radius: 3.0,
+    });
+    apply_mask(&mut signals, &scene);
+    // Points are half a meter apart, centered a quarter meter into their cell.
+    let at = |x: f32, z: f32| signals[(x * 2.0) as usize + (z * 2.0) as usize * 20];
+    assert_eq!(at(5.0, 5.0), -50.0);
+    assert_eq!(at(7.5, 5.0), -50.0);
+    assert_eq!(at(5.0, 8.5), NO_SIGNAL);
+    assert_eq!(at(0.0, 0.0), NO_SIGNAL);
+    assert_eq!(at(8.0, 8.0), NO_SIGNAL);
+}
+
+#[test]
+fn test_compare_to_measurements() {
+    let scene = test_config(10, 8, vec![]);
+    let mut signals = vec![NO_SIGNAL; 11 * 9];
+    for x in 0..10 {
+        for y in 0..8 {
+            signals[x + y * 10] = -60.0 - x as f32 - 2.0 * y as f32;
+        }
+    }
+    // Between pixel centers, the map is interpolated linearly.
+    let measurements: Vec<Measurement> = [(0.5, 0.5), (3.0, 4.5), (7.2, 2.9), (9.5, 7.5)]
+        .into_iter()
+        .map(|(x, y)| Measurement {
+            x,
+            y,
+            dbm: -60.0 - (x - 0.5) - 2.0 * (y - 0.5),
+        })
+        .chain([Measurement {
+            x: 20.0,
+            y: 2.0,
+            dbm: -80.0,
+        }])
+        .collect();
+    let stats = compare_to_measurements(&signals, &scene, &measurements);
+    assert_eq!(stats.count, 4);
+    assert_approx_eq!(stats.mean_error, 0.0, 1e-4);
+    assert_approx_eq!(stats.rmse, 0.0, 1e-4);
+    assert_approx_eq!(stats.correlation.unwrap(), 1.0, 1e-4);
+    let mut csv = vec![];
+    write_residuals(&mut csv, &stats).unwrap();
+    let csv = String::from_utf8(csv).unwrap();
+    assert_eq!(csv.lines().count(), 5);
+    assert!(csv.starts_with("x,y,measured,simulated,error\n0.5,0.5,-60,-60,0\n"));
+    // Nothing on the map, and measurements that do not vary.
+    let stats = compare_to_measurements(&signals, &scene, &measurements[4..]);
+    assert_eq!((stats.count, stats.correlation), (0, None));
+    let flat: Vec<Measurement> = measurements[..4]
+        .iter()
+        .map(|m| Measurement { dbm: -70.0, ..*m })
+        .collect();
+    assert_eq!(
+        compare_to_measurements(&signals, &scene, &flat).correlation,
+        None
+    );
+}
+
+#[test]
+fn test_read_measurements() {
+    let csv = "x,y,dbm\n1.5,2,-70.5\n\n3,4,-80\n";
+    let measurements = read_measurements(csv.as_bytes()).unwrap();
+    assert_eq!(measurements.len(), 2);
+    assert_eq!(measurements[0].dbm, -70.5);
+    assert!(read_measurements("1,2\n".as_bytes()).is_err());
+}
+
+#[test]
+fn test_timing_report() {
+    let base = std::env::temp_dir().join(format!("sigmap_timing_{}", std::process::id()));
+    let mut second = test_station(
+        Point3D::new(2.0, 10.0, 2.0),
+        30.0,
+        Frequency::from_mhz(2000.0),
+    );
+    second.id = 2;
+    let scene = test_config(
+        10,
+        10,
+        vec![
+            test_ground(10.0, 10.0),
+            test_station(
+                Point3D::new(5.0, 10.0, 5.0),
+                30.0,
+                Frequency::from_mhz(2000.0),
+            ),
+            second,
+        ],
+    );
+    let options = SigmapOptions {
+        subdirectories: true,
+        ..Default::default()
+    };
+    let timing = generate_sigmap(base.to_str().unwrap(), &scene, &options).unwrap();
+    std::fs::remove_dir_all(base).unwrap();
+    assert!(timing.total_seconds > 0.0);
+    assert!(timing.threads >= 1);
+    let ids: Vec<i64> = timing.stations.iter().map(|s| s.id).collect();
+    assert_eq!(ids, vec![1, 2]);
+    assert!(timing
+        .stations
+        .iter()
+        .all(|s| s.frequency == Frequency::from_mhz(2000.0)));
+}
+
+#[test]
+fn test_diff_maps() {
+    let a = vec![-60.0, NO_SIGNAL, -80.0, NO_SIGNAL, -150.0];
+    assert_eq!(diff_maps(&a, &a), vec![0.0; 5]);
+    let b = vec![-70.0, -100.0, NO_SIGNAL, NO_SIGNAL, NO_SIGNAL];
+    // A loss, coverage gained, coverage lost and two points without signal in either.
+    assert_eq!(diff_maps(&a, &b), vec![-10.0, 40.0, -60.0, 0.0, 0.0]);
+    assert_eq!(diff_to_color(0.0), (255, 255, 255));
+    assert_eq!(diff_to_color(-DIFF_RANGE), (0, 0, 255));
+    assert_eq!(diff_to_color(2.0 * DIFF_RANGE), (255, 0, 0));
+}
+
+#[test]
+fn test_reproject_mercator_data_file() {
+    use crate::config::GeoReference;
+    let base = std::env::temp_dir().join(format!("sigmap_mercator_{}", std::process::id()));
+    let mut station = test_station(
+        Point3D::new(5.0, 5.0, 5.0),
+        30.0,
+        Frequency::from_mhz(2000.0),
+    );
+    station.id = 1;
+    let mut scene = test_config(10, 10, vec![test_ground(10.0, 10.0), station]);
+    scene.geo_reference = Some(GeoReference {
+        lat: 52.0,
+        lon: 5.0,
+    });
+    let options = SigmapOptions {
+        subdirectories: true,
+        ..Default::default()
+    };
+    generate_sigmap(base.to_str().unwrap(), &scene, &options).unwrap();
+    let signals = read_floats(&base.join("1").join("2000.data"));
+    assert_eq!(signals.len(), 11 * 11);
+    // At zoom 20 a tile pixel is about 9 cm here, so every map point shows up.
+    let map = reproject_mercator(&signals, &scene, 20).unwrap();
+    for signal in &signals[..100] {
+        assert!(map.signals.contains(signal));
+    }
+    assert!(reproject_mercator(&signals[..99], &scene, 20).is_err());
+    std::fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn test_write_diff() {
+    let base = std::env::temp_dir().join(format!("sigmap_write_diff_{}", std::process::id()));
+    let mut station = test_station(
+        Point3D::new(5.0, 5.0, 5.0),
+        30.0,
+        Frequency::from_mhz(2000.0),
+    );
+    station.id = 1;
+    let mut scene = test_config(10, 10, vec![test_ground(10.0, 10.0), station]);
+    let options = SigmapOptions {
+        subdirectories: true,
+        ..Default::default()
+    };
+    let run = |name: &str, scene: &Config| {
+        let dir = base.join(name);
+        generate_sigmap(dir.to_str().unwrap(), scene, &options).unwrap();
+        read_floats(&dir.join("1").join("2000.data"))
+    };
+    let before = run("before", &scene);
+    assert_eq!(before.len(), 11 * 11);
+    // The same station 6 dB weaker.
+    if let Material::Light(light) = &mut scene.objects[1].material {
+        light.strength -= 6.0;
+    }
+    let after = run("after", &scene);
+    let diff_base = base.join("diff");
+    write_diff(diff_base.to_str().unwrap(), &before, &after, &scene).unwrap();
+    let diff = read_floats(&base.join("diff.data"));
+    assert_eq!(diff.len(), 10 * 10);
+    assert_eq!(diff, diff_maps(&before[..100], &after[..100]));
+    assert!(diff.iter().any(|d| (d + 6.0).abs() < 1e-3));
+    assert!(write_diff(diff_base.to_str().unwrap(), &before[..99], &after, &scene).is_err());
+    std::fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn test_reproject_mercator() {
+    use crate::config::GeoReference;
+    // A kilometer square map south east of the reference, in 100 m pixels that
+    // each hold their own index.
+    let mut scene = test_config(10, 10, vec![]);
+    scene.world_width = 1000.0;
+    scene.world_height = 1000.0;
+    scene.grid_origin = Point3D::new(0.0, 0.0, -1000.0);
+    let signals: Vec<f32> = (0..100).map(|i| -i as f32).collect();
+    assert!(reproject_mercator(&signals, &scene, 16).is_err());
+    let reference = GeoReference {
+        lat: 52.0,
+        lon: 5.0,
+    };
+    scene.geo_reference = Some(reference);
+    let map = reproject_mercator(&signals, &scene, 16).unwrap();
+    // The north west corner is the reference itself.
+    let (left, top) = geo_to_mercator(52.0, 5.0, 16);
+    assert_eq!(map.origin, (left as usize, top as usize));
+    let (south, east) = reference.to_geo(Point3D::new(1000.0, 0.0, -1000.0));
+    let (right, bottom) = geo_to_mercator(south, east, 16);
+    assert_eq!(map.width, right.ceil() as usize - map.origin.0);
+    assert_eq!(map.height, bottom.ceil() as usize - map.origin.1);
+    // Going south is going down the image, but up the rows of the map.
+    let at = |x: usize, y: usize| map.signals[x + y * map.width];
+    assert_eq!(at(1, 1), signals[90]);
+    assert_eq!(at(map.width - 2, 1), signals[99]);
+    assert_eq!(at(1, map.height - 2), signals[0]);
+    assert_eq!(at(map.width - 2, map.height - 2), signals[9]);
+    let (lat, lon) = mercator_to_geo(left, top, 16);
+    assert_approx_eq!(lat, 52.0, 1e-9);
+    assert_approx_eq!(lon, 5.0, 1e-9);
+}
+
+#[test]
+fn test_ground_reflection() {
+    use crate::config::GroundReflection;
+    // A ground that lets rays through, so nothing bounces off it unless ground
+    // reflection is on, and a low metal canopy over x in [10, 18]. Rays aimed at
+    // the ground under it hit its top instead and are reflected to the sky.
+    let ground = Cube::new(
+        Point3D::new(10.0, 0.0, 10.0),
+        10.0,
+        0.0,
+        10.0,
+        Material::Wall(Wall::new(Srgb::new(0.5, 0.5, 0.5), 0.0)),
+        0,
+    );
+    let canopy = Cube::new(
+        Point3D::new(14.0, 1.1, 10.0),
+        4.0,
+        0.1,
+        10.0,
+        Material::Metal(Metal::new(Srgb::new(0.5, 0.5, 0.5), 0.0, 0.0)),
+        2,
+    );
+    let station = test_station(
+        Point3D::new(2.0, 5.0, 10.0),
+        30.0,
+        Frequency::from_mhz(2000.0),
+    );
+    let mut scene = test_config(20, 20, vec![ground, canopy, station.clone()]);
+    let covered_under_canopy = |scene: &Config| {
+        let result = generate_signal(&station, scene, &SigmapOptions::default());
+        (13..18)
+            .filter(|x| result.signals[x + 10 * 20] > NO_SIGNAL)
+            .count()
+    };
+    assert_eq!(covered_under_canopy(&scene), 0);
+    scene.ground_reflection = vec![GroundReflection {
+        frequency: Frequency::from_mhz(2000.0),
+        coefficient: 0.5,
+    }];
+    // Rays reflected off the ground in front of the canopy bounce between its
+    // underside and the ground.
+    assert!(covered_under_canopy(&scene) >= 3);
+}
+
+#[test]
+fn test_selected_stations() {
+    let base = std::env::temp_dir().join(format!("sigmap_selected_{}", std::process::id()));
+    let mut second = test_station(
+        Point3D::new(2.0, 10.0, 2.0),
+        30.0,
+        Frequency::from_mhz(2000.0),
+    );
+    second.id = 2;
+    let scene = test_config(
+        10,
+        10,
+        vec![
+            test_ground(10.0, 10.0),
+            test_station(
+                Point3D::new(5.0, 10.0, 5.0),
+                30.0,
+                Frequency::from_mhz(2000.0),
+            ),
+            second,
+        ],
+    );
+    let options = SigmapOptions {
+        subdirectories: true,
+        png: true,
+        stations: vec![2],
+        ..Default::default()
+    };
+    let timing = generate_sigmap(base.to_str().unwrap(), &scene, &options).unwrap();
+    assert_eq!(timing.stations.len(), 1);
+    let written: Vec<String> = std::fs::read_dir(&base)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    assert_eq!(written, vec!["2"]);
+    let files = output_files(base.to_str().unwrap(), &scene, &options);
+    assert!(files.iter().all(|f| Path::new(f).is_file()), "{:?}", files);
+    std::fs::remove_dir_all(&base).unwrap();
+    let options = SigmapOptions {
+        stations: vec![5],
+        ..Default::default()
+    };
+    let error = generate_sigmap(base.to_str().unwrap(), &scene, &options).unwrap_err();
+    assert_eq!(error.to_string(), "scene contains none of the stations [5]");
+    assert!(matches!(error, RaytracerError::Validation(_)));
+}
+
+#[test]
+fn test_monte_carlo_without_jitter() {
+    // Mirror-like ground and single bounces, so tracing is repeatable.
+    let ground = Cube::new(
+        Point3D::new(10.0, 0.0, 10.0),
+        10.0,
+        0.0,
+        10.0,
+        Material::Metal(Metal::new(Srgb::new(0.5, 0.5, 0.5), 0.0, 0.0)),
+        0,
+    );
+    let station = test_station(
+        Point3D::new(5.0, 10.0, 12.0),
+        30.0,
+        Frequency::from_mhz(2000.0),
+    );
+    let mut scene = test_config(20, 20, vec![ground, station.clone()]);
+    scene.max_depth = 1;
+    let options = SigmapOptions::default();
+    let single = station_signal(&station, &scene, &options).0.signals;
+    let maps = monte_carlo(&scene, 3, 0.0, &options).unwrap();
+    assert_eq!(maps.mean.len(), single.len());
+    for (i, signal) in single.iter().enumerate() {
+        assert_approx_eq!(maps.mean[i], signal, 1e-4);
+        assert_eq!(maps.p10[i], *signal);
+    }
+    assert!(monte_carlo(&scene, 0, 0.0, &options).is_err());
+}
+
+#[test]
+fn test_monte_carlo_no_clobber() {
+    let base = std::env::temp_dir().join(format!("sigmap_mc_clobber_{}", std::process::id()));
+    let station = test_station(
+        Point3D::new(5.0, 10.0, 5.0),
+        30.0,
+        Frequency::from_mhz(2000.0),
+    );
+    let scene = test_config(10, 10, vec![test_ground(10.0, 10.0), station]);
+    let options = SigmapOptions {
+        subdirectories: true,
+        no_clobber: true,
+        ..Default::default()
+    };
+    std::fs::create_dir_all(&base).unwrap();
+    std::fs::write(base.join("mc_p10.data"), b"").unwrap();
+    let error = generate_monte_carlo(base.to_str().unwrap(), &scene, 2, 1.0, &options);
+    assert!(matches!(
+        error,
+        Err(RaytracerError::Io(e)) if e.to_string().ends_with("mc_p10.data already exists")
+    ));
+    // The mean was not written either.
+    assert!(!base.join("mc_mean.data").exists());
+    std::fs::remove_file(base.join("mc_p10.data")).unwrap();
+    generate_monte_carlo(base.to_str().unwrap(), &scene, 2, 1.0, &options).unwrap();
+    assert!(base.join("mc_mean.data").exists() && base.join("mc_p10.data").exists());
+    std::fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn test_render_plan() {
+    let building = Cube::new(
+        Point3D::new(15.0, 5.0, 5.0),
+        2.0,
+        5.0,
+        2.0,
+        Material::Lambertian(Lambertian::new(Srgb::new(0.5, 0.5, 0.5))),
+        2,
+    );
+    let station = test_station(
+        Point3D::new(4.0, 10.0, 12.0),
+        30.0,
+        Frequency::from_mhz(2000.0),
+    );
+    let scene = test_config(20, 16, vec![test_ground(20.0, 16.0), building, station]);
+    let plan = render_plan(&scene);
+    assert_eq!(plan.len(), 20 * 16 * 3);
+    // Rows run from the top, the far (+z) edge.
+    let pixel = |x: usize, y: usize| {
+        let i = (x + (15 - y) * 20) * 3;
+        (plan[i], plan[i + 1], plan[i + 2])
+    };
+    for (x, y) in [(3, 11), (4, 12), (5, 13)] {
+        assert_eq!(pixel(x, y), STATION_COLOR);
+    }
+    // The building, seen through the station's sector.
+    let (r, g, b) = pixel(15, 5);
+    assert!(r == 255 && g < 128 && b < 128, "{:?}", (r, g, b));
+    assert_ne!(pixel(10, 2), STATION_COLOR);
+}
+
+#[test]
+fn test_no_signal_color() {
+    let signals = vec![-60.0, NO_SIGNAL, -139.0, -200.0];
+    let mut pixels = vec![0; 3 * 2 * 3];
+    signal_to_pixels(&signals, None, &mut pixels, 2, 2, [10, 20, 30]);
+    // set_pixel puts row 0 at the end of the buffer.
+    let pixel = |x: usize, y: usize| {
+        let i = (x + (2 - y) * 2) * 3;
+        (pixels[i], pixels[i + 1], pixels[i + 2])
+    };
+    assert_eq!(pixel(0, 0), signal_to_color(-60.0));
+    assert_eq!(pixel(1, 0), (10, 20, 30));
+    // Weak coverage stays on the ramp, which is black at the bottom.
+    assert_eq!(pixel(0, 1), signal_to_color(-139.0));
+    assert_eq!(signal_to_color(NO_SIGNAL), (0, 0, 0));
+    assert_eq!(pixel(1, 1), (10, 20, 30));
+}
+
+#[test]
+fn test_aperture() {
+    let mut station = test_station(
+        Point3D::new(10.0, 10.0, 10.0),
+        30.0,
+        Frequency::from_mhz(2000.0),
+    );
+    let scene = test_config(20, 20, vec![station.clone()]);
+    let offsets = |station: &Cube| {
+        let mut offsets = vec![];
+        for (target_x, target_y) in [(2, 3), (17, 5), (10, 18), (4, 15)] {
+            for ray in station_rays(station, &scene, target_x, target_y) {
+                offsets.push(ray.origin - station.origin);
+            }
+        }
+        offsets
+    };
+    assert!(offsets(&station).iter().all(|o| o.length() == 0.0));
+    if let Material::Light(light) = &mut station.material {
+        light.aperture = Aperture {
+            width: 2.0,
+            height: 1.0,
+        };
+    }
+    let offsets = offsets(&station);
+    for offset in offsets.iter() {
+        let horizontal = (offset.x().powi(2) + offset.z().powi(2)).sqrt();
+        assert!(horizontal <= 1.0 + 1e-5 && offset.y().abs() <= 0.5 + 1e-5);
+    }
+    assert!(offsets.iter().any(|o| o.y() > 0.1) && offsets.iter().any(|o| o.y() < -0.1));
+    assert!(offsets.iter().any(|o| o.x().abs() + o.z().abs() > 0.5));
+}
+
+#[test]
+fn test_azimuthal_subsampling() {
+    // Two sectors, with a beam edge along +z from the station.
+    let mut station = test_station(
+        Point3D::new(10.0, 10.0, 10.0),
+        30.0,
+        Frequency::from_mhz(2000.0),
+    );
+    if let Material::Light(light) = &mut station.material {
+        light.beams = 2;
+    }
+    let mut scene = test_config(20, 20, vec![station.clone()]);
+    let near_edge = |scene: &Config| {
+        let mut count = 0;
+        for target_y in 12..20 {
+            for target_x in 8..13 {
+                for ray in station_rays(&station, scene, target_x, target_y) {
+                    let ground = ray.at(-ray.origin.y() / ray.direction.y());
+                    if (ground.x() - station.origin.x()).abs() < 0.4 {
+                        count += 1;
+                    }
+                }
+            }
+        }
+        count
+    };
+    let grid = near_edge(&scene);
+    scene.subsampling = Subsampling::Azimuthal;
+    let azimuthal = near_edge(&scene);
+    assert_eq!(
+        station_rays(&station, &scene, 3, 4).len(),
+        SUBSAMPLING.pow(2)
+    );
+    assert!(azimuthal > grid, "{} vs {}", azimuthal, grid);
+}
+
+#[test]
+fn test_coincident_cubes() {
+    let wall = Cube::new(
+        Point3D::new(0.0, 0.0, 0.0),
+        2.0,
+        2.0,
+        2.0,
+        Material::Wall(Wall::new(Srgb::new(0.5, 0.5, 0.5), 0.0)),
+        7,
+    );
+    let metal = Cube::new(
+        Point3D::new(0.0, 0.0, 0.0),
+        2.0,
+        2.0,
+        2.0,
+        Material::Metal(Metal::new(Srgb::new(0.5, 0.5, 0.5), 0.0, 0.0)),
+        3,
+    );
+    let ray = Ray::new(
+        Point3D::new(-5.0, 0.3, 0.2),
+        Point3D::new(1.0, 0.0, 0.0),
+        0.0,
+        0.0,
+        Frequency::from_mhz(2000.0),
+    );
+    for objects in [[&wall, &metal], [&metal, &wall]] {
+        let world = World::new(&objects);
+        for _ in 0..10 {
+            let hit = world.hit(&ray, 0.001, f32::MAX).unwrap();
+            assert!(matches!(hit.material, Material::Metal(_)));
+        }
+    }
+}
+
+#[test]
+fn test_best_arrival() {
+    let station = test_station(
+        Point3D::new(5.0, 10.0, 5.0),
+        30.0,
+        Frequency::from_mhz(2000.0),
+    );
+    let scene = test_config(10, 10, vec![test_ground(10.0, 10.0), station.clone()]);
+    let options = SigmapOptions {
+        angles: true,
+        ..Default::default()
+    };
+    let result = generate_signal(&station, &scene, &options);
+    let (azimuth, elevation, dbm) = best_arrival(&result, &scene, 8, 2).unwrap();
+    assert_eq!(dbm, result.signals[8 + 2 * 10]);
+    // Looking back from around (8.5, 2.5) to the station at (5, 10, 5).
+    let (dx, dz) = (5.0 - 8.5f32, 5.0 - 2.5f32);
+    assert!((azimuth - dz.atan2(dx)).abs() < 0.3, "azimuth {}", azimuth);
+    let expected = 10f32.atan2((dx * dx + dz * dz).sqrt());
+    assert!(
+        (elevation - expected).abs() < 0.2,
+        "elevation {}",
+        elevation
+    );
+    let no_angles = generate_signal(&station, &scene, &SigmapOptions::default());
+    assert!(best_arrival(&no_angles, &scene, 8, 2).is_none());
+}
+
+#[test]
+fn test_rooftop_diffraction() {
+    use crate::config::Diffraction;
+    // A 20 m tall block over x in [10, 20] puts the street behind it in the shadow
+    // of a station at 30 m. Rays aimed beyond the block hit its roof.
+    let station = test_station(
+        Point3D::new(5.0, 30.0, 10.0),
+        60.0,
+        Frequency::from_mhz(2000.0),
+    );
+    let building = Cube::from_corners(
+        Point3D::new(10.0, 0.0, 0.0),
+        Point3D::new(20.0, 20.0, 20.0),
+        Material::Metal(Metal::new(Srgb::new(0.5, 0.5, 0.5), 0.0, 0.0)),
+        2,
+    );
+    let mut scene = test_config(
+        40,
+        20,
+        vec![test_ground(40.0, 20.0), building, station.clone()],
+    );
+    let street_coverage = |scene: &Config| {
+        let result = generate_signal(&station, scene, &SigmapOptions::default());
+        let mut covered = 0;
+        for x in 22..38 {
+            for y in 2..18 {
+                if !is_no_signal(result.signals[x + y * 40]) {
+                    covered += 1;
+                }
+            }
+        }
+        covered
+    };
+    let without = street_coverage(&scene);
+    scene.diffraction = Some(Diffraction {
+        loss: 10.0,
+        min_height: 10.0,
+        max_angle: 85.0,
+    });
+    let with = street_coverage(&scene);
+    assert!(with > without + 50, "{} vs {}", with, without);
+}
+
+#[test]
+fn test_grid_origin() {
+    let mut scene = test_config(10, 10, vec![]);
+    scene.grid_origin = Point3D::new(1000.0, 0.0, -500.0);
+    assert_eq!(grid_coord(Point3D::new(2.5, 0.0, 3.5), &scene), None);
+    assert_eq!(
+        grid_coord(Point3D::new(1002.5, 0.0, -496.5), &scene),
+        Some(2 + 3 * 10)
+    );
+    assert_eq!(grid_coord(Point3D::new(1010.5, 0.0, -496.5), &scene), None);
+    // A station over the shifted map covers it like one over an unshifted map.
+    let station = test_station(
+        Point3D::new(1005.0, 10.0, -495.0),
+        30.0,
+        Frequency::from_mhz(2000.0),
+    );
+    let ground = Cube::new(
+        Point3D::new(1005.0, 0.0, -495.0),
+        5.0,
+        0.0,
+        5.0,
+        Material::Lambertian(Lambertian::new(Srgb::new(0.5, 0.5, 0.5))),
+        0,
+    );
+    scene.objects = vec![ground, station.clone()];
+    scene.max_depth = 1;
+    let result = generate_signal(&station, &scene, &SigmapOptions::default());
+    for x in 1..10 {
+        for y in 1..10 {
+            assert!(!is_no_signal(result.signals[x + y * 10]), "({}, {})", x, y);
+        }
+    }
 }
@@ -21,6 +21,11 @@ impl Sphere {
             material,
         }
     }
+
+    /// Whether `p` lies inside the sphere or on its surface.
+    pub fn contains(&self, p: &Point3D) -> bool {
+        (*p - self.center).length_squared() <= self.radius * self.radius
+    }
 }
 
 fn u_v_from_sphere_hit_point(hit_point_on_sphere: Point3D) -> (f32, f32) {
@@ -34,7 +39,7 @@ fn u_v_from_sphere_hit_point(hit_point_on_sphere: Point3D) -> (f32, f32) {
 }
 
 impl Hittable for Sphere {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
         let oc = ray.origin - self.center;
         let a = ray.direction.length_squared();
         let half_b = oc.dot(&ray.direction);
@@ -58,6 +63,11 @@ impl Hittable for Sphere {
                         point: p,
                         normal: if front_face { normal } else { -normal },
                         front_face,
+                        thickness: if front_face {
+                            (root_b - root_a) * a.sqrt()
+                        } else {
+                            0.0
+                        },
                         material: &self.material,
                         u,
                         v,
@@ -67,5 +77,47 @@ impl Hittable for Sphere {
         }
         None
     }
+
+    fn bounding_box(&self) -> (Point3D, Point3D) {
+        let extent = Point3D::new(self.radius, self.radius, self.radius);
+        (self.center - extent, self.center + extent)
+    }
+}
+
+#[test]
+fn test_sphere_bounding_box() {
+    let sphere = Sphere::new(
+        Point3D::new(1.0, 2.0, 3.0),
+        2.0,
+        Material::Glass(crate::materials::Glass::new(1.5)),
+    );
+    let (min, max) = sphere.bounding_box();
+    for i in 0..16 {
+        let phi = i as f32 * std::f32::consts::PI / 8.0;
+        for psi in [-1.2, -0.4, 0.0, 0.7, 1.5] {
+            let direction = Point3D::new(
+                phi.sin() * f32::cos(psi),
+                f32::sin(psi),
+                phi.cos() * f32::cos(psi),
+            );
+            let p = sphere.center + direction * sphere.radius;
+            assert!(p.x() >= min.x() && p.y() >= min.y() && p.z() >= min.z());
+            assert!(p.x() <= max.x() && p.y() <= max.y() && p.z() <= max.z());
+        }
+    }
 }
 
+
+#[test]
+fn test_sphere_contains() {
+    let sphere = Sphere::new(
+        Point3D::new(1.0, 2.0, 3.0),
+        2.0,
+        Material::Glass(crate::materials::Glass::new(1.5)),
+    );
+    assert!(sphere.contains(&Point3D::new(1.0, 2.0, 3.0)));
+    assert!(sphere.contains(&Point3D::new(2.0, 3.0, 3.0)));
+    assert!(sphere.contains(&Point3D::new(1.0, 2.0, 5.0)));
+    assert!(!sphere.contains(&Point3D::new(2.5, 3.5, 3.0)));
+    assert!(!sphere.contains(&Point3D::new(1.0, -0.5, 3.0)));
+}
@@ -0,0 +1,32 @@
+use serde::Serialize;
+use std::time::Duration;
+
+use crate::frequency::Frequency;
+
+/// How long a render or signal map took, for tracking performance across runs.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TimingReport {
+    pub total_seconds: f64,
+    // Size of the thread pool the work was spread over.
+    pub threads: usize,
+    // One entry per station traced, empty for a render.
+    pub stations: Vec<StationTiming>,
+}
+
+/// Time spent tracing one station and writing its maps.
+#[derive(Debug, Clone, Serialize)]
+pub struct StationTiming {
+    pub id: i64,
+    pub frequency: Frequency,
+    pub seconds: f64,
+}
+
+impl TimingReport {
+    pub fn new(total: Duration, stations: Vec<StationTiming>) -> TimingReport {
+        TimingReport {
+            total_seconds: total.as_secs_f64(),
+            threads: rayon::current_num_threads(),
+            stations,
+        }
+    }
+}
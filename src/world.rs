@@ -0,0 +1,126 @@
+use std::borrow::Borrow;
+
+use crate::cube::Cube;
+use crate::point3d::Point3D;
+use crate::ray::{HitRecord, Hittable, Ray};
+
+/// Hits closer together than this are on coincident faces.
+const COINCIDENT_EPSILON: f32 = 1e-4;
+
+/// The objects of a scene as one `Hittable`, shared by the render and the signal
+/// map. Works on the scene's own objects as well as on a selection of them.
+#[derive(Debug, Clone, Copy)]
+pub struct World<'a, C = Cube> {
+    objects: &'a [C],
+}
+
+impl<'a, C: Borrow<Cube>> World<'a, C> {
+    pub fn new(objects: &'a [C]) -> World<'a, C> {
+        World { objects }
+    }
+
+    pub fn objects(&self) -> &'a [C] {
+        self.objects
+    }
+}
+
+impl<C: Borrow<Cube>> Hittable for World<'_, C> {
+    /// The closest hit along `ray`. Of hits on coincident faces, such as two imported
+    /// buildings sharing a wall, the object with the lowest id wins, so the order of the
+    /// objects does not matter.
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        let mut closest: Option<(HitRecord, i64)> = None;
+        for object in self.objects.iter().map(|o| o.borrow()) {
+            let limit = match &closest {
+                Some((hit, _)) => (hit.t + COINCIDENT_EPSILON).min(t_max),
+                None => t_max,
+            };
+            if let Some(hit) = object.hit(ray, t_min, limit) {
+                let closer = match &closest {
+                    Some((best, id)) => hit.t < best.t - COINCIDENT_EPSILON || object.id < *id,
+                    None => true,
+                };
+                if closer {
+                    closest = Some((hit, object.id));
+                }
+            }
+        }
+        closest.map(|(hit, _)| hit)
+    }
+
+    fn bounding_box(&self) -> (Point3D, Point3D) {
+        let boxes = self.objects.iter().map(|o| o.borrow().bounding_box());
+        boxes
+            .reduce(|(min_a, max_a), (min_b, max_b)| (min_a.min(&min_b), max_a.max(&max_b)))
+            .unwrap_or((Point3D::new(0.0, 0.0, 0.0), Point3D::new(0.0, 0.0, 0.0)))
+    }
+}
+
+#[cfg(test)]
+use crate::frequency::Frequency;
+#[cfg(test)]
+use crate::materials::{Lambertian, Material};
+#[cfg(test)]
+use palette::Srgb;
+
+#[test]
+fn test_world_hit() {
+    use rand::{Rng, SeedableRng};
+    let mut rng = rand::rngs::StdRng::seed_from_u64(20);
+    let material = Material::Lambertian(Lambertian::new(Srgb::new(0.5, 0.5, 0.5)));
+    let objects: Vec<Cube> = (0..20)
+        .map(|id| {
+            let mut cube = Cube::new(
+                Point3D::new(
+                    rng.gen_range(-10.0..10.0),
+                    rng.gen_range(-10.0..10.0),
+                    rng.gen_range(-10.0..10.0),
+                ),
+                rng.gen_range(0.5..3.0),
+                rng.gen_range(0.5..3.0),
+                rng.gen_range(0.5..3.0),
+                material.clone(),
+                id,
+            );
+            cube.rotation_y = rng.gen_range(0.0..90.0);
+            cube
+        })
+        .collect();
+    let selection: Vec<&Cube> = objects.iter().collect();
+    let world = World::new(&objects);
+    let selected = World::new(&selection);
+    let mut hits = 0;
+    for _ in 0..200 {
+        let direction = Point3D::new(
+            rng.gen_range(-1.0..1.0),
+            rng.gen_range(-1.0..1.0),
+            rng.gen_range(-1.0..1.0),
+        );
+        let ray = Ray::new(
+            Point3D::new(0.0, 0.0, 0.0) - direction * 30.0,
+            direction,
+            0.0,
+            0.0,
+            Frequency::from_mhz(2000.0),
+        );
+        // The closest hit over all objects, one by one.
+        let closest = objects
+            .iter()
+            .filter_map(|o| o.hit(&ray, 0.001, f32::MAX))
+            .map(|hit| hit.t)
+            .fold(None, |best: Option<f32>, t| {
+                Some(best.map_or(t, |b| b.min(t)))
+            });
+        let found = world.hit(&ray, 0.001, f32::MAX).map(|hit| hit.t);
+        match (closest, found) {
+            (Some(closest), Some(found)) => {
+                assert!((closest - found).abs() <= COINCIDENT_EPSILON);
+                hits += 1;
+            }
+            (None, None) => {}
+            _ => panic!("World found {:?}, scanning found {:?}", found, closest),
+        }
+        assert_eq!(selected.hit(&ray, 0.001, f32::MAX).map(|hit| hit.t), found);
+    }
+    assert!(hits > 0);
+}